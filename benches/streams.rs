@@ -0,0 +1,272 @@
+//! Measures the throughput of many concurrent TCP streams between two `Net`s
+//! linked in memory, and the CPU time spent on many small writes and
+//! datagrams.
+//!
+//! Run with `cargo bench --bench streams`.
+
+use smoltcp::{
+    iface::Config,
+    phy::{DeviceCapabilities, Medium},
+    wire::HardwareAddress,
+};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_smoltcp::{
+    BufferSize, Net, NetConfig, TcpStream,
+    device::{Framed, Framing},
+};
+
+const TOTAL_BYTES: usize = 256 << 20;
+const ROUND_TRIPS: usize = 200_000;
+const SMALL_WRITES: usize = 1_000_000;
+const DATAGRAMS: usize = 200_000;
+
+fn net_pair() -> (Net, Net) {
+    let mut caps = DeviceCapabilities::default();
+    caps.medium = Medium::Ip;
+    caps.max_transmission_unit = 1500;
+    let (a, b) = tokio::io::duplex(1 << 20);
+    let net = |io, ip: &str| {
+        let mut config = NetConfig::new(
+            Config::new(HardwareAddress::Ip),
+            ip.parse().unwrap(),
+            vec![],
+        );
+        config.buffer_size = BufferSize {
+            tcp_rx_size: 1 << 16,
+            tcp_tx_size: 1 << 16,
+            ..Default::default()
+        };
+        Net::new(Framed::new(io, Framing::LengthU16, caps.clone()), config)
+    };
+    (net(a, "10.0.0.1/24"), net(b, "10.0.0.2/24"))
+}
+
+/// Opens `streams` connections, the listener only accepts one at a time.
+async fn connect(client: &Net, server: &Net, streams: usize) -> Vec<(TcpStream, TcpStream)> {
+    let mut listener = server
+        .tcp_bind("10.0.0.2:5000".parse().unwrap())
+        .await
+        .unwrap();
+    let mut pairs = Vec::new();
+    for _ in 0..streams {
+        let (connected, accepted) = tokio::join!(
+            client.tcp_connect("10.0.0.2:5000".parse().unwrap()),
+            listener.accept()
+        );
+        pairs.push((connected.unwrap(), accepted.unwrap().0));
+    }
+    pairs
+}
+
+/// Every stream sends its share of `TOTAL_BYTES` in 16 KiB writes.
+async fn bulk(streams: usize) -> Duration {
+    let (client, server) = net_pair();
+    let per_stream = TOTAL_BYTES / streams;
+    let pairs = connect(&client, &server, streams).await;
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (mut writer, mut reader) in pairs {
+        tasks.push(tokio::spawn(async move {
+            let buf = vec![0u8; 16 << 10];
+            let mut written = 0;
+            while written < per_stream {
+                let len = buf.len().min(per_stream - written);
+                writer.write_all(&buf[..len]).await.unwrap();
+                written += len;
+            }
+            writer
+        }));
+        tasks.push(tokio::spawn(async move {
+            let mut buf = vec![0u8; 1 << 16];
+            let mut read = 0;
+            while read < per_stream {
+                read += reader.read(&mut buf).await.unwrap();
+            }
+            reader
+        }));
+    }
+    let mut streams = Vec::new();
+    for task in tasks {
+        streams.push(task.await.unwrap());
+    }
+    start.elapsed()
+}
+
+/// Every stream does its share of `ROUND_TRIPS` 64-byte request/responses.
+async fn ping_pong(streams: usize) -> Duration {
+    let (client, server) = net_pair();
+    let per_stream = ROUND_TRIPS / streams;
+    let pairs = connect(&client, &server, streams).await;
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (mut client, mut server) in pairs {
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while server.read_exact(&mut buf).await.is_ok() {
+                server.write_all(&buf).await.unwrap();
+            }
+        });
+        tasks.push(tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            for _ in 0..per_stream {
+                client.write_all(&buf).await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+            }
+            client
+        }));
+    }
+    let mut streams = Vec::new();
+    for task in tasks {
+        streams.push(task.await.unwrap());
+    }
+    start.elapsed()
+}
+
+/// Every stream does its share of `SMALL_WRITES` 16-byte writes.
+async fn small_writes(streams: usize) -> Duration {
+    let (client, server) = net_pair();
+    let per_stream = SMALL_WRITES / streams * 16;
+    let pairs = connect(&client, &server, streams).await;
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (mut writer, mut reader) in pairs {
+        tasks.push(tokio::spawn(async move {
+            let buf = [0u8; 16];
+            for _ in 0..per_stream / buf.len() {
+                writer.write_all(&buf).await.unwrap();
+            }
+            writer
+        }));
+        tasks.push(tokio::spawn(async move {
+            let mut buf = vec![0u8; 1 << 16];
+            let mut read = 0;
+            while read < per_stream {
+                read += reader.read(&mut buf).await.unwrap();
+            }
+            reader
+        }));
+    }
+    let mut streams = Vec::new();
+    for task in tasks {
+        streams.push(task.await.unwrap());
+    }
+    start.elapsed()
+}
+
+/// Sends `DATAGRAMS` 32-byte datagrams, returns how many were received, the
+/// receiver may drop some of them.
+async fn datagrams() -> (Duration, usize) {
+    let (client, server) = net_pair();
+    let sender = client
+        .udp_bind("10.0.0.1:5000".parse().unwrap())
+        .await
+        .unwrap();
+    let receiver = server
+        .udp_bind("10.0.0.2:5000".parse().unwrap())
+        .await
+        .unwrap();
+    let target = "10.0.0.2:5000".parse().unwrap();
+
+    let start = Instant::now();
+    let receiver = tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        let mut received = 0;
+        let idle = Duration::from_millis(100);
+        while let Ok(r) = tokio::time::timeout(idle, receiver.recv_from(&mut buf)).await {
+            r.unwrap();
+            received += 1;
+        }
+        received
+    });
+    for _ in 0..DATAGRAMS {
+        sender.send_to(&[0u8; 32], target).await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    (elapsed, receiver.await.unwrap())
+}
+
+/// The CPU time used by the process so far.
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: `usage` is valid for writes, and initialized on success.
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    let time = |t: libc::timeval| {
+        Duration::new(t.tv_sec as u64, 0) + Duration::from_micros(t.tv_usec as u64)
+    };
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} worker threads", threads);
+
+    for streams in [1, 16, 256] {
+        let elapsed = runtime.block_on(runtime.spawn(bulk(streams))).unwrap();
+        println!(
+            "bulk      {:>4} streams: {:>8.1} MB/s",
+            streams,
+            TOTAL_BYTES as f64 / elapsed.as_secs_f64() / 1e6
+        );
+    }
+    for streams in [1, 16, 256] {
+        let elapsed = runtime.block_on(runtime.spawn(ping_pong(streams))).unwrap();
+        println!(
+            "ping-pong {:>4} streams: {:>8.0} round trips/s",
+            streams,
+            ROUND_TRIPS as f64 / elapsed.as_secs_f64()
+        );
+    }
+    for streams in [1, 16, 256] {
+        let cpu_start = cpu_time();
+        let elapsed = runtime
+            .block_on(runtime.spawn(small_writes(streams)))
+            .unwrap();
+        let cpu = match (cpu_start, cpu_time()) {
+            (Some(start), Some(end)) => format!(
+                ", {:.0} ns CPU/write",
+                (end - start).as_nanos() as f64 / SMALL_WRITES as f64
+            ),
+            _ => String::new(),
+        };
+        println!(
+            "small     {:>4} streams: {:>8.0} writes/s{}",
+            streams,
+            SMALL_WRITES as f64 / elapsed.as_secs_f64(),
+            cpu
+        );
+    }
+    let cpu_start = cpu_time();
+    let (elapsed, received) = runtime.block_on(runtime.spawn(datagrams())).unwrap();
+    let cpu = match (cpu_start, cpu_time()) {
+        (Some(start), Some(end)) => format!(
+            ", {:.0} ns CPU/datagram received",
+            (end - start).as_nanos() as f64 / received as f64
+        ),
+        _ => String::new(),
+    };
+    println!(
+        "datagrams           : {:>8.0} sends/s, {:.1}% received{}",
+        DATAGRAMS as f64 / elapsed.as_secs_f64(),
+        received as f64 * 100.0 / DATAGRAMS as f64,
+        cpu
+    );
+}
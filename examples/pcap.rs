@@ -1,6 +1,5 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use dns_parser::QueryType;
-use pcap::{Capture, Device};
 use smoltcp::{
     phy::DeviceCapabilities,
     wire::{EthernetAddress, IpAddress, IpCidr},
@@ -20,11 +19,30 @@ struct Opt {
     gateway: String,
 }
 
-#[cfg(unix)]
-fn get_by_device(device: Device) -> Result<impl AsyncDevice> {
+#[cfg(target_os = "linux")]
+fn get_by_device(device: &str, ethernet_addr: EthernetAddress) -> Result<impl AsyncDevice + use<>> {
+    use tokio_smoltcp::device::PacketSocket;
+
+    let mut caps = DeviceCapabilities::default();
+    caps.max_burst_size = Some(100);
+    caps.max_transmission_unit = 1514;
+
+    PacketSocket::builder(device, caps)
+        .filter_mac(ethernet_addr)
+        .open()
+        .context("Failed to open device")
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn get_by_device(
+    device: &str,
+    _ethernet_addr: EthernetAddress,
+) -> Result<impl AsyncDevice + use<>> {
+    use pcap::Capture;
     use std::io;
     use tokio_smoltcp::device::AsyncCapture;
 
+    let device = find_device(device)?;
     let cap = Capture::from_device(device.clone())
         .context("Failed to capture device")?
         .promisc(true)
@@ -37,14 +55,14 @@ fn get_by_device(device: Device) -> Result<impl AsyncDevice> {
         match e {
             pcap::Error::IoError(e) => e.into(),
             pcap::Error::TimeoutExpired => io::ErrorKind::WouldBlock.into(),
-            other => io::Error::new(io::ErrorKind::Other, other),
+            other => io::Error::other(other),
         }
     }
     let mut caps = DeviceCapabilities::default();
     caps.max_burst_size = Some(100);
     caps.max_transmission_unit = 1500;
 
-    Ok(AsyncCapture::new(
+    AsyncCapture::new(
         cap.setnonblock().context("Failed to set nonblock")?,
         |d| {
             let r = d.next_packet().map_err(map_err).map(|p| p.to_vec());
@@ -58,13 +76,19 @@ fn get_by_device(device: Device) -> Result<impl AsyncDevice> {
         },
         caps,
     )
-    .context("Failed to create async capture")?)
+    .context("Failed to create async capture")
 }
 
 #[cfg(windows)]
-fn get_by_device(device: Device) -> Result<impl AsyncDevice> {
+fn get_by_device(
+    device: &str,
+    _ethernet_addr: EthernetAddress,
+) -> Result<impl AsyncDevice + use<>> {
+    use pcap::Capture;
     use tokio::sync::mpsc::{Receiver, Sender};
     use tokio_smoltcp::device::ChannelCapture;
+
+    let device = find_device(device)?;
     let mut caps = DeviceCapabilities::default();
     caps.max_burst_size = Some(100);
     caps.max_transmission_unit = 1500;
@@ -104,16 +128,22 @@ fn get_by_device(device: Device) -> Result<impl AsyncDevice> {
     Ok(capture)
 }
 
-async fn async_main(opt: Opt) -> Result<()> {
-    let device = Device::list()?
+#[cfg(not(target_os = "linux"))]
+fn find_device(name: &str) -> Result<pcap::Device> {
+    use anyhow::anyhow;
+
+    pcap::Device::list()?
         .into_iter()
-        .find(|d| d.name == opt.device)
-        .ok_or(anyhow!("Device not found"))?;
+        .find(|d| d.name == name)
+        .ok_or(anyhow!("Device not found"))
+}
+
+async fn async_main(opt: Opt) -> Result<()> {
     let ethernet_addr: EthernetAddress = opt.ethernet_addr.parse().unwrap();
     let ip_addr: IpCidr = opt.ip_addr.parse().unwrap();
     let gateway: IpAddress = opt.gateway.parse().unwrap();
 
-    let device = get_by_device(device)?;
+    let device = get_by_device(&opt.device, ethernet_addr)?;
     let mut interface_config = iface::Config::new(ethernet_addr.into());
     interface_config.random_seed = rand::random();
     let net = Net::new(
@@ -0,0 +1,376 @@
+//! A slirp-style bridge from a virtual network to the host.
+//!
+//! The `Net` of a `Bridge` accepts the TCP connections and UDP datagrams of
+//! the guests to any address, and proxies them through sockets of the host,
+//! so a VM or a sandbox on the other side of its device reaches the outside
+//! as the host process.
+//!
+//! ```no_run
+//! # use tokio_smoltcp::device::AsyncDevice;
+//! # fn bridge(guest: impl AsyncDevice + 'static) {
+//! use tokio_smoltcp::bridge::{Bridge, BridgeConfig};
+//! use tokio_smoltcp::smoltcp::{iface::Config, wire::*};
+//! use tokio_smoltcp::NetConfig;
+//!
+//! let mut config = BridgeConfig::new();
+//! config.dns = Some(("10.0.2.3".parse().unwrap(), "1.1.1.1:53".parse().unwrap()));
+//! // Keep the guest away from the services of the host.
+//! config.policy = Some(Box::new(|_, addr| !addr.ip().is_loopback()));
+//! let bridge = Bridge::new(
+//!     guest,
+//!     NetConfig::new(Config::new(HardwareAddress::Ip), "10.0.2.2/24".parse().unwrap(), vec![]),
+//!     config,
+//! );
+//! # }
+//! ```
+
+use crate::{
+    Clock, Net, NetConfig, TcpListener, TcpStream, UdpSocket,
+    clock::timeout,
+    device::{AsyncDevice, Packet},
+    filter::{Headers, PacketFilter, TcpFlags, Verdict},
+    reactor::Reactor,
+    relay::{UdpRelay, Until},
+};
+use futures::{StreamExt, channel::mpsc};
+use parking_lot::Mutex;
+use smoltcp::{
+    phy::Medium,
+    wire::{IpAddress, IpCidr, IpProtocol},
+};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, OnceLock, atomic::AtomicUsize},
+    time::Duration,
+};
+use tokio::io::copy_bidirectional;
+
+/// Decides whether a connection to a destination is proxied.
+pub type Policy = Box<dyn FnMut(IpProtocol, SocketAddr) -> bool + Send>;
+
+/// A config for a `Bridge`.
+#[non_exhaustive]
+pub struct BridgeConfig {
+    /// Forwards the DNS queries sent to the first address, over UDP or TCP
+    /// port 53, to the resolver at the second one. The policy isn't asked
+    /// about them.
+    pub dns: Option<(IpAddr, SocketAddr)>,
+    /// Called with the destination of every new connection, or of the first
+    /// datagram to a destination, and proxies it only if it returns `true`.
+    /// A denied TCP connection is reset, a denied datagram answered with an
+    /// ICMP port unreachable. Every destination is allowed by default.
+    pub policy: Option<Policy>,
+    /// How long to wait for the handshake of the guest and the connection of
+    /// the host, 10 seconds by default.
+    pub connect_timeout: Duration,
+    /// How long a UDP flow is kept without traffic, 60 seconds by default.
+    pub udp_timeout: Duration,
+}
+
+impl BridgeConfig {
+    pub fn new() -> Self {
+        Self {
+            dns: None,
+            policy: None,
+            connect_timeout: Duration::from_secs(10),
+            udp_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A `Net` proxying the connections of the guests to the host.
+///
+/// It enables AnyIP and routes every address to itself, a guest then reaches
+/// any destination through the `Net`'s address as its gateway. The packets to
+/// the `Net`'s own address are left to its sockets, as well as the broadcasts
+/// and multicasts. The filter set in the `NetConfig`, if any, is called first.
+pub struct Bridge {
+    net: Net,
+}
+
+impl Bridge {
+    /// Creates a `Net` over `device` bridged to the host. It panics if the
+    /// medium is not supported.
+    pub fn new<D: AsyncDevice + 'static>(
+        device: D,
+        mut config: NetConfig,
+        bridge: BridgeConfig,
+    ) -> Bridge {
+        let reactor = Arc::new(OnceLock::new());
+        let flows = Arc::new(Mutex::new(Flows::default()));
+        let (sender, receiver) = mpsc::unbounded();
+        let local = config.ip_addr.address();
+        config.filter = Some(Box::new(BridgeFilter {
+            filter: config.filter.take(),
+            reactor: reactor.clone(),
+            local,
+            broadcast: match config.ip_addr {
+                IpCidr::Ipv4(cidr) => cidr.broadcast().map(IpAddress::Ipv4),
+                #[allow(unreachable_patterns)]
+                _ => None,
+            },
+            flows: flows.clone(),
+            policy: bridge.policy,
+            dns: bridge.dns,
+            sender,
+        }));
+
+        let (net, fut) = Net::new2(device, config);
+        let _ = reactor.set(net.reactor.clone());
+        net.set_any_ip(true);
+        net.routes_mut(|routes| match local {
+            IpAddress::Ipv4(v4) => {
+                routes.add_default_ipv4_route(v4).unwrap();
+            }
+            IpAddress::Ipv6(v6) => {
+                routes.add_default_ipv6_route(v6).unwrap();
+            }
+            #[allow(unreachable_patterns)]
+            _ => panic!("Unsupported address"),
+        });
+        tokio::spawn(fut);
+        tokio::spawn(run(
+            receiver,
+            flows,
+            net.clock().clone(),
+            bridge.connect_timeout,
+            bridge.udp_timeout,
+        ));
+
+        Bridge { net }
+    }
+    /// Returns the `Net` of the bridge.
+    pub fn net(&self) -> &Net {
+        &self.net
+    }
+}
+
+/// The flows proxied, so the retransmissions don't open them again.
+#[derive(Default)]
+struct Flows {
+    /// By guest and destination.
+    tcp: HashSet<(SocketAddr, SocketAddr)>,
+    /// By destination, a socket receives the datagrams of every guest.
+    udp: HashSet<SocketAddr>,
+}
+
+enum Flow {
+    Tcp {
+        listener: TcpListener,
+        peer: SocketAddr,
+        target: SocketAddr,
+    },
+    Udp {
+        socket: UdpSocket,
+        target: SocketAddr,
+    },
+}
+
+/// Opens a socket for the first packet of a flow, before the interface sees
+/// it.
+struct BridgeFilter {
+    filter: Option<Box<dyn PacketFilter>>,
+    reactor: Arc<OnceLock<Arc<Reactor>>>,
+    local: IpAddress,
+    /// The broadcast address of the `Net`'s subnet.
+    broadcast: Option<IpAddress>,
+    flows: Arc<Mutex<Flows>>,
+    policy: Option<Policy>,
+    dns: Option<(IpAddr, SocketAddr)>,
+    sender: mpsc::UnboundedSender<Flow>,
+}
+
+impl PacketFilter for BridgeFilter {
+    fn ingress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        if let Some(filter) = &mut self.filter
+            && filter.ingress(medium, packet) == Verdict::Drop
+        {
+            return Verdict::Drop;
+        }
+        let Some(reactor) = self.reactor.get() else {
+            return Verdict::Accept;
+        };
+        let Some(ip) = Headers::parse(medium, packet).ip else {
+            return Verdict::Accept;
+        };
+        let Some((src_port, dst_port)) = ip.ports else {
+            return Verdict::Accept;
+        };
+        // Broadcasts and multicasts are for the sockets of the `Net`, if any,
+        // they have no single destination to proxy to.
+        if ip.dst == self.local
+            || ip.dst.is_multicast()
+            || ip.dst.is_broadcast()
+            || Some(ip.dst) == self.broadcast
+        {
+            return Verdict::Accept;
+        }
+        let peer = SocketAddr::new(ip.src.into(), src_port);
+        let local = SocketAddr::new(ip.dst.into(), dst_port);
+
+        let mut flows = self.flows.lock();
+        let flow = match ip.protocol {
+            IpProtocol::Tcp => {
+                // Only a SYN opens a connection, its retransmissions go to the
+                // socket opened by the first one.
+                let syn = ip
+                    .tcp_flags
+                    .is_some_and(|f| f.contains(TcpFlags::SYN) && !f.contains(TcpFlags::ACK));
+                if !syn || flows.tcp.contains(&(peer, local)) {
+                    return Verdict::Accept;
+                }
+                let Some(target) = target(&mut self.policy, self.dns, IpProtocol::Tcp, local)
+                else {
+                    return Verdict::Accept;
+                };
+                let Ok(listener) = TcpListener::new(reactor.clone(), local.into()) else {
+                    return Verdict::Accept;
+                };
+                flows.tcp.insert((peer, local));
+                Flow::Tcp {
+                    listener,
+                    peer,
+                    target,
+                }
+            }
+            IpProtocol::Udp => {
+                if flows.udp.contains(&local) {
+                    return Verdict::Accept;
+                }
+                let Some(target) = target(&mut self.policy, self.dns, IpProtocol::Udp, local)
+                else {
+                    return Verdict::Accept;
+                };
+                let Ok(socket) = UdpSocket::new(reactor.clone(), local.into()) else {
+                    return Verdict::Accept;
+                };
+                flows.udp.insert(local);
+                Flow::Udp { socket, target }
+            }
+            _ => return Verdict::Accept,
+        };
+        let _ = self.sender.unbounded_send(flow);
+        Verdict::Accept
+    }
+    fn egress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        match &mut self.filter {
+            Some(filter) => filter.egress(medium, packet),
+            None => Verdict::Accept,
+        }
+    }
+}
+
+/// Returns where to proxy a flow to `local`, if it's allowed.
+fn target(
+    policy: &mut Option<Policy>,
+    dns: Option<(IpAddr, SocketAddr)>,
+    protocol: IpProtocol,
+    local: SocketAddr,
+) -> Option<SocketAddr> {
+    if let Some((addr, upstream)) = dns
+        && local == SocketAddr::new(addr, 53)
+    {
+        return Some(upstream);
+    }
+    let allowed = policy.as_mut().is_none_or(|policy| policy(protocol, local));
+    allowed.then_some(local)
+}
+
+async fn run(
+    mut receiver: mpsc::UnboundedReceiver<Flow>,
+    flows: Arc<Mutex<Flows>>,
+    clock: Arc<dyn Clock>,
+    connect_timeout: Duration,
+    udp_timeout: Duration,
+) {
+    while let Some(flow) = receiver.next().await {
+        let flows = flows.clone();
+        let clock = clock.clone();
+        match flow {
+            Flow::Tcp {
+                listener,
+                peer,
+                target,
+            } => {
+                tokio::spawn(async move {
+                    let local = listener.local_addr().unwrap();
+                    proxy_tcp(listener, target, clock, connect_timeout).await;
+                    flows.lock().tcp.remove(&(peer, local));
+                });
+            }
+            Flow::Udp { socket, target } => {
+                tokio::spawn(async move {
+                    let local = socket.local_addr().unwrap();
+                    proxy_udp(socket, target, clock, udp_timeout).await;
+                    flows.lock().udp.remove(&local);
+                });
+            }
+        }
+    }
+}
+
+/// Connects to `target` while the guest finishes its handshake, then copies
+/// both ways until both sides closed. The guest is reset if the host can't
+/// connect.
+async fn proxy_tcp(
+    listener: TcpListener,
+    target: SocketAddr,
+    clock: Arc<dyn Clock>,
+    connect_timeout: Duration,
+) {
+    let clock = &*clock;
+    let (guest, host) = tokio::join!(
+        timeout(clock, connect_timeout, TcpStream::accept_one(listener)),
+        timeout(
+            clock,
+            connect_timeout,
+            tokio::net::TcpStream::connect(target)
+        )
+    );
+    let Some(Ok(mut guest)) = guest else {
+        return;
+    };
+    match host {
+        Some(Ok(mut host)) => {
+            let _ = copy_bidirectional(&mut guest, &mut host).await;
+        }
+        // The guest is already connected, reset it like the host was.
+        _ => guest.abort(),
+    }
+}
+
+/// Sends the datagrams of each guest from its own host socket, and the replies
+/// back, until no guest sent or received anything for `udp_timeout`.
+async fn proxy_udp(
+    socket: UdpSocket,
+    target: SocketAddr,
+    clock: Arc<dyn Clock>,
+    udp_timeout: Duration,
+) {
+    let relay = UdpRelay {
+        socket: Arc::new(socket),
+        target,
+        timeout: udp_timeout,
+        max_peers: Arc::new(AtomicUsize::new(usize::MAX)),
+        until: Until::Idle,
+        clock,
+    };
+    relay.run(|| bind_udp(target)).await
+}
+
+/// Binds a host socket able to reach `target`.
+async fn bind_udp(target: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let unspecified: IpAddr = match target {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    tokio::net::UdpSocket::bind((unspecified, 0)).await
+}
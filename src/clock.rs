@@ -0,0 +1,194 @@
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use smoltcp::time::{Duration, Instant};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// The source of time of a `Net`.
+///
+/// The reactor reads the time from it to drive the interface and sleeps on it
+/// until the next timer of the sockets, e.g. a TCP retransmission, is due.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+    /// Returns a future completing once `now()` reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// The default `Clock`, following `tokio::time`.
+///
+/// It starts at the wall-clock time it was made at, and then advances with
+/// tokio's clock, so it also stops while tokio's time is paused and jumps
+/// forward when tokio auto-advances it.
+#[derive(Debug, Clone, Copy)]
+pub struct TokioClock {
+    start: tokio::time::Instant,
+    epoch: Instant,
+}
+
+impl TokioClock {
+    /// Makes a new `TokioClock` starting now.
+    pub fn new() -> TokioClock {
+        TokioClock {
+            start: tokio::time::Instant::now(),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        self.epoch + Duration::from(self.start.elapsed())
+    }
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let after = if deadline > self.epoch {
+            (deadline - self.epoch).into()
+        } else {
+            std::time::Duration::ZERO
+        };
+        Box::pin(tokio::time::sleep_until(self.start + after))
+    }
+}
+
+/// Returns a future completing once `duration` has passed on `clock`.
+pub(crate) fn sleep(clock: &dyn Clock, duration: std::time::Duration) -> BoxFuture<'static, ()> {
+    clock.sleep_until(clock.now() + Duration::from(duration))
+}
+
+/// Runs `future` until `duration` has passed on `clock`, returning `None` if
+/// it didn't complete by then.
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: std::time::Duration,
+    future: F,
+) -> Option<F::Output> {
+    let sleep = sleep(clock, duration);
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = sleep => None,
+    }
+}
+
+struct VirtualState {
+    now: Instant,
+    next_id: u64,
+    sleepers: BTreeMap<u64, (Instant, Option<Waker>)>,
+}
+
+impl Default for VirtualState {
+    fn default() -> Self {
+        VirtualState {
+            now: Instant::ZERO,
+            next_id: 0,
+            sleepers: BTreeMap::new(),
+        }
+    }
+}
+
+/// A `Clock` that only advances when told to.
+///
+/// Cloning it gives another handle to the same clock, so one can be put in
+/// the `NetConfig` of every `Net` of a simulation while the test drives time,
+/// e.g. by advancing to `next_deadline` whenever the stacks are idle. Timeouts
+/// then fire instantly and in the same order on every run.
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    state: Arc<Mutex<VirtualState>>,
+}
+
+impl VirtualClock {
+    /// Makes a new `VirtualClock` at `Instant::ZERO`.
+    pub fn new() -> VirtualClock {
+        Self::default()
+    }
+    /// Advances the clock by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let now = self.state.lock().now;
+        self.advance_to(now + duration);
+    }
+    /// Advances the clock to `instant`, it never goes backwards.
+    pub fn advance_to(&self, instant: Instant) {
+        let mut wakers = Vec::new();
+        {
+            let mut state = self.state.lock();
+            if instant <= state.now {
+                return;
+            }
+            state.now = instant;
+            for (deadline, waker) in state.sleepers.values_mut() {
+                if *deadline <= instant {
+                    wakers.extend(waker.take());
+                }
+            }
+        }
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+    /// Returns the earliest deadline someone is sleeping until, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let state = self.state.lock();
+        state
+            .sleepers
+            .values()
+            .map(|(deadline, _)| *deadline)
+            .filter(|deadline| *deadline > state.now)
+            .min()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.state.lock().now
+    }
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sleepers.insert(id, (deadline, None));
+        Box::pin(VirtualSleep {
+            state: self.state.clone(),
+            id,
+        })
+    }
+}
+
+struct VirtualSleep {
+    state: Arc<Mutex<VirtualState>>,
+    id: u64,
+}
+
+impl Future for VirtualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock();
+        let now = state.now;
+        let Some((deadline, waker)) = state.sleepers.get_mut(&self.id) else {
+            return Poll::Ready(());
+        };
+        if *deadline <= now {
+            return Poll::Ready(());
+        }
+        *waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for VirtualSleep {
+    fn drop(&mut self) {
+        self.state.lock().sleepers.remove(&self.id);
+    }
+}
@@ -0,0 +1,153 @@
+//! A connector making the HTTP connections of hyper through a `Net`.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use tokio_smoltcp::Net;
+//! # async fn get(net: Arc<Net>) -> Result<(), Box<dyn std::error::Error>> {
+//! use http_body_util::Empty;
+//! use hyper::body::Bytes;
+//! use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+//! use tokio_smoltcp::connector::NetConnector;
+//!
+//! let connector = NetConnector::new(net);
+//! let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+//! let response = client.get("http://example.com/".parse()?).await?;
+//! println!("{}", response.status());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Net, TcpStream, clock::timeout};
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A `tower::Service<Uri>` connecting to the host of the URI through a `Net`.
+///
+/// The host is resolved with `Net::lookup`, by the DNS server of the
+/// `NetConfig`, unless it is an IP address. The connections are plain TCP, a TLS
+/// connector can wrap this one for HTTPS.
+#[derive(Clone)]
+pub struct NetConnector {
+    net: Arc<Net>,
+    connect_timeout: Option<Duration>,
+}
+
+impl NetConnector {
+    pub fn new(net: Arc<Net>) -> Self {
+        Self {
+            net,
+            connect_timeout: None,
+        }
+    }
+
+    /// Sets how long a connection may take to be established, no limit by
+    /// default.
+    pub fn set_connect_timeout(&mut self, connect_timeout: Option<Duration>) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    async fn connect(self, uri: Uri) -> io::Result<NetConnection> {
+        let host = uri
+            .host()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI without a host"))?;
+        let port = match uri.port_u16() {
+            Some(port) => port,
+            None if uri.scheme() == Some(&http::uri::Scheme::HTTPS) => 443,
+            None => 80,
+        };
+        // IPv6 hosts keep their brackets in URIs.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let ip = self.net.lookup(host).await?[0];
+
+        let connect = self.net.tcp_connect(SocketAddr::new(ip, port));
+        let stream = match self.connect_timeout {
+            Some(connect_timeout) => timeout(&**self.net.clock(), connect_timeout, connect)
+                .await
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))??,
+            None => connect.await?,
+        };
+        Ok(NetConnection { stream })
+    }
+}
+
+impl tower_service::Service<Uri> for NetConnector {
+    type Response = NetConnection;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<NetConnection>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+/// A connection made by a `NetConnector`.
+pub struct NetConnection {
+    stream: TcpStream,
+}
+
+impl NetConnection {
+    /// Returns the `TcpStream` of the connection.
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl Connection for NetConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl Read for NetConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        // SAFETY: `ReadBuf` only writes initialized bytes to the buffer, and
+        // `filled` counts the ones it wrote.
+        let filled = unsafe {
+            let mut read_buf = ReadBuf::uninit(buf.as_mut());
+            match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => read_buf.filled().len(),
+                other => return other,
+            }
+        };
+        // SAFETY: the first `filled` bytes were just initialized.
+        unsafe { buf.advance(filled) };
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Write for NetConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -1,23 +1,136 @@
-use futures::{Sink, Stream};
+use bytes::{BufMut, BytesMut};
+use futures::{Sink, Stream, ready};
 pub use smoltcp::phy::DeviceCapabilities;
 use smoltcp::{
     phy::{Device, RxToken, TxToken},
     time::Instant,
 };
-use std::{collections::VecDeque, io};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
 #[cfg(unix)]
 mod unix;
 #[cfg(unix)]
 pub use unix::*;
 
-pub use channel_capture::ChannelCapture;
+pub use channel_capture::{ChannelCapture, ChannelCaptureBuilder, DEFAULT_CHANNEL_CAPACITY};
 mod channel_capture;
 
+pub use pcap_replay::{PcapReplay, ReplayHandle};
+mod pcap_replay;
+
+pub use udp_tunnel::UdpTunnel;
+mod udp_tunnel;
+
+pub use framed::{Framed, Framing};
+mod framed;
+
+pub use vec_adapter::VecAdapter;
+mod vec_adapter;
+
+pub use bond::{Bond, BondBuilder, BondHandle, BondMode};
+mod bond;
+
+pub use vlan::{Vlan, VlanPort};
+mod vlan;
+
+#[cfg(target_os = "linux")]
+pub use tun::{Tun, TunBuilder};
+#[cfg(target_os = "linux")]
+mod tun;
+
+#[cfg(target_os = "linux")]
+pub use packet_socket::{PacketSocket, PacketSocketBuilder};
+#[cfg(target_os = "linux")]
+mod packet_socket;
+
+#[cfg(target_os = "linux")]
+mod offload;
+
 /// Default value of `max_burst_size`.
 pub const DEFAULT_MAX_BURST_SIZE: usize = 100;
 
+/// What to do when a packet is queued while the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait until there is room in the queue.
+    #[default]
+    Block,
+    /// Drop the oldest packet in the queue to make room.
+    DropOldest,
+}
+
 /// A packet used in `AsyncDevice`.
-pub type Packet = Vec<u8>;
+///
+/// Packets are usually split off a `PacketPool`, so they don't need an
+/// allocation of their own.
+pub type Packet = BytesMut;
+
+/// A pool of packet buffers.
+///
+/// Packets are split off a shared arena. Once every packet split from the arena
+/// is dropped, its memory is reclaimed for the next packets, so no allocation
+/// happens in the steady state.
+#[derive(Debug)]
+pub struct PacketPool {
+    arena: BytesMut,
+    capacity: usize,
+}
+
+impl PacketPool {
+    /// Make a new `PacketPool` whose arena holds `capacity` bytes.
+    pub fn new(capacity: usize) -> PacketPool {
+        PacketPool {
+            arena: BytesMut::with_capacity(capacity),
+            capacity,
+        }
+    }
+    /// Allocates a zeroed packet of `len` bytes.
+    ///
+    /// The packet has to be initialised since it's handed out as a `&mut [u8]`,
+    /// e.g. to smoltcp's `TxToken`. Packets copied from other buffers should
+    /// use `concat` instead, which doesn't write them twice.
+    pub fn alloc(&mut self, len: usize) -> Packet {
+        let buf = self.buffer(len);
+        buf.put_bytes(0, len);
+        buf.split()
+    }
+    /// Allocates a packet holding `parts` one after the other.
+    pub fn concat(&mut self, parts: &[&[u8]]) -> Packet {
+        let buf = self.buffer(parts.iter().map(|part| part.len()).sum());
+        for part in parts {
+            buf.extend_from_slice(part);
+        }
+        buf.split()
+    }
+    /// Returns an empty buffer with at least `len` bytes of spare capacity.
+    ///
+    /// Bytes written to the buffer are taken out as a packet with `BytesMut::split`,
+    /// the ones left in it are discarded by the next call.
+    pub fn buffer(&mut self, len: usize) -> &mut BytesMut {
+        self.arena.clear();
+        self.reserve(len);
+        &mut self.arena
+    }
+    /// Splits an empty packet with `capacity` bytes of spare capacity off the
+    /// arena, to be filled in place later.
+    ///
+    /// Bytes written to `buffer` but not taken out are discarded.
+    pub fn spare(&mut self, capacity: usize) -> Packet {
+        self.reserve(capacity);
+        self.arena.clear();
+        let rest = self.arena.split_off(capacity);
+        std::mem::replace(&mut self.arena, rest)
+    }
+    fn reserve(&mut self, len: usize) {
+        if self.arena.capacity() - self.arena.len() < len {
+            self.arena.reserve(self.capacity.max(len));
+        }
+    }
+}
 
 /// A device that send and receive packets asynchronously.
 pub trait AsyncDevice:
@@ -25,21 +138,101 @@ pub trait AsyncDevice:
 {
     /// Returns the device capabilities.
     fn capabilities(&self) -> &DeviceCapabilities;
+
+    /// Receives up to `max` packets at once, appending them to `buf`.
+    ///
+    /// Returns the number of packets received, or `None` when the device has
+    /// ended. The default implementation polls the stream until it would block.
+    fn poll_recv_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut VecDeque<Packet>,
+        max: usize,
+    ) -> Poll<Option<io::Result<usize>>> {
+        poll_recv_batch(self, cx, buf, max)
+    }
+
+    /// Sends the packets in `packets`, removing them as they are sent.
+    ///
+    /// Returns when every packet has been sent. The default implementation
+    /// feeds them one by one into the sink.
+    fn poll_send_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        packets: &mut VecDeque<Packet>,
+    ) -> Poll<io::Result<()>> {
+        poll_send_batch(self, cx, packets)
+    }
+}
+
+/// The default implementation of `AsyncDevice::poll_recv_batch`.
+pub fn poll_recv_batch<D: AsyncDevice + ?Sized>(
+    device: &mut D,
+    cx: &mut Context<'_>,
+    buf: &mut VecDeque<Packet>,
+    max: usize,
+) -> Poll<Option<io::Result<usize>>> {
+    let mut count = 0;
+    while count < max {
+        match Pin::new(&mut *device).poll_next(cx) {
+            Poll::Ready(Some(Ok(packet))) => {
+                buf.push_back(packet);
+                count += 1;
+            }
+            Poll::Ready(Some(Err(e))) if count == 0 => return Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) if count == 0 => return Poll::Ready(None),
+            Poll::Pending if count == 0 => return Poll::Pending,
+            _ => break,
+        }
+    }
+    Poll::Ready(Some(Ok(count)))
+}
+
+/// The default implementation of `AsyncDevice::poll_send_batch`.
+pub fn poll_send_batch<D: AsyncDevice + ?Sized>(
+    device: &mut D,
+    cx: &mut Context<'_>,
+    packets: &mut VecDeque<Packet>,
+) -> Poll<io::Result<()>> {
+    let mut device = Pin::new(device);
+    while !packets.is_empty() {
+        ready!(device.as_mut().poll_ready(cx))?;
+        device.as_mut().start_send(packets.pop_front().unwrap())?;
+    }
+    device.poll_flush(cx)
 }
 
 impl<T> AsyncDevice for Box<T>
 where
-    T: AsyncDevice,
+    T: AsyncDevice + ?Sized,
 {
     fn capabilities(&self) -> &DeviceCapabilities {
         (**self).capabilities()
     }
+
+    fn poll_recv_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut VecDeque<Packet>,
+        max: usize,
+    ) -> Poll<Option<io::Result<usize>>> {
+        (**self).poll_recv_batch(cx, buf, max)
+    }
+
+    fn poll_send_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        packets: &mut VecDeque<Packet>,
+    ) -> Poll<io::Result<()>> {
+        (**self).poll_send_batch(cx, packets)
+    }
 }
 
 /// A device that send and receive packets synchronously.
 pub struct BufferDevice {
     caps: DeviceCapabilities,
     max_burst_size: usize,
+    pool: PacketPool,
     recv_queue: VecDeque<Packet>,
     send_queue: VecDeque<Packet>,
 }
@@ -52,9 +245,7 @@ impl RxToken for BufferRxToken {
     where
         F: FnOnce(&[u8]) -> R,
     {
-        let p = &mut self.0;
-        let result = f(p);
-        result
+        f(&mut self.0)
     }
 }
 
@@ -66,7 +257,7 @@ impl<'d> TxToken for BufferTxToken<'d> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buffer = vec![0u8; len];
+        let mut buffer = self.0.pool.alloc(len);
         let result = f(&mut buffer);
 
         self.0.send_queue.push_back(buffer);
@@ -82,10 +273,9 @@ impl Device for BufferDevice {
         where Self:'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        match self.recv_queue.pop_front() {
-            Some(p) => Some((BufferRxToken(p), BufferTxToken(self))),
-            None => None,
-        }
+        self.recv_queue
+            .pop_front()
+            .map(|p| (BufferRxToken(p), BufferTxToken(self)))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
@@ -104,9 +294,11 @@ impl Device for BufferDevice {
 impl BufferDevice {
     pub(crate) fn new(caps: DeviceCapabilities) -> BufferDevice {
         let max_burst_size = caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        let pool = PacketPool::new(caps.max_transmission_unit * max_burst_size);
         BufferDevice {
             caps,
             max_burst_size,
+            pool,
             recv_queue: VecDeque::with_capacity(max_burst_size),
             send_queue: VecDeque::with_capacity(max_burst_size),
         }
@@ -127,3 +319,33 @@ impl BufferDevice {
         self.recv_queue.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_discards_leftovers() {
+        let mut pool = PacketPool::new(64);
+        pool.buffer(8).extend_from_slice(b"leftover");
+        assert_eq!(pool.alloc(4), [0; 4][..]);
+
+        pool.buffer(8).extend_from_slice(b"leftover");
+        assert_eq!(pool.concat(&[b"ab", b"cd"]), b"abcd"[..]);
+
+        pool.buffer(8).extend_from_slice(b"leftover");
+        let packet = pool.spare(16);
+        assert!(packet.is_empty());
+        assert!(packet.capacity() >= 16);
+    }
+
+    #[test]
+    fn pool_grows() {
+        let mut pool = PacketPool::new(16);
+        let packets: Vec<Packet> = (0..8u8).map(|i| pool.concat(&[&[i; 10]])).collect();
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet[..], [i as u8; 10]);
+        }
+        assert_eq!(pool.alloc(100).len(), 100);
+    }
+}
@@ -0,0 +1,443 @@
+use futures::{Sink, Stream, future::BoxFuture, ready};
+use smoltcp::phy::{DeviceCapabilities, Medium};
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{
+    Clock, TokioClock,
+    clock::sleep,
+    device::{AsyncDevice, DEFAULT_MAX_BURST_SIZE, Packet},
+    filter::Headers,
+};
+
+/// How a `Bond` distributes transmitted packets across its links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BondMode {
+    /// Send each packet on the next link that is up.
+    #[default]
+    RoundRobin,
+    /// Send every packet on the first link that is up, in the order the links
+    /// were added.
+    ActiveBackup,
+    /// Send the packets of a flow on the same link, chosen by hashing the
+    /// addresses, protocol and ports of the packet.
+    Hash,
+}
+
+type HealthCheck = Box<dyn FnMut(usize) -> bool + Send>;
+
+struct Link {
+    device: Box<dyn AsyncDevice>,
+    send_queue: VecDeque<Packet>,
+    ended: bool,
+}
+
+/// A device that presents several devices as one.
+///
+/// Transmitted packets are distributed across the links that are up according
+/// to the `BondMode`, and the packets received on every link are merged. A link
+/// goes down when sending on it or receiving from it fails, and comes back up
+/// when the health check or `BondHandle::set_up` says so. A link whose stream
+/// has ended never comes back. Packets sent while no link is up are dropped.
+pub struct Bond {
+    links: Vec<Link>,
+    mode: BondMode,
+    tx_next: usize,
+    rx_next: usize,
+    health_check: Option<(Duration, HealthCheck)>,
+    health_sleep: Option<BoxFuture<'static, ()>>,
+    clock: Arc<dyn Clock>,
+    handle: BondHandle,
+    dropped: Arc<AtomicU64>,
+    caps: DeviceCapabilities,
+}
+
+/// A builder for `Bond`.
+pub struct BondBuilder {
+    links: Vec<Box<dyn AsyncDevice>>,
+    mode: BondMode,
+    health_check: Option<(Duration, HealthCheck)>,
+    clock: Arc<dyn Clock>,
+    caps: DeviceCapabilities,
+}
+
+impl BondBuilder {
+    /// Sets how transmitted packets are distributed. Default to `BondMode::RoundRobin`.
+    pub fn mode(mut self, mode: BondMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Adds a link. Its medium must match the medium of the bond.
+    pub fn link(mut self, device: impl AsyncDevice + 'static) -> Self {
+        self.links.push(Box::new(device));
+        self
+    }
+
+    /// Calls `check` with the index of every link that hasn't ended each
+    /// `period`, and sets the link up or down according to the result.
+    pub fn health_check<F>(mut self, period: Duration, check: F) -> Self
+    where
+        F: FnMut(usize) -> bool + Send + 'static,
+    {
+        self.health_check = Some((period, Box::new(check)));
+        self
+    }
+
+    /// Sets the clock the health check is timed by, which should be the one
+    /// of the `Net`. Default to a `TokioClock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Builds the `Bond`, with every link up.
+    pub fn build(self) -> io::Result<Bond> {
+        if self.links.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a bond needs at least one link",
+            ));
+        }
+        if let Some(link) = self
+            .links
+            .iter()
+            .find(|link| link.capabilities().medium != self.caps.medium)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "link medium {:?} does not match medium {:?}",
+                    link.capabilities().medium,
+                    self.caps.medium
+                ),
+            ));
+        }
+
+        let handle = BondHandle {
+            up: self.links.iter().map(|_| AtomicBool::new(true)).collect(),
+        };
+        Ok(Bond {
+            links: self
+                .links
+                .into_iter()
+                .map(|device| Link {
+                    device,
+                    send_queue: VecDeque::new(),
+                    ended: false,
+                })
+                .collect(),
+            mode: self.mode,
+            tx_next: 0,
+            rx_next: 0,
+            health_check: self.health_check,
+            health_sleep: None,
+            clock: self.clock,
+            handle,
+            dropped: Arc::new(AtomicU64::new(0)),
+            caps: self.caps,
+        })
+    }
+}
+
+/// A handle to observe and control the links of a `Bond` after it is moved
+/// into a `Net`.
+#[derive(Clone)]
+pub struct BondHandle {
+    up: Arc<[AtomicBool]>,
+}
+
+impl BondHandle {
+    /// Returns the number of links.
+    pub fn len(&self) -> usize {
+        self.up.len()
+    }
+
+    /// Returns `true` if the bond has no link.
+    pub fn is_empty(&self) -> bool {
+        self.up.is_empty()
+    }
+
+    /// Returns whether the link at `index` is up.
+    pub fn is_up(&self, index: usize) -> bool {
+        self.up[index].load(Ordering::Relaxed)
+    }
+
+    /// Sets the link at `index` up or down. A link whose stream has ended is
+    /// never used again.
+    pub fn set_up(&self, index: usize, up: bool) {
+        self.up[index].store(up, Ordering::Relaxed);
+    }
+
+    /// Returns the link used in `BondMode::ActiveBackup`, if any is up.
+    pub fn active(&self) -> Option<usize> {
+        (0..self.len()).find(|&i| self.is_up(i))
+    }
+}
+
+impl Bond {
+    /// Make a new `Bond` over `links`, distributing packets with `mode`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set,
+    /// and should not exceed the MTU of any link.
+    pub fn new(
+        links: Vec<Box<dyn AsyncDevice>>,
+        mode: BondMode,
+        caps: DeviceCapabilities,
+    ) -> io::Result<Bond> {
+        BondBuilder {
+            links,
+            mode,
+            health_check: None,
+            clock: Arc::new(TokioClock::new()),
+            caps,
+        }
+        .build()
+    }
+
+    /// Returns a builder to configure a `Bond`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set,
+    /// and should not exceed the MTU of any link.
+    pub fn builder(caps: DeviceCapabilities) -> BondBuilder {
+        BondBuilder {
+            links: Vec::new(),
+            mode: BondMode::default(),
+            health_check: None,
+            clock: Arc::new(TokioClock::new()),
+            caps,
+        }
+    }
+
+    /// Returns a handle to observe and control the links.
+    pub fn handle(&self) -> BondHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the number of packets dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped packets, which can be read after the
+    /// device is moved into a `Net`.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    fn is_up(&self, index: usize) -> bool {
+        !self.links[index].ended && self.handle.is_up(index)
+    }
+
+    fn set_down(&mut self, index: usize) {
+        self.handle.set_up(index, false);
+        let queue = &mut self.links[index].send_queue;
+        self.dropped
+            .fetch_add(queue.len() as u64, Ordering::Relaxed);
+        queue.clear();
+    }
+
+    fn poll_health_check(&mut self, cx: &mut Context<'_>) {
+        let Some((period, check)) = &mut self.health_check else {
+            return;
+        };
+        loop {
+            // The first check runs at once, the next ones a period after the
+            // previous.
+            if let Some(sleep) = &mut self.health_sleep
+                && sleep.as_mut().poll(cx).is_pending()
+            {
+                return;
+            }
+            self.health_sleep = Some(sleep(&*self.clock, *period));
+            for (index, link) in self.links.iter().enumerate() {
+                if !link.ended {
+                    self.handle.set_up(index, check(index));
+                }
+            }
+        }
+    }
+
+    /// Returns the link to send `packet` on.
+    fn select(&mut self, packet: &[u8]) -> Option<usize> {
+        let up: Vec<usize> = (0..self.links.len()).filter(|&i| self.is_up(i)).collect();
+        if up.is_empty() {
+            return None;
+        }
+        let index = match self.mode {
+            BondMode::RoundRobin => {
+                let index = up
+                    .iter()
+                    .copied()
+                    .find(|&i| i >= self.tx_next)
+                    .unwrap_or(up[0]);
+                self.tx_next = index + 1;
+                index
+            }
+            BondMode::ActiveBackup => up[0],
+            BondMode::Hash => up[(flow_hash(self.caps.medium, packet) % up.len() as u64) as usize],
+        };
+        Some(index)
+    }
+
+    fn enqueue(&mut self, packet: Packet) {
+        match self.select(&packet) {
+            Some(index) => self.links[index].send_queue.push_back(packet),
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Sends the queued packets of every link. Links that fail are set down.
+    fn poll_send_queues(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut pending = false;
+        for index in 0..self.links.len() {
+            let link = &mut self.links[index];
+            if link.send_queue.is_empty() {
+                continue;
+            }
+            match link.device.poll_send_batch(cx, &mut link.send_queue) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(_)) => self.set_down(index),
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Hashes the addresses, protocol and ports of `packet`.
+fn flow_hash(medium: Medium, packet: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let Some(ip) = Headers::parse(medium, packet).ip else {
+        // Not IP, hash the link-layer addresses.
+        packet.get(..12).unwrap_or(packet).hash(&mut hasher);
+        return hasher.finish();
+    };
+    ip.src.hash(&mut hasher);
+    ip.dst.hash(&mut hasher);
+    ip.protocol.hash(&mut hasher);
+    // The fragments of a datagram are kept together, though only the first
+    // one carries the ports.
+    if !ip.fragment {
+        ip.ports.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl Stream for Bond {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.poll_health_check(cx);
+
+        let count = this.links.len();
+        for offset in 0..count {
+            let index = (this.rx_next + offset) % count;
+            let link = &mut this.links[index];
+            if link.ended {
+                continue;
+            }
+            match Pin::new(&mut link.device).poll_next(cx) {
+                Poll::Ready(Some(Ok(packet))) => {
+                    // Start with the next link, so a busy link doesn't starve the others.
+                    this.rx_next = index + 1;
+                    return Poll::Ready(Some(Ok(packet)));
+                }
+                Poll::Ready(Some(Err(_))) => this.set_down(index),
+                Poll::Ready(None) => {
+                    link.ended = true;
+                    this.set_down(index);
+                }
+                Poll::Pending => {}
+            }
+        }
+
+        if this.links.iter().all(|link| link.ended) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Sink<Packet> for Bond {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let max_burst_size = self.caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        let queued: usize = self.links.iter().map(|link| link.send_queue.len()).sum();
+        if queued >= max_burst_size {
+            ready!(self.poll_send_queues(cx));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        self.enqueue(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_send_queues(cx).map(Ok)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncDevice for Bond {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+
+    fn poll_send_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        packets: &mut VecDeque<Packet>,
+    ) -> Poll<io::Result<()>> {
+        let max_burst_size = self.caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        loop {
+            // Queue at most `max_burst_size` packets per link, the rest waits
+            // in `packets` for the link to catch up.
+            while let Some(packet) = packets.front() {
+                let tx_next = self.tx_next;
+                match self.select(packet) {
+                    Some(index) if self.links[index].send_queue.len() >= max_burst_size => {
+                        self.tx_next = tx_next;
+                        break;
+                    }
+                    Some(index) => {
+                        let packet = packets.pop_front().unwrap();
+                        self.links[index].send_queue.push_back(packet);
+                    }
+                    None => {
+                        packets.pop_front();
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            ready!(self.poll_send_queues(cx));
+            if packets.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
@@ -1,74 +1,263 @@
-use futures::{Sink, Stream};
+use futures::{Sink, Stream, ready};
 use smoltcp::phy::DeviceCapabilities;
 use std::{
+    collections::VecDeque,
+    future::Future,
     io,
     pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
     task::{Context, Poll},
 };
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender, channel};
 use tokio_util::sync::{PollSendError, PollSender};
 
-use crate::device::AsyncDevice;
+use crate::device::{AsyncDevice, OverflowPolicy, Packet, vec_adapter::packet_from_vec};
+
+/// Default capacity of the channels of a `ChannelCapture`.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1000;
+
+/// A queue in front of a channel under `OverflowPolicy::DropOldest`, dropping
+/// its oldest value when full.
+struct DropOldest<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<T> DropOldest<T> {
+    fn new(capacity: usize, dropped: Arc<AtomicU64>) -> Self {
+        DropOldest {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped,
+        }
+    }
+
+    fn push(&mut self, value: T) {
+        if self.queue.len() >= self.capacity {
+            self.queue.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.queue.push_back(value);
+    }
+}
+
+type RecvSender = Sender<io::Result<Vec<u8>>>;
+type SendReceiver = Receiver<Vec<u8>>;
 
 /// A device that send and receive packets using a channel.
 pub struct ChannelCapture {
     recv: Receiver<io::Result<Vec<u8>>>,
     send: PollSender<Vec<u8>>,
+    send_queue: Option<DropOldest<Vec<u8>>>,
+    dropped: Arc<AtomicU64>,
     caps: DeviceCapabilities,
 }
 
-impl ChannelCapture {
-    /// Make a new `ChannelCapture` with the given `recv` and `send` channels.
+/// A builder for `ChannelCapture`.
+pub struct ChannelCaptureBuilder {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    caps: DeviceCapabilities,
+}
+
+impl ChannelCaptureBuilder {
+    /// Sets the capacity of each channel. Default to `DEFAULT_CHANNEL_CAPACITY`.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets what to do when the send channel is full. Default to `OverflowPolicy::Block`.
     ///
-    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
-    pub fn new<R, S>(recv: R, send: S, caps: DeviceCapabilities) -> Self
+    /// With `OverflowPolicy::DropOldest`, the packets waiting for room in the
+    /// channel are queued up to the capacity, dropping the oldest ones. It only
+    /// applies to the send channel: the recv closure decides itself what to do
+    /// when the stack falls behind, with `Sender::try_send` for instance.
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Runs `recv` and `send` on their own OS threads.
+    ///
+    /// They should use the blocking methods of the channels. Their exit is
+    /// reported as a `BrokenPipe` error of the stream.
+    pub fn spawn_threads<R, S>(self, recv: R, send: S) -> ChannelCapture
     where
         S: FnOnce(Receiver<Vec<u8>>) + Send + 'static,
         R: FnOnce(Sender<io::Result<Vec<u8>>>) + Send + 'static,
     {
-        let (tx1, rx1) = channel(1000);
-        let (tx2, rx2) = channel(1000);
+        let (capture, recv_tx, send_rx) = self.build();
+        // Weak, so the stream still ends once the recv thread exits.
+        let send_tx = recv_tx.downgrade();
         std::thread::spawn(move || {
-            recv(tx2);
-            eprintln!("Recv thread exited")
+            recv(recv_tx.clone());
+            let _ = recv_tx.blocking_send(Err(exited("Recv thread exited")));
         });
         std::thread::spawn(move || {
-            send(rx1);
-            eprintln!("Send thread exited")
+            send(send_rx);
+            if let Some(send_tx) = send_tx.upgrade() {
+                let _ = send_tx.blocking_send(Err(exited("Send thread exited")));
+            }
+        });
+        capture
+    }
+
+    /// Runs `recv` and `send` as tasks on the current tokio runtime.
+    ///
+    /// Their exit is reported as a `BrokenPipe` error of the stream.
+    pub fn spawn_tasks<R, RF, S, SF>(self, recv: R, send: S) -> ChannelCapture
+    where
+        S: FnOnce(Receiver<Vec<u8>>) -> SF,
+        SF: Future<Output = ()> + Send + 'static,
+        R: FnOnce(Sender<io::Result<Vec<u8>>>) -> RF,
+        RF: Future<Output = ()> + Send + 'static,
+    {
+        let (capture, recv_tx, send_rx) = self.build();
+        // Weak, so the stream still ends once the recv task exits.
+        let send_tx = recv_tx.downgrade();
+        let recv = recv(recv_tx.clone());
+        let send = send(send_rx);
+        tokio::spawn(async move {
+            recv.await;
+            let _ = recv_tx.send(Err(exited("Recv task exited"))).await;
+        });
+        tokio::spawn(async move {
+            send.await;
+            if let Some(send_tx) = send_tx.upgrade() {
+                let _ = send_tx.send(Err(exited("Send task exited"))).await;
+            }
         });
-        ChannelCapture {
-            send: PollSender::new(tx1),
-            recv: rx2,
+        capture
+    }
+
+    fn build(self) -> (ChannelCapture, RecvSender, SendReceiver) {
+        let (tx1, rx1) = channel(self.capacity);
+        let (tx2, rx2) = channel(self.capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        (
+            ChannelCapture {
+                send: PollSender::new(tx1),
+                recv: rx2,
+                send_queue: (self.overflow == OverflowPolicy::DropOldest)
+                    .then(|| DropOldest::new(self.capacity, dropped.clone())),
+                dropped,
+                caps: self.caps,
+            },
+            tx2,
+            rx1,
+        )
+    }
+}
+
+fn exited(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, msg.to_string())
+}
+
+impl ChannelCapture {
+    /// Make a new `ChannelCapture` with the given `recv` and `send` channels.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn new<R, S>(recv: R, send: S, caps: DeviceCapabilities) -> Self
+    where
+        S: FnOnce(Receiver<Vec<u8>>) + Send + 'static,
+        R: FnOnce(Sender<io::Result<Vec<u8>>>) + Send + 'static,
+    {
+        Self::builder(caps).spawn_threads(recv, send)
+    }
+
+    /// Returns a builder to configure the channels of a `ChannelCapture`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn builder(caps: DeviceCapabilities) -> ChannelCaptureBuilder {
+        ChannelCaptureBuilder {
+            capacity: DEFAULT_CHANNEL_CAPACITY,
+            overflow: OverflowPolicy::default(),
             caps,
         }
     }
+
+    /// Returns the number of packets to send dropped by `OverflowPolicy::DropOldest`.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped packets, which can be read after the
+    /// device is moved into a `Net`.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    /// Moves the packets of `send_queue` into the channel until it is full.
+    fn poll_send_queue(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(queue) = &mut self.send_queue else {
+            return Poll::Ready(Ok(()));
+        };
+        while !queue.queue.is_empty() {
+            ready!(self.send.poll_reserve(cx)).map_err(map_err)?;
+            let packet = queue.queue.pop_front().unwrap();
+            self.send.send_item(packet).map_err(map_err)?;
+        }
+        Poll::Ready(Ok(()))
+    }
 }
 
 impl Stream for ChannelCapture {
-    type Item = io::Result<Vec<u8>>;
+    type Item = io::Result<Packet>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.recv.poll_recv(cx)
+        // The send queue waits for room in the channel while the stack only
+        // receives.
+        if let Poll::Ready(Err(e)) = self.poll_send_queue(cx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let packet = ready!(self.recv.poll_recv(cx));
+        Poll::Ready(packet.map(|p| p.map(packet_from_vec)))
     }
 }
 
 fn map_err(e: PollSendError<Vec<u8>>) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, e)
+    io::Error::new(io::ErrorKind::BrokenPipe, e)
 }
 
-impl Sink<Vec<u8>> for ChannelCapture {
+impl Sink<Packet> for ChannelCapture {
     type Error = io::Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.send_queue.is_some() {
+            if let Poll::Ready(result) = self.poll_send_queue(cx) {
+                result?;
+            }
+            return Poll::Ready(Ok(()));
+        }
         self.send.poll_reserve(cx).map_err(map_err)
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        self.send.send_item(item).map_err(map_err)
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        match &mut self.send_queue {
+            Some(queue) => {
+                queue.push(item.into());
+                Ok(())
+            }
+            None => self.send.send_item(item.into()).map_err(map_err),
+        }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.send.poll_reserve(cx).map_err(map_err)
+        if self.send_queue.is_some() {
+            return match self.poll_send_queue(cx) {
+                // Don't hold the reactor back, the queue is drained when the stream is polled.
+                Poll::Pending => Poll::Ready(Ok(())),
+                r => r,
+            };
+        }
+        // Packets are sent as soon as they're started, and a slot reserved
+        // here would hold the channel for nothing.
+        Poll::Ready(Ok(()))
     }
 
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -0,0 +1,336 @@
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{Sink, Stream};
+use smoltcp::phy::DeviceCapabilities;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{self, Decoder, Encoder};
+
+use crate::device::{AsyncDevice, Packet};
+
+const MAX_FRAME_SIZE: usize = 65535;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+/// The framing used to carry packets over a byte stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Each packet is prefixed by its length as a big-endian `u16`.
+    LengthU16,
+    /// Each packet is prefixed by its length as a big-endian `u32`.
+    LengthU32,
+    /// Packets are delimited as described in RFC 1055.
+    Slip,
+    /// The format of QEMU's `-netdev socket` and `-netdev stream` backends.
+    Qemu,
+}
+
+/// A codec that splits a byte stream into packets according to a `Framing`.
+#[derive(Debug, Clone)]
+struct FrameCodec {
+    framing: Framing,
+}
+
+impl FrameCodec {
+    fn new(framing: Framing) -> Self {
+        FrameCodec { framing }
+    }
+
+    fn decode_length(&self, src: &mut BytesMut, prefix: usize) -> io::Result<Option<Packet>> {
+        if src.len() < prefix {
+            return Ok(None);
+        }
+        let len = match prefix {
+            2 => u16::from_be_bytes([src[0], src[1]]) as usize,
+            _ => u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize,
+        };
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes is too large", len),
+            ));
+        }
+        if src.len() < prefix + len {
+            src.reserve(prefix + len - src.len());
+            return Ok(None);
+        }
+        src.advance(prefix);
+        Ok(Some(src.split_to(len)))
+    }
+
+    fn decode_slip(&self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        loop {
+            let end = match src.iter().position(|b| *b == SLIP_END) {
+                Some(end) => end,
+                // Every byte may be escaped into two.
+                None if src.len() > 2 * MAX_FRAME_SIZE => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "SLIP frame is too large",
+                    ));
+                }
+                None => return Ok(None),
+            };
+            let mut packet = src.split_to(end + 1);
+            // Empty frames are produced by the leading END used to flush line noise.
+            if end == 0 {
+                continue;
+            }
+
+            // Unescape in place, the packet can only shrink.
+            let mut len = 0;
+            let mut escaped = false;
+            for i in 0..end {
+                let b = match (escaped, packet[i]) {
+                    (false, SLIP_ESC) => {
+                        escaped = true;
+                        continue;
+                    }
+                    (true, SLIP_ESC_END) => SLIP_END,
+                    (true, SLIP_ESC_ESC) => SLIP_ESC,
+                    (_, b) => b,
+                };
+                packet[len] = b;
+                len += 1;
+                escaped = false;
+            }
+            packet.truncate(len);
+            return Ok(Some(packet));
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        match self.framing {
+            Framing::LengthU16 => self.decode_length(src, 2),
+            Framing::LengthU32 | Framing::Qemu => self.decode_length(src, 4),
+            Framing::Slip => self.decode_slip(src),
+        }
+    }
+}
+
+impl Encoder<Packet> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> io::Result<()> {
+        // The decoder of the peer would fail on a larger frame, ending its
+        // stream.
+        if item.len() > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("packet of {} bytes is too large", item.len()),
+            ));
+        }
+        match self.framing {
+            Framing::LengthU16 => {
+                dst.reserve(2 + item.len());
+                dst.put_u16(item.len() as u16);
+                dst.put_slice(&item);
+            }
+            Framing::LengthU32 | Framing::Qemu => {
+                dst.reserve(4 + item.len());
+                dst.put_u32(item.len() as u32);
+                dst.put_slice(&item);
+            }
+            Framing::Slip => {
+                dst.reserve(item.len() + 2);
+                dst.put_u8(SLIP_END);
+                for b in item {
+                    match b {
+                        SLIP_END => dst.put_slice(&[SLIP_ESC, SLIP_ESC_END]),
+                        SLIP_ESC => dst.put_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+                        b => dst.put_u8(b),
+                    }
+                }
+                dst.put_u8(SLIP_END);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A device that sends and receives packets framed over a byte stream.
+///
+/// It can be used with anything implementing `AsyncRead + AsyncWrite`, such as
+/// a serial line, the stdio of a child process, a TCP connection or a Unix
+/// socket. The device ends when the stream reaches EOF.
+pub struct Framed<T> {
+    inner: codec::Framed<T, FrameCodec>,
+    caps: DeviceCapabilities,
+}
+
+impl<T> Framed<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    /// Make a new `Framed` over `io` using the given `framing`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set,
+    /// and must not exceed 65535, the largest frame sent or received.
+    pub fn new(io: T, framing: Framing, caps: DeviceCapabilities) -> Self {
+        Framed {
+            inner: codec::Framed::new(io, FrameCodec::new(framing)),
+            caps,
+        }
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref()
+    }
+
+    /// Consumes the `Framed`, returning the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T> Stream for Framed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl<T> Sink<Packet> for Framed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<T> AsyncDevice for Framed<T>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAMINGS: [Framing; 4] = [
+        Framing::LengthU16,
+        Framing::LengthU32,
+        Framing::Slip,
+        Framing::Qemu,
+    ];
+
+    fn encode(framing: Framing, packets: &[&[u8]]) -> BytesMut {
+        let mut codec = FrameCodec::new(framing);
+        let mut dst = BytesMut::new();
+        for packet in packets {
+            codec.encode(Packet::from(*packet), &mut dst).unwrap();
+        }
+        dst
+    }
+
+    fn decode_all(framing: Framing, src: &mut BytesMut) -> Vec<Packet> {
+        let mut codec = FrameCodec::new(framing);
+        let mut packets = Vec::new();
+        while let Some(packet) = codec.decode(src).unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn round_trip() {
+        let escapes = [SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC, 0, SLIP_END];
+        let large = vec![SLIP_ESC; MAX_FRAME_SIZE];
+        let packets: [&[u8]; 4] = [b"hello", &escapes, &[], &large];
+        for framing in FRAMINGS {
+            let mut src = encode(framing, &packets);
+            let decoded = decode_all(framing, &mut src);
+            // An empty SLIP frame can't be told from the END flushing noise.
+            let expected: Vec<&[u8]> = packets
+                .iter()
+                .filter(|p| framing != Framing::Slip || !p.is_empty())
+                .copied()
+                .collect();
+            assert_eq!(decoded, expected, "{:?}", framing);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn partial_frames() {
+        for framing in FRAMINGS {
+            let encoded = encode(framing, &[b"first", b"second"]);
+            let mut codec = FrameCodec::new(framing);
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            // Fed one byte at a time, like a slow stream.
+            for b in encoded {
+                src.put_u8(b);
+                decoded.extend(codec.decode(&mut src).unwrap());
+            }
+            assert_eq!(decoded, [&b"first"[..], b"second"], "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn slip_noise() {
+        // Leading ENDs and a stray escape are tolerated.
+        let mut src = BytesMut::from(&[SLIP_END, SLIP_END, 1, SLIP_ESC, 2, SLIP_END][..]);
+        assert_eq!(decode_all(Framing::Slip, &mut src), [&[1, 2][..]]);
+    }
+
+    #[test]
+    fn oversize_frames() {
+        for framing in FRAMINGS {
+            let mut codec = FrameCodec::new(framing);
+            let err = codec
+                .encode(
+                    Packet::from(&vec![0; MAX_FRAME_SIZE + 1][..]),
+                    &mut BytesMut::new(),
+                )
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", framing);
+        }
+
+        for framing in [Framing::LengthU32, Framing::Qemu] {
+            let mut src = BytesMut::new();
+            src.put_u32(MAX_FRAME_SIZE as u32 + 1);
+            let err = FrameCodec::new(framing).decode(&mut src).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", framing);
+        }
+
+        let mut src = BytesMut::from(&vec![0; 2 * MAX_FRAME_SIZE + 1][..]);
+        let err = FrameCodec::new(Framing::Slip).decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
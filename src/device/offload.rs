@@ -0,0 +1,709 @@
+use std::collections::VecDeque;
+
+use crate::device::{Packet, PacketPool};
+
+/// The length of `struct virtio_net_hdr`.
+pub(crate) const VNET_HDR_LEN: usize = 10;
+
+/// The largest IP packet carried with a virtio-net header.
+pub(crate) const MAX_GSO_LEN: usize = 65535;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_IPV6: [u8; 2] = [0x86, 0xdd];
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_URG: u8 = 0x20;
+const TCP_CWR: u8 = 0x80;
+
+/// A `struct virtio_net_hdr`, in native endianness.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct VnetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHdr {
+    pub(crate) fn decode(buf: &[u8]) -> VnetHdr {
+        let u16_at = |i: usize| u16::from_ne_bytes([buf[i], buf[i + 1]]);
+        VnetHdr {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; VNET_HDR_LEN] {
+        let mut buf = [0; VNET_HDR_LEN];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        buf
+    }
+}
+
+/// The layout of a TCP or UDP packet which is not an IP fragment.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    l3: usize,
+    l4: usize,
+    /// The end of the transport header.
+    payload: usize,
+    /// The end of the IP packet, before any link layer padding.
+    end: usize,
+    proto: u8,
+    v6: bool,
+}
+
+impl Layout {
+    fn parse(frame: &[u8], l3: usize) -> Option<Layout> {
+        if l3 > 0 {
+            let ethertype = frame.get(l3 - 2..l3)?;
+            if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                return None;
+            }
+        }
+        let ip = frame.get(l3..)?;
+        let (l4, proto, end, v6) = match ip.first()? >> 4 {
+            4 if ip.len() >= 20 => {
+                // Fragments can't be offloaded.
+                if u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0 {
+                    return None;
+                }
+                let ihl = (ip[0] & 0xf) as usize * 4;
+                let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+                (l3 + ihl, ip[9], l3 + total_len, false)
+            }
+            6 if ip.len() >= 40 => {
+                let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+                (l3 + 40, ip[6], l3 + 40 + payload_len, true)
+            }
+            _ => return None,
+        };
+        let payload = match proto {
+            PROTO_TCP => l4 + (*frame.get(l4 + 12)? >> 4) as usize * 4,
+            PROTO_UDP => l4 + 8,
+            _ => return None,
+        };
+        let layout = Layout {
+            l3,
+            l4,
+            payload,
+            end,
+            proto,
+            v6,
+        };
+        // The headers are indexed without bounds checks from here on, a
+        // malformed packet of the peer mustn't panic the reactor.
+        if l4 < l3 + 20
+            || (layout.is_tcp() && payload < l4 + 20)
+            || l4 + layout.csum_offset() + 2 > end
+            || payload > end
+            || end > frame.len()
+        {
+            return None;
+        }
+        Some(layout)
+    }
+
+    fn is_tcp(&self) -> bool {
+        self.proto == PROTO_TCP
+    }
+
+    fn csum_offset(&self) -> usize {
+        if self.is_tcp() { 16 } else { 6 }
+    }
+
+    fn payload_len(&self) -> usize {
+        self.end - self.payload
+    }
+
+    /// Returns the sum of the pseudo-header for a transport packet of `len` bytes.
+    fn pseudo_header(&self, frame: &[u8], len: usize) -> u32 {
+        let addrs = if self.v6 {
+            &frame[self.l3 + 8..self.l3 + 40]
+        } else {
+            &frame[self.l3 + 12..self.l3 + 20]
+        };
+        sum(addrs) + self.proto as u32 + len as u32
+    }
+
+    /// Writes the partial checksum expected along `VIRTIO_NET_HDR_F_NEEDS_CSUM`.
+    fn write_partial_checksum(&self, frame: &mut [u8]) {
+        let partial = fold(self.pseudo_header(frame, self.end - self.l4));
+        let at = self.l4 + self.csum_offset();
+        frame[at..at + 2].copy_from_slice(&partial.to_be_bytes());
+    }
+
+    fn verify_checksum(&self, frame: &[u8]) -> bool {
+        let at = self.l4 + self.csum_offset();
+        if !self.is_tcp() && !self.v6 && frame[at..at + 2] == [0, 0] {
+            return true;
+        }
+        let sum = self.pseudo_header(frame, self.end - self.l4) + sum(&frame[self.l4..self.end]);
+        fold(sum) == 0xffff
+    }
+
+    /// Updates the lengths of the IP header after the packet was resized to `frame.len()`.
+    fn set_len(&mut self, frame: &mut [u8]) {
+        self.end = frame.len();
+        let ip_len = self.end - self.l3;
+        if self.v6 {
+            frame[self.l3 + 4..self.l3 + 6].copy_from_slice(&((ip_len - 40) as u16).to_be_bytes());
+        } else {
+            frame[self.l3 + 2..self.l3 + 4].copy_from_slice(&(ip_len as u16).to_be_bytes());
+            self.fill_ipv4_checksum(frame);
+        }
+    }
+
+    fn fill_ipv4_checksum(&self, frame: &mut [u8]) {
+        frame[self.l3 + 10..self.l3 + 12].copy_from_slice(&[0, 0]);
+        let checksum = !fold(sum(&frame[self.l3..self.l4]));
+        frame[self.l3 + 10..self.l3 + 12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn headers_match(&self, a: &[u8], b: &[u8]) -> bool {
+        let (l3, l4) = (self.l3, self.l4);
+        let ip_match = if self.v6 {
+            // Everything but the payload length.
+            a[l3..l3 + 4] == b[l3..l3 + 4] && a[l3 + 6..l4] == b[l3 + 6..l4]
+        } else {
+            // Everything but the total length, the identification and the checksum.
+            a[l3..l3 + 2] == b[l3..l3 + 2]
+                && a[l3 + 6..l3 + 10] == b[l3 + 6..l3 + 10]
+                && a[l3 + 12..l4] == b[l3 + 12..l4]
+        };
+        // Everything but the sequence number, the flags and the checksum.
+        a[..l3] == b[..l3]
+            && ip_match
+            && a[l4..l4 + 4] == b[l4..l4 + 4]
+            && a[l4 + 8..l4 + 13] == b[l4 + 8..l4 + 13]
+            && a[l4 + 14..l4 + 16] == b[l4 + 14..l4 + 16]
+            && a[l4 + 18..self.payload] == b[l4 + 18..self.payload]
+    }
+}
+
+fn sum(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum = chunks
+        .by_ref()
+        .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+        .fold(0u32, |acc, w| acc.wrapping_add(w));
+    if let [b] = chunks.remainder() {
+        sum += (*b as u32) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn seq(frame: &[u8], layout: &Layout) -> u32 {
+    let at = layout.l4 + 4;
+    u32::from_be_bytes([frame[at], frame[at + 1], frame[at + 2], frame[at + 3]])
+}
+
+/// Computes the TCP or UDP checksum of a frame whose checksum was left to the
+/// device, as indicated by `TP_STATUS_CSUMNOTREADY` on packet sockets.
+pub(crate) fn fill_checksum(frame: &mut [u8], l3: usize) {
+    let Some(layout) = Layout::parse(frame, l3) else {
+        return;
+    };
+    let at = layout.l4 + layout.csum_offset();
+    frame[at..at + 2].copy_from_slice(&[0, 0]);
+    let sum =
+        layout.pseudo_header(frame, layout.end - layout.l4) + sum(&frame[layout.l4..layout.end]);
+    let checksum = match !fold(sum) {
+        0 if !layout.is_tcp() => 0xffff,
+        checksum => checksum,
+    };
+    frame[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Turns a frame received after a virtio-net header into packets.
+///
+/// TCP super-packets are split into segments of `gso_size` bytes. Packets whose
+/// checksum was neither validated nor left to compute are verified here, as
+/// `Tun` tells smoltcp not to check TCP and UDP checksums.
+pub(crate) fn receive(
+    hdr: &VnetHdr,
+    frame: Packet,
+    l3: usize,
+    pool: &mut PacketPool,
+    out: &mut VecDeque<Packet>,
+) {
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            let unchecked =
+                hdr.flags & (VIRTIO_NET_HDR_F_NEEDS_CSUM | VIRTIO_NET_HDR_F_DATA_VALID) == 0;
+            if unchecked
+                && let Some(layout) = Layout::parse(&frame, l3)
+                && !layout.verify_checksum(&frame)
+            {
+                return;
+            }
+            out.push_back(frame);
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+            split(&frame, hdr.gso_size as usize, l3, pool, out)
+        }
+        // UDP segmentation isn't negotiated.
+        _ => {}
+    }
+}
+
+fn split(frame: &[u8], mss: usize, l3: usize, pool: &mut PacketPool, out: &mut VecDeque<Packet>) {
+    let Some(layout) = Layout::parse(frame, l3).filter(|l| l.is_tcp()) else {
+        return;
+    };
+    if mss == 0 {
+        return;
+    }
+    let hdr_len = layout.payload;
+    let first_seq = seq(frame, &layout);
+    let flags = frame[layout.l4 + 13];
+    let ident = u16::from_be_bytes([frame[l3 + 4], frame[l3 + 5]]);
+
+    let payload = &frame[hdr_len..layout.end];
+    let count = payload.len().div_ceil(mss);
+    for (i, chunk) in payload.chunks(mss).enumerate() {
+        let mut segment = pool.concat(&[&frame[..hdr_len], chunk]);
+
+        let mut layout = layout;
+        if !layout.v6 {
+            let ident = ident.wrapping_add(i as u16);
+            segment[l3 + 4..l3 + 6].copy_from_slice(&ident.to_be_bytes());
+        }
+        layout.set_len(&mut segment);
+
+        let seq = first_seq.wrapping_add((i * mss) as u32);
+        segment[layout.l4 + 4..layout.l4 + 8].copy_from_slice(&seq.to_be_bytes());
+        let mut flags = flags;
+        if i + 1 < count {
+            flags &= !(TCP_FIN | TCP_PSH);
+        }
+        if i > 0 {
+            flags &= !TCP_CWR;
+        }
+        segment[layout.l4 + 13] = flags;
+        out.push_back(segment);
+    }
+}
+
+/// A TCP super-packet being built.
+struct Pending {
+    frame: Packet,
+    layout: Layout,
+    mss: usize,
+    segments: usize,
+    next_seq: u32,
+    /// Whether a segment shorter than `mss` or ending a push was appended.
+    closed: bool,
+}
+
+/// Coalesces consecutive TCP segments of a flow into GSO super-packets, and
+/// leaves the TCP and UDP checksums to the kernel.
+pub(crate) struct Coalescer {
+    l3: usize,
+    pending: Option<Pending>,
+}
+
+impl Coalescer {
+    pub(crate) fn new(l3: usize) -> Coalescer {
+        Coalescer { l3, pending: None }
+    }
+
+    /// Adds a packet, pushing the frames ready to be written to `out`.
+    ///
+    /// The super-packets are built in buffers allocated from `pool`.
+    pub(crate) fn push(
+        &mut self,
+        packet: Packet,
+        pool: &mut PacketPool,
+        out: &mut VecDeque<(VnetHdr, Packet)>,
+    ) {
+        let layout = Layout::parse(&packet, self.l3);
+
+        if let (Some(pending), Some(layout)) = (&mut self.pending, &layout)
+            && pending.can_append(&packet, layout)
+        {
+            pending.append(&packet, layout, pool);
+            if pending.closed {
+                self.finish(out);
+            }
+            return;
+        }
+        self.finish(out);
+
+        match layout {
+            Some(layout)
+                if layout.is_tcp()
+                    && layout.payload_len() > 0
+                    && packet[layout.l4 + 13]
+                        & (TCP_FIN | TCP_SYN | TCP_RST | TCP_PSH | TCP_URG)
+                        == 0 =>
+            {
+                self.pending = Some(Pending {
+                    next_seq: seq(&packet, &layout).wrapping_add(layout.payload_len() as u32),
+                    mss: layout.payload_len(),
+                    segments: 1,
+                    closed: false,
+                    frame: packet,
+                    layout,
+                });
+            }
+            Some(layout) => out.push_back(single(packet, &layout)),
+            None => out.push_back((VnetHdr::default(), packet)),
+        }
+    }
+
+    /// Pushes the super-packet being built to `out`.
+    pub(crate) fn finish(&mut self, out: &mut VecDeque<(VnetHdr, Packet)>) {
+        let Some(mut pending) = self.pending.take() else {
+            return;
+        };
+        if pending.segments == 1 {
+            out.push_back(single(pending.frame, &pending.layout));
+            return;
+        }
+
+        let layout = &mut pending.layout;
+        layout.set_len(&mut pending.frame);
+        layout.write_partial_checksum(&mut pending.frame);
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if layout.v6 {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            },
+            hdr_len: layout.payload as u16,
+            gso_size: pending.mss as u16,
+            csum_start: layout.l4 as u16,
+            csum_offset: layout.csum_offset() as u16,
+        };
+        out.push_back((hdr, pending.frame));
+    }
+}
+
+impl Pending {
+    fn can_append(&self, packet: &[u8], layout: &Layout) -> bool {
+        let len = layout.payload_len();
+        let flags = packet[layout.l4 + 13];
+        let current_flags = self.frame[self.layout.l4 + 13];
+
+        !self.closed
+            && layout.is_tcp()
+            && layout.v6 == self.layout.v6
+            && layout.l4 == self.layout.l4
+            && layout.payload == self.layout.payload
+            && len > 0
+            && len <= self.mss
+            && self.frame.len() - self.layout.l3 + len <= MAX_GSO_LEN
+            && seq(packet, layout) == self.next_seq
+            && flags & !(TCP_PSH | TCP_FIN) == current_flags
+            && self.layout.headers_match(&self.frame, packet)
+    }
+
+    fn append(&mut self, packet: &[u8], layout: &Layout, pool: &mut PacketPool) {
+        if self.segments == 1 {
+            // Move the first segment to a buffer large enough for the super-packet.
+            let mut frame = pool.spare(self.layout.l3 + MAX_GSO_LEN);
+            frame.extend_from_slice(&self.frame[..self.layout.end]);
+            self.frame = frame;
+        }
+        let len = layout.payload_len();
+        self.frame
+            .extend_from_slice(&packet[layout.payload..layout.end]);
+        self.frame[self.layout.l4 + 13] = packet[layout.l4 + 13];
+        self.segments += 1;
+        self.next_seq = self.next_seq.wrapping_add(len as u32);
+        self.closed = len < self.mss || packet[layout.l4 + 13] & (TCP_PSH | TCP_FIN) != 0;
+    }
+}
+
+/// Leaves the checksum of a single packet to the kernel.
+fn single(mut packet: Packet, layout: &Layout) -> (VnetHdr, Packet) {
+    layout.write_partial_checksum(&mut packet);
+    let hdr = VnetHdr {
+        flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+        gso_type: VIRTIO_NET_HDR_GSO_NONE,
+        hdr_len: layout.payload as u16,
+        gso_size: 0,
+        csum_start: layout.l4 as u16,
+        csum_offset: layout.csum_offset() as u16,
+    };
+    (hdr, packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    fn pool() -> PacketPool {
+        PacketPool::new(1 << 18)
+    }
+
+    /// Makes an IPv4 TCP packet from 10.0.0.1:1000 to 10.0.0.2:2000, with
+    /// valid checksums.
+    fn tcp(seq: u32, flags: u8, payload: &[u8]) -> Packet {
+        let mut packet = Packet::new();
+        packet.extend_from_slice(&[0x45, 0, 0, 0, 0x12, 0x34, 0x40, 0, 64, PROTO_TCP, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&1000u16.to_be_bytes());
+        packet.extend_from_slice(&2000u16.to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&1u32.to_be_bytes());
+        packet.extend_from_slice(&[0x50, flags | 0x10, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        let len = (packet.len() as u16).to_be_bytes();
+        packet[2..4].copy_from_slice(&len);
+        let mut layout = Layout::parse(&packet, 0).unwrap();
+        layout.set_len(&mut packet);
+        fill_checksum(&mut packet, 0);
+        packet
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn checksums_valid(packet: &[u8]) -> bool {
+        let layout = Layout::parse(packet, 0).unwrap();
+        fold(sum(&packet[..layout.l4])) == 0xffff && layout.verify_checksum(packet)
+    }
+
+    /// Completes the partial checksum the way the kernel does.
+    fn complete_checksum(hdr: &VnetHdr, packet: &mut [u8]) {
+        let start = hdr.csum_start as usize;
+        let at = start + hdr.csum_offset as usize;
+        let checksum = !fold(sum(&packet[start..]));
+        packet[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn checksums() {
+        let mut packet = tcp(1, 0, &payload(101));
+        assert!(checksums_valid(&packet));
+        packet[50] ^= 1;
+        assert!(!checksums_valid(&packet));
+
+        let layout = Layout::parse(&packet, 0).unwrap();
+        let (hdr, mut partial) = single(packet, &layout);
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!((hdr.csum_start, hdr.csum_offset), (20, 16));
+        complete_checksum(&hdr, &mut partial);
+        assert!(checksums_valid(&partial));
+    }
+
+    #[test]
+    fn layout_rejects_fragments_and_truncation() {
+        let packet = tcp(1, 0, &payload(10));
+        assert!(Layout::parse(&packet[..packet.len() - 1], 0).is_none());
+        let mut fragment = packet.clone();
+        fragment[6] |= 0x20;
+        assert!(Layout::parse(&fragment, 0).is_none());
+        // Not IPv4 nor IPv6 after an Ethernet header.
+        let mut frame = vec![0; 14];
+        frame.extend_from_slice(&packet);
+        assert!(Layout::parse(&frame, 14).is_none());
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4);
+        assert!(Layout::parse(&frame, 14).is_some());
+    }
+
+    #[test]
+    fn layout_rejects_short_transport_headers() {
+        let mut pool = pool();
+        let mut out = VecDeque::new();
+        let gso = VnetHdr {
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            gso_size: MSS as u16,
+            ..Default::default()
+        };
+
+        // A data offset under 5 words, in a frame ending before the flags.
+        let mut packet = tcp(1, 0, &[]);
+        packet.truncate(33);
+        packet[2..4].copy_from_slice(&33u16.to_be_bytes());
+        packet[32] = 0;
+        assert!(Layout::parse(&packet, 0).is_none());
+        receive(&gso, packet.clone(), 0, &mut pool, &mut out);
+        fill_checksum(&mut packet, 0);
+        assert!(out.is_empty());
+
+        // A TCP header cut before the checksum, with a valid data offset.
+        let mut packet = tcp(1, 0, &[]);
+        packet.truncate(34);
+        packet[2..4].copy_from_slice(&34u16.to_be_bytes());
+        assert!(Layout::parse(&packet, 0).is_none());
+        receive(&gso, packet.clone(), 0, &mut pool, &mut out);
+        fill_checksum(&mut packet, 0);
+        assert!(out.is_empty());
+
+        // A UDP header cut before the checksum.
+        let mut packet = tcp(1, 0, &[]);
+        packet[9] = PROTO_UDP;
+        packet.truncate(27);
+        packet[2..4].copy_from_slice(&27u16.to_be_bytes());
+        assert!(Layout::parse(&packet, 0).is_none());
+        fill_checksum(&mut packet, 0);
+
+        // The coalescer passes them through untouched.
+        let mut coalescer = Coalescer::new(0);
+        let mut frames = VecDeque::new();
+        coalescer.push(packet.clone(), &mut pool, &mut frames);
+        coalescer.finish(&mut frames);
+        assert_eq!(frames[0], (VnetHdr::default(), packet));
+    }
+
+    #[test]
+    fn split_across_mss() {
+        let data = payload(2 * MSS + 500);
+        let frame = tcp(100, TCP_PSH | TCP_FIN, &data);
+        let mut out = VecDeque::new();
+        split(&frame, MSS, 0, &mut pool(), &mut out);
+
+        assert_eq!(out.len(), 3);
+        let mut received = Vec::new();
+        for (i, segment) in out.iter().enumerate() {
+            let layout = Layout::parse(segment, 0).unwrap();
+            // The TCP checksum is left to smoltcp, which doesn't check it.
+            assert_eq!(fold(sum(&segment[..20])), 0xffff);
+            assert_eq!(seq(segment, &layout), 100 + (i * MSS) as u32);
+            assert_eq!(
+                u16::from_be_bytes([segment[4], segment[5]]),
+                0x1234 + i as u16
+            );
+            let last = i == 2;
+            assert_eq!(segment[33] & (TCP_PSH | TCP_FIN) != 0, last);
+            assert_eq!(layout.payload_len(), if last { 500 } else { MSS });
+            received.extend_from_slice(&segment[layout.payload..]);
+        }
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn split_exact_multiple() {
+        let mut out = VecDeque::new();
+        split(&tcp(0, 0, &payload(2 * MSS)), MSS, 0, &mut pool(), &mut out);
+        assert_eq!(out.len(), 2);
+        split(&tcp(0, 0, &payload(10)), 0, 0, &mut pool(), &mut out);
+        assert_eq!(out.len(), 2);
+    }
+
+    #[test]
+    fn coalesce_until_push() {
+        let mut pool = pool();
+        let mut coalescer = Coalescer::new(0);
+        let mut out = VecDeque::new();
+        let data = payload(3 * MSS);
+        for i in 0..3 {
+            let flags = if i == 2 { TCP_PSH } else { 0 };
+            let chunk = &data[i * MSS..(i + 1) * MSS];
+            coalescer.push(tcp((i * MSS) as u32, flags, chunk), &mut pool, &mut out);
+        }
+        // The push closes the super-packet.
+        assert_eq!(out.len(), 1);
+        coalescer.finish(&mut out);
+        assert_eq!(out.len(), 1);
+
+        let (hdr, mut packet) = out.pop_front().unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!((hdr.gso_size, hdr.hdr_len), (MSS as u16, 40));
+        assert_eq!(packet.len(), 40 + 3 * MSS);
+        assert_eq!(packet[33] & TCP_PSH, TCP_PSH);
+        assert_eq!(&packet[40..], &data[..]);
+        complete_checksum(&hdr, &mut packet);
+        assert!(checksums_valid(&packet));
+
+        // The kernel splits it back into the original segments.
+        let mut segments = VecDeque::new();
+        split(&packet, MSS, 0, &mut pool, &mut segments);
+        assert_eq!(segments.len(), 3);
+    }
+
+    #[test]
+    fn coalesce_stops_at_fin_gap_and_short_segment() {
+        let mut pool = pool();
+        let mut coalescer = Coalescer::new(0);
+        let mut out = VecDeque::new();
+        let chunk = payload(MSS);
+
+        coalescer.push(tcp(0, 0, &chunk), &mut pool, &mut out);
+        coalescer.push(tcp(MSS as u32, TCP_FIN, &chunk), &mut pool, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].0.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+
+        // A gap in the sequence numbers.
+        coalescer.push(tcp(0, 0, &chunk), &mut pool, &mut out);
+        coalescer.push(tcp(2 * MSS as u32, 0, &chunk), &mut pool, &mut out);
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[1].0.gso_type, VIRTIO_NET_HDR_GSO_NONE);
+
+        // A shorter segment ends the super-packet, a longer one can't join it.
+        coalescer.push(tcp(3 * MSS as u32, 0, &chunk[..10]), &mut pool, &mut out);
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[2].0.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        coalescer.push(tcp(0, 0, &chunk[..10]), &mut pool, &mut out);
+        coalescer.push(tcp(10, 0, &chunk), &mut pool, &mut out);
+        coalescer.finish(&mut out);
+        assert_eq!(out.len(), 5);
+        assert!(
+            out.range(3..)
+                .all(|(hdr, _)| hdr.gso_type == VIRTIO_NET_HDR_GSO_NONE)
+        );
+
+        // Packets that aren't TCP data go through with a partial checksum.
+        coalescer.push(tcp(0, TCP_SYN, &[]), &mut pool, &mut out);
+        assert_eq!(out.len(), 6);
+        let (hdr, mut packet) = out.pop_back().unwrap();
+        complete_checksum(&hdr, &mut packet);
+        assert!(checksums_valid(&packet));
+    }
+
+    #[test]
+    fn receive_verifies_unchecked_packets() {
+        let mut pool = pool();
+        let mut out = VecDeque::new();
+        let mut bad = tcp(0, 0, &payload(10));
+        bad[45] ^= 1;
+
+        receive(&VnetHdr::default(), bad.clone(), 0, &mut pool, &mut out);
+        assert!(out.is_empty());
+        let valid = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_DATA_VALID,
+            ..Default::default()
+        };
+        receive(&valid, bad, 0, &mut pool, &mut out);
+        assert_eq!(out.len(), 1);
+    }
+}
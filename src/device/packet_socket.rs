@@ -0,0 +1,580 @@
+use futures::{Sink, Stream, ready};
+use smoltcp::{
+    phy::{DeviceCapabilities, Medium},
+    wire::EthernetAddress,
+};
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::device::{AsyncDevice, DEFAULT_MAX_BURST_SIZE, Packet, PacketPool, offload};
+
+/// Default size of a block of the rings of a `PacketSocket`.
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 18;
+
+/// Default number of blocks of the rings of a `PacketSocket`.
+pub const DEFAULT_BLOCK_COUNT: usize = 8;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const RX_FRAME_SIZE: usize = 2048;
+
+/// Offset of the frame in a slot of the TX ring.
+const TX_DATA_OFFSET: usize = libc::TPACKET3_HDRLEN - mem::size_of::<libc::sockaddr_ll>();
+
+/// A memory-mapped packet ring, shared with the kernel.
+struct Ring {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is owned by the `Ring`, and only accessed through `&mut PacketSocket`.
+unsafe impl Send for Ring {}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a mapping created by `mmap`.
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+impl Ring {
+    /// Returns the status word at `offset`, which is shared with the kernel.
+    fn status(&self, offset: usize) -> &AtomicU32 {
+        // SAFETY: `offset` is an aligned offset within the mapping.
+        unsafe { &*self.ptr.add(offset).cast::<AtomicU32>() }
+    }
+}
+
+/// A device that sends and receives Ethernet frames on a Linux interface
+/// through an `AF_PACKET` socket, without libpcap.
+///
+/// Frames are exchanged through memory-mapped `TPACKET_V3` rings. Received
+/// frames are delivered when a block of the ring is full, or after the retire
+/// timeout. GRO should be disabled on the interface, as frames larger than the
+/// blocks of the ring are truncated and dropped.
+pub struct PacketSocket {
+    fd: AsyncFd<OwnedFd>,
+    ring: Ring,
+    block_size: usize,
+    block_count: usize,
+    rx_block: usize,
+    tx_frame_size: usize,
+    tx_frames_per_block: usize,
+    tx_frame: usize,
+    tx_pending: bool,
+    pool: PacketPool,
+    recv_queue: VecDeque<Packet>,
+    dropped: Arc<AtomicU64>,
+    caps: DeviceCapabilities,
+}
+
+/// A builder for `PacketSocket`.
+pub struct PacketSocketBuilder {
+    interface: String,
+    block_size: usize,
+    block_count: usize,
+    retire_timeout: Duration,
+    promiscuous: bool,
+    filter_mac: Option<EthernetAddress>,
+    caps: DeviceCapabilities,
+}
+
+impl PacketSocketBuilder {
+    /// Sets the size of a block of the rings, which must be a multiple of the
+    /// page size. Default to `DEFAULT_BLOCK_SIZE`.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the number of blocks of each ring. Default to `DEFAULT_BLOCK_COUNT`.
+    pub fn block_count(mut self, block_count: usize) -> Self {
+        self.block_count = block_count;
+        self
+    }
+
+    /// Sets how long the kernel waits before handing a block that isn't full
+    /// over. Default to 1ms.
+    pub fn retire_timeout(mut self, retire_timeout: Duration) -> Self {
+        self.retire_timeout = retire_timeout;
+        self
+    }
+
+    /// Sets whether the interface is put in promiscuous mode, which is needed
+    /// to receive frames for a MAC address other than the interface's.
+    /// Default to `true`.
+    pub fn promiscuous(mut self, promiscuous: bool) -> Self {
+        self.promiscuous = promiscuous;
+        self
+    }
+
+    /// Attaches a BPF filter so that only frames sent to `mac`, broadcast or
+    /// multicast are received. Default to receiving every frame.
+    pub fn filter_mac(mut self, mac: EthernetAddress) -> Self {
+        self.filter_mac = Some(mac);
+        self
+    }
+
+    /// Opens the socket and binds it to the interface.
+    pub fn open(self) -> io::Result<PacketSocket> {
+        if self.caps.medium != Medium::Ethernet {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PacketSocket only supports Medium::Ethernet",
+            ));
+        }
+        let interface = CString::new(self.interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        // SAFETY: `interface` is a valid C string.
+        let ifindex = unsafe { libc::if_nametoindex(interface.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The protocol is set when binding, so nothing is received before the rings are set up.
+        // SAFETY: no pointer is involved.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and isn't owned by anything else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw = fd.as_raw_fd();
+
+        if let Some(mac) = self.filter_mac {
+            let mut filter = mac_filter(mac);
+            let prog = libc::sock_fprog {
+                len: filter.len() as u16,
+                filter: filter.as_mut_ptr(),
+            };
+            setsockopt(raw, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &prog)?;
+        }
+        setsockopt(
+            raw,
+            libc::SOL_PACKET,
+            libc::PACKET_VERSION,
+            &(libc::tpacket_versions::TPACKET_V3 as libc::c_int),
+        )?;
+        // Skip malformed frames instead of stopping the transmission.
+        setsockopt(raw, libc::SOL_PACKET, libc::PACKET_LOSS, &1 as &libc::c_int)?;
+        // Not supported before Linux 4.20, in which case the host's frames are filtered by MAC only.
+        let _ = setsockopt(
+            raw,
+            libc::SOL_PACKET,
+            libc::PACKET_IGNORE_OUTGOING,
+            &1 as &libc::c_int,
+        );
+
+        let mtu = self.caps.max_transmission_unit;
+        let tx_frame_size = (TX_DATA_OFFSET + mtu).next_multiple_of(libc::TPACKET_ALIGNMENT);
+        if self.block_size < tx_frame_size.max(RX_FRAME_SIZE) || self.block_count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocks are too small",
+            ));
+        }
+        let tx_frames_per_block = self.block_size / tx_frame_size;
+
+        let rx_req = libc::tpacket_req3 {
+            tp_block_size: self.block_size as u32,
+            tp_block_nr: self.block_count as u32,
+            tp_frame_size: RX_FRAME_SIZE as u32,
+            tp_frame_nr: (self.block_size / RX_FRAME_SIZE * self.block_count) as u32,
+            tp_retire_blk_tov: self.retire_timeout.as_millis().max(1) as u32,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(raw, libc::SOL_PACKET, libc::PACKET_RX_RING, &rx_req)?;
+        let tx_req = libc::tpacket_req3 {
+            tp_frame_size: tx_frame_size as u32,
+            tp_frame_nr: (tx_frames_per_block * self.block_count) as u32,
+            tp_retire_blk_tov: 0,
+            ..rx_req
+        };
+        setsockopt(raw, libc::SOL_PACKET, libc::PACKET_TX_RING, &tx_req)?;
+
+        // The RX ring is followed by the TX ring.
+        let len = 2 * self.block_size * self.block_count;
+        // SAFETY: no memory is referenced, the mapping is checked below.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                raw,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let ring = Ring {
+            ptr: ptr.cast(),
+            len,
+        };
+
+        // SAFETY: `sockaddr_ll` is a plain C struct, for which zero is a valid value.
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as i32;
+        // SAFETY: `addr` is a valid `sockaddr_ll` of the given length.
+        if unsafe {
+            libc::bind(
+                raw,
+                (&addr as *const libc::sockaddr_ll).cast(),
+                mem::size_of::<libc::sockaddr_ll>() as u32,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        if self.promiscuous {
+            let mreq = libc::packet_mreq {
+                mr_ifindex: ifindex as i32,
+                mr_type: libc::PACKET_MR_PROMISC as u16,
+                mr_alen: 0,
+                mr_address: [0; 8],
+            };
+            setsockopt(raw, libc::SOL_PACKET, libc::PACKET_ADD_MEMBERSHIP, &mreq)?;
+        }
+
+        // SAFETY: `fd` is owned by the `AsyncFd`.
+        let fd = unsafe { AsyncFd::register(fd)? };
+        let max_burst_size = self.caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        Ok(PacketSocket {
+            fd,
+            ring,
+            block_size: self.block_size,
+            block_count: self.block_count,
+            rx_block: 0,
+            tx_frame_size,
+            tx_frames_per_block,
+            tx_frame: 0,
+            tx_pending: false,
+            pool: PacketPool::new(mtu * max_burst_size),
+            recv_queue: VecDeque::new(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            caps: self.caps,
+        })
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: `value` is valid for reads of `size_of::<T>()` bytes.
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (value as *const T).cast(),
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns a classic BPF program accepting frames sent to `mac`, broadcast or multicast.
+fn mac_filter(mac: EthernetAddress) -> [libc::sock_filter; 8] {
+    let op = |code: u32, jt: u8, jf: u8, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let [a, b, c, d, e, f] = mac.0;
+    [
+        // The group bit of the destination.
+        op(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 0, 0, 0),
+        op(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, 4, 0, 1),
+        op(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0, 0, 2),
+        op(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            0,
+            3,
+            u32::from_be_bytes([c, d, e, f]),
+        ),
+        op(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 0, 0, 0),
+        op(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            0,
+            1,
+            u16::from_be_bytes([a, b]) as u32,
+        ),
+        op(libc::BPF_RET | libc::BPF_K, 0, 0, u32::MAX),
+        op(libc::BPF_RET | libc::BPF_K, 0, 0, 0),
+    ]
+}
+
+impl PacketSocket {
+    /// Opens a packet socket on `interface`, in promiscuous mode.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn open(interface: &str, caps: DeviceCapabilities) -> io::Result<PacketSocket> {
+        Self::builder(interface, caps).open()
+    }
+
+    /// Returns a builder to configure a packet socket on `interface`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn builder(interface: &str, caps: DeviceCapabilities) -> PacketSocketBuilder {
+        PacketSocketBuilder {
+            interface: interface.to_string(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_count: DEFAULT_BLOCK_COUNT,
+            retire_timeout: Duration::from_millis(1),
+            promiscuous: true,
+            filter_mac: None,
+            caps,
+        }
+    }
+
+    /// Returns the number of packets dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped packets, which can be read after the
+    /// device is moved into a `Net`.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    fn rx_block_offset(&self) -> usize {
+        self.rx_block * self.block_size
+    }
+
+    fn rx_block_ready(&self) -> bool {
+        let status = self
+            .ring
+            .status(self.rx_block_offset() + block_status_offset());
+        status.load(Ordering::Acquire) & libc::TP_STATUS_USER != 0
+    }
+
+    /// Copies the frames of the current RX block to `recv_queue`, and hands
+    /// the block back to the kernel.
+    fn read_block(&mut self) {
+        let block = self.rx_block_offset();
+        // SAFETY: the block belongs to user space until its status is reset.
+        let desc = unsafe { &*self.ring.ptr.add(block).cast::<libc::tpacket_block_desc>() };
+        // SAFETY: `bh1` is the header of `TPACKET_V3` blocks.
+        let bh1 = unsafe { &desc.hdr.bh1 };
+
+        let mut offset = block + bh1.offset_to_first_pkt as usize;
+        for _ in 0..bh1.num_pkts {
+            // SAFETY: the kernel lays the packets out within the block.
+            let hdr = unsafe { &*self.ring.ptr.add(offset).cast::<libc::tpacket3_hdr>() };
+            // SAFETY: the frame is within the block.
+            let frame = unsafe {
+                std::slice::from_raw_parts(
+                    self.ring.ptr.add(offset + hdr.tp_mac as usize),
+                    hdr.tp_snaplen as usize,
+                )
+            };
+            if hdr.tp_snaplen < hdr.tp_len || frame.len() < ETHERNET_HEADER_LEN {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.recv_queue
+                    .push_back(copy_frame(&mut self.pool, hdr, frame));
+            }
+            offset += hdr.tp_next_offset as usize;
+        }
+
+        self.ring
+            .status(block + block_status_offset())
+            .store(libc::TP_STATUS_KERNEL, Ordering::Release);
+        self.rx_block = (self.rx_block + 1) % self.block_count;
+    }
+
+    /// Returns the offset of the current TX slot.
+    fn tx_frame_offset(&self) -> usize {
+        let block = self.tx_frame / self.tx_frames_per_block;
+        let index = self.tx_frame % self.tx_frames_per_block;
+        (self.block_count + block) * self.block_size + index * self.tx_frame_size
+    }
+
+    /// Returns whether the current TX slot can be filled, reclaiming it if the
+    /// kernel rejected its frame.
+    fn tx_frame_available(&self) -> bool {
+        let status = self
+            .ring
+            .status(self.tx_frame_offset() + tx_status_offset());
+        match status.load(Ordering::Acquire) {
+            libc::TP_STATUS_AVAILABLE => true,
+            libc::TP_STATUS_WRONG_FORMAT => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                status.store(libc::TP_STATUS_AVAILABLE, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Asks the kernel to send the frames of the TX ring.
+    fn kick(&mut self) {
+        // SAFETY: no buffer is passed.
+        let r = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                ptr::null(),
+                0,
+            )
+        };
+        // Frames that can't be sent now, e.g. while the interface is down,
+        // stay in the ring until the next kick.
+        if r >= 0 {
+            self.tx_pending = false;
+        }
+    }
+}
+
+fn block_status_offset() -> usize {
+    mem::offset_of!(libc::tpacket_block_desc, hdr)
+        + mem::offset_of!(libc::tpacket_hdr_v1, block_status)
+}
+
+fn tx_status_offset() -> usize {
+    mem::offset_of!(libc::tpacket3_hdr, tp_status)
+}
+
+/// Copies a received frame, restoring the VLAN tag stripped by the kernel and
+/// computing the checksum left to the device.
+fn copy_frame(pool: &mut PacketPool, hdr: &libc::tpacket3_hdr, frame: &[u8]) -> Packet {
+    if hdr.tp_status & libc::TP_STATUS_VLAN_VALID == 0 {
+        let mut packet = pool.concat(&[frame]);
+        if hdr.tp_status & libc::TP_STATUS_CSUMNOTREADY != 0 {
+            offload::fill_checksum(&mut packet, ETHERNET_HEADER_LEN);
+        }
+        return packet;
+    }
+
+    let tpid = if hdr.tp_status & libc::TP_STATUS_VLAN_TPID_VALID != 0 {
+        hdr.hv1.tp_vlan_tpid
+    } else {
+        libc::ETH_P_8021Q as u16
+    };
+    let mut packet = pool.concat(&[
+        &frame[..12],
+        &tpid.to_be_bytes(),
+        &(hdr.hv1.tp_vlan_tci as u16).to_be_bytes(),
+        &frame[12..],
+    ]);
+    if hdr.tp_status & libc::TP_STATUS_CSUMNOTREADY != 0 {
+        offload::fill_checksum(&mut packet, ETHERNET_HEADER_LEN + 4);
+    }
+    packet
+}
+
+impl Stream for PacketSocket {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(packet) = self.recv_queue.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            if self.rx_block_ready() {
+                self.read_block();
+                continue;
+            }
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            // The block is checked again before waiting, in case it was
+            // handed over after the check above.
+            if !self.rx_block_ready() {
+                guard.clear_ready();
+            }
+        }
+    }
+}
+
+impl Sink<Packet> for PacketSocket {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            if self.tx_frame_available() {
+                return Poll::Ready(Ok(()));
+            }
+            self.kick();
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            if !self.tx_frame_available() {
+                guard.clear_ready();
+            }
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        if !self.tx_frame_available() {
+            return Err(io::Error::other(
+                "PacketSocket::start_send called before poll_ready",
+            ));
+        }
+        let len = item.len().min(self.tx_frame_size - TX_DATA_OFFSET);
+        let offset = self.tx_frame_offset();
+        // SAFETY: the slot is available, so it belongs to user space.
+        unsafe {
+            let hdr = &mut *self.ring.ptr.add(offset).cast::<libc::tpacket3_hdr>();
+            hdr.tp_len = len as u32;
+            hdr.tp_snaplen = len as u32;
+            hdr.tp_next_offset = 0;
+            ptr::copy_nonoverlapping(
+                item.as_ptr(),
+                self.ring.ptr.add(offset + TX_DATA_OFFSET),
+                len,
+            );
+        }
+        self.ring
+            .status(offset + tx_status_offset())
+            .store(libc::TP_STATUS_SEND_REQUEST, Ordering::Release);
+
+        self.tx_frame = (self.tx_frame + 1) % (self.tx_frames_per_block * self.block_count);
+        self.tx_pending = true;
+        Ok(())
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        if self.tx_pending {
+            self.kick();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncDevice for PacketSocket {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+}
@@ -0,0 +1,319 @@
+use futures::{Sink, Stream, future::BoxFuture};
+use parking_lot::Mutex;
+use smoltcp::{
+    phy::{DeviceCapabilities, Medium},
+    time::Instant,
+};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+use crate::{
+    Clock, TokioClock,
+    device::{AsyncDevice, Packet, PacketPool},
+};
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+/// The largest packet read from a file, the snaplen of `tcpdump` and
+/// Wireshark.
+const MAX_SNAPLEN: usize = 262144;
+
+/// A device that replays packets from a pcap file and records the packets
+/// transmitted by the stack.
+///
+/// Once every packet is replayed the device stays pending, so the `Net` keeps
+/// running and its responses can be inspected through `ReplayHandle`.
+pub struct PcapReplay {
+    packets: VecDeque<(Duration, Packet)>,
+    original_timing: bool,
+    start: Option<Instant>,
+    sleep: Option<(Instant, BoxFuture<'static, ()>)>,
+    clock: Arc<dyn Clock>,
+    handle: ReplayHandle,
+    caps: DeviceCapabilities,
+}
+
+impl PcapReplay {
+    /// Make a new `PcapReplay` from the pcap file at `path`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::medium` must match the link type of the file.
+    pub fn open<P: AsRef<Path>>(path: P, caps: DeviceCapabilities) -> io::Result<Self> {
+        Self::new(File::open(path)?, caps)
+    }
+
+    /// Make a new `PcapReplay` from a reader of pcap formatted data.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::medium` must match the link type of the file.
+    pub fn new<R: Read>(reader: R, caps: DeviceCapabilities) -> io::Result<Self> {
+        let (link_type, packets) = read_pcap(BufReader::new(reader))?;
+        let medium_matches = match caps.medium {
+            Medium::Ethernet => link_type == LINKTYPE_ETHERNET,
+            Medium::Ip => matches!(link_type, LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        };
+        if !medium_matches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "pcap link type {} does not match medium {:?}",
+                    link_type, caps.medium
+                ),
+            ));
+        }
+
+        Ok(PcapReplay {
+            packets,
+            original_timing: false,
+            start: None,
+            sleep: None,
+            clock: Arc::new(TokioClock::new()),
+            handle: ReplayHandle::default(),
+            caps,
+        })
+    }
+
+    /// Replay packets with the inter-packet timing recorded in the file
+    /// instead of as fast as possible.
+    pub fn set_original_timing(&mut self, original_timing: bool) {
+        self.original_timing = original_timing;
+    }
+
+    /// Sets the clock the original timing follows, which should be the one of
+    /// the `Net`. Default to a `TokioClock`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Returns a handle to observe the replay after the device is moved into a `Net`.
+    pub fn handle(&self) -> ReplayHandle {
+        self.handle.clone()
+    }
+}
+
+impl Stream for PcapReplay {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        let offset = match this.packets.front() {
+            Some((offset, _)) => *offset,
+            None => {
+                this.handle.finish();
+                return Poll::Pending;
+            }
+        };
+
+        if this.original_timing {
+            let clock = &this.clock;
+            let start = *this.start.get_or_insert_with(|| clock.now());
+            let deadline = start + offset.into();
+            if deadline > clock.now() {
+                if this.sleep.as_ref().is_none_or(|(at, _)| *at != deadline) {
+                    this.sleep = Some((deadline, clock.sleep_until(deadline)));
+                }
+                let (_, sleep) = this.sleep.as_mut().unwrap();
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        let (_, packet) = this.packets.pop_front().unwrap();
+        this.handle.inner.replayed.lock().push(packet.clone());
+        Poll::Ready(Some(Ok(packet)))
+    }
+}
+
+impl Sink<Packet> for PcapReplay {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        self.handle.inner.transmitted.lock().push(item);
+        self.handle.inner.notify.notify_waiters();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncDevice for PcapReplay {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+}
+
+#[derive(Default)]
+struct ReplayState {
+    replayed: Mutex<Vec<Packet>>,
+    transmitted: Mutex<Vec<Packet>>,
+    finished: AtomicBool,
+    notify: Notify,
+}
+
+/// A handle to the packets replayed and transmitted by a `PcapReplay`.
+#[derive(Clone, Default)]
+pub struct ReplayHandle {
+    inner: Arc<ReplayState>,
+}
+
+impl ReplayHandle {
+    /// Returns the packets replayed into the stack so far.
+    pub fn replayed(&self) -> Vec<Packet> {
+        self.inner.replayed.lock().clone()
+    }
+
+    /// Returns the packets transmitted by the stack so far.
+    pub fn transmitted(&self) -> Vec<Packet> {
+        self.inner.transmitted.lock().clone()
+    }
+
+    /// Takes the packets transmitted by the stack so far.
+    pub fn take_transmitted(&self) -> Vec<Packet> {
+        std::mem::take(&mut *self.inner.transmitted.lock())
+    }
+
+    /// Returns whether every packet of the file has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.inner.finished.load(Ordering::SeqCst)
+    }
+
+    /// Waits until every packet of the file has been replayed.
+    pub async fn finished(&self) {
+        self.wait_until(|| self.is_finished()).await
+    }
+
+    /// Waits until the stack has transmitted at least `count` packets.
+    pub async fn wait_transmitted(&self, count: usize) {
+        self.wait_until(|| self.inner.transmitted.lock().len() >= count)
+            .await
+    }
+
+    async fn wait_until(&self, cond: impl Fn() -> bool) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if cond() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn finish(&self) {
+        if !self.inner.finished.swap(true, Ordering::SeqCst) {
+            self.inner.notify.notify_waiters();
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads a classic pcap file, returning its link type and the packets with
+/// their offset from the first packet.
+fn read_pcap<R: BufRead>(mut reader: R) -> io::Result<(u32, VecDeque<(Duration, Packet)>)> {
+    let mut header = [0u8; 24];
+    reader.read_exact(&mut header)?;
+
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let (big_endian, nanos) = match magic {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        0x4d3cb2a1 => (true, true),
+        0x0a0d0d0a => return Err(invalid_data("pcapng files are not supported")),
+        _ => return Err(invalid_data("not a pcap file")),
+    };
+    let read_u32 = |b: &[u8]| {
+        let b = b.try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    // The upper bits may carry the FCS length, only the lower 16 bits are the link type.
+    let link_type = read_u32(&header[20..24]) & 0xffff;
+    let snaplen = match read_u32(&header[16..20]) as usize {
+        0 => MAX_SNAPLEN,
+        snaplen => snaplen.min(MAX_SNAPLEN),
+    };
+
+    let mut pool = PacketPool::new(65536);
+    let mut packets = VecDeque::new();
+    let mut first = None;
+    let mut record = [0u8; 16];
+    while read_record_header(&mut reader, &mut record)? {
+        let secs = read_u32(&record[0..4]) as u64;
+        let frac = read_u32(&record[4..8]);
+        let incl_len = read_u32(&record[8..12]) as usize;
+        if incl_len > snaplen {
+            return Err(invalid_data("packet longer than the snaplen"));
+        }
+
+        let timestamp = if nanos {
+            Duration::new(secs, frac)
+        } else {
+            Duration::new(secs, 0) + Duration::from_micros(frac as u64)
+        };
+        let first = *first.get_or_insert(timestamp);
+
+        // Copied straight from the reader's buffer, so the packet isn't
+        // zero-filled first.
+        let buf = pool.buffer(incl_len);
+        while buf.len() < incl_len {
+            let available = reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let len = available.len().min(incl_len - buf.len());
+            buf.extend_from_slice(&available[..len]);
+            reader.consume(len);
+        }
+        packets.push_back((timestamp.saturating_sub(first), buf.split_to(incl_len)));
+    }
+
+    Ok((link_type, packets))
+}
+
+/// Reads the header of the next record, returning `false` at the end of the
+/// file. A header cut short is an error, the file is truncated.
+fn read_record_header<R: Read>(reader: &mut R, record: &mut [u8; 16]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < record.len() {
+        match reader.read(&mut record[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(invalid_data("truncated pcap record header")),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
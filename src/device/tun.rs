@@ -0,0 +1,359 @@
+use bytes::BytesMut;
+use futures::{Sink, Stream, ready};
+use smoltcp::phy::{Checksum, DeviceCapabilities, Medium};
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::device::{
+    AsyncDevice, DEFAULT_MAX_BURST_SIZE, Packet, PacketPool,
+    offload::{self, Coalescer, MAX_GSO_LEN, VNET_HDR_LEN, VnetHdr},
+};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// A device backed by a Linux TUN or TAP interface.
+///
+/// A TUN interface is opened when `DeviceCapabilities::medium` is `Medium::Ip`,
+/// and a TAP interface when it is `Medium::Ethernet`. The interface must be
+/// configured and brought up by other means, e.g. with `ip link`.
+///
+/// With offloads enabled, packets are exchanged with a virtio-net header:
+/// TCP super-packets from the kernel are split into segments, consecutive
+/// segments sent by smoltcp are coalesced, and the TCP and UDP checksums are
+/// left to the kernel.
+///
+/// Packets the kernel refuses, e.g. while the interface is down, are dropped.
+pub struct Tun {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+    offload: bool,
+    l3: usize,
+    pool: PacketPool,
+    recv_queue: VecDeque<Packet>,
+    send_queue: VecDeque<(VnetHdr, Packet)>,
+    /// Coalesces the packets sent until the next flush.
+    coalescer: Coalescer,
+    dropped: Arc<AtomicU64>,
+    caps: DeviceCapabilities,
+}
+
+/// A builder for `Tun`.
+pub struct TunBuilder {
+    name: String,
+    offload: bool,
+    caps: DeviceCapabilities,
+}
+
+impl TunBuilder {
+    /// Sets the name of the interface. The kernel picks one if it is empty,
+    /// which is the default.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Negotiates virtio-net headers to offload checksums and TCP segmentation.
+    /// Default to `false`.
+    ///
+    /// When enabled, `DeviceCapabilities::checksum` is set so that smoltcp
+    /// neither computes nor verifies TCP and UDP checksums.
+    pub fn offload(mut self, offload: bool) -> Self {
+        self.offload = offload;
+        self
+    }
+
+    /// Opens the interface, creating it if it doesn't exist.
+    pub fn open(self) -> io::Result<Tun> {
+        let TunBuilder {
+            name,
+            offload,
+            mut caps,
+        } = self;
+
+        let (kind, l3) = match caps.medium {
+            Medium::Ip => (libc::IFF_TUN, 0),
+            Medium::Ethernet => (libc::IFF_TAP, ETHERNET_HEADER_LEN),
+            #[allow(unreachable_patterns)]
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unsupported medium",
+                ));
+            }
+        };
+        let mut flags = kind | libc::IFF_NO_PI;
+        if offload {
+            flags |= libc::IFF_VNET_HDR;
+        }
+
+        let (fd, name) = open_tun(&name, flags)?;
+        if offload {
+            let features = libc::TUN_F_CSUM | libc::TUN_F_TSO4 | libc::TUN_F_TSO6;
+            // SAFETY: `TUNSETOFFLOAD` takes its argument by value.
+            if unsafe {
+                libc::ioctl(
+                    fd.as_raw_fd(),
+                    libc::TUNSETOFFLOAD,
+                    features as libc::c_ulong,
+                )
+            } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            caps.checksum.tcp = Checksum::None;
+            caps.checksum.udp = Checksum::None;
+        }
+
+        let max_burst_size = caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        let mut pool_size = caps.max_transmission_unit * max_burst_size;
+        if offload {
+            pool_size = pool_size.max(2 * (VNET_HDR_LEN + l3 + MAX_GSO_LEN));
+        }
+        // SAFETY: `fd` is owned by the `AsyncFd`.
+        let fd = unsafe { AsyncFd::register(fd)? };
+        Ok(Tun {
+            fd,
+            name,
+            offload,
+            l3,
+            pool: PacketPool::new(pool_size),
+            recv_queue: VecDeque::new(),
+            send_queue: VecDeque::new(),
+            coalescer: Coalescer::new(l3),
+            dropped: Arc::new(AtomicU64::new(0)),
+            caps,
+        })
+    }
+}
+
+fn open_tun(name: &str, flags: libc::c_int) -> io::Result<(OwnedFd, String)> {
+    // SAFETY: the path is a valid C string.
+    let fd = unsafe {
+        libc::open(
+            c"/dev/net/tun".as_ptr(),
+            libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and isn't owned by anything else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: `ifreq` is a plain C struct, for which zero is a valid value.
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    if name.len() >= req.ifr_name.len() || name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid interface name",
+        ));
+    }
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    req.ifr_ifru.ifru_flags = flags as libc::c_short;
+    // SAFETY: `req` is a valid `ifreq`, which `TUNSETIFF` fills with the name.
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the kernel returns a nul terminated name.
+    let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) };
+    Ok((fd, name.to_string_lossy().into_owned()))
+}
+
+impl Tun {
+    /// Opens the interface `name` without offloads.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn open(name: &str, caps: DeviceCapabilities) -> io::Result<Tun> {
+        Self::builder(caps).name(name).open()
+    }
+
+    /// Returns a builder to configure the interface.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn builder(caps: DeviceCapabilities) -> TunBuilder {
+        TunBuilder {
+            name: String::new(),
+            offload: false,
+            caps,
+        }
+    }
+
+    /// Returns the name of the interface.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether offloads are enabled.
+    pub fn offload(&self) -> bool {
+        self.offload
+    }
+
+    /// Returns the number of packets dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped packets, which can be read after the
+    /// device is moved into a `Net`.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    /// Reads a frame from the interface into `recv_queue`.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let len = if self.offload {
+            VNET_HDR_LEN + self.l3 + MAX_GSO_LEN
+        } else {
+            self.caps.max_transmission_unit
+        };
+
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let buf = self.pool.buffer(len);
+            match guard.try_io(|fd| read(fd.as_raw_fd(), buf, len)) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            }
+            let mut frame = buf.split();
+
+            if !self.offload {
+                self.recv_queue.push_back(frame);
+            } else if frame.len() >= VNET_HDR_LEN {
+                let hdr = VnetHdr::decode(&frame);
+                let frame = frame.split_off(VNET_HDR_LEN);
+                offload::receive(&hdr, frame, self.l3, &mut self.pool, &mut self.recv_queue);
+            }
+            return Poll::Ready(Ok(()));
+        }
+    }
+
+    /// Writes the frames in `send_queue`.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some((hdr, packet)) = self.send_queue.front() {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| write(fd.as_raw_fd(), self.offload, hdr, packet)) {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(_would_block) => continue,
+            }
+            self.send_queue.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads a frame of at most `len` bytes into the spare capacity of `buf`.
+fn read(fd: RawFd, buf: &mut BytesMut, len: usize) -> io::Result<()> {
+    let spare = &mut buf.spare_capacity_mut()[..len];
+    // SAFETY: `spare` is valid for writes of `len` bytes.
+    let n = unsafe { libc::read(fd, spare.as_mut_ptr().cast(), len) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the first `n` bytes of the spare capacity were initialized by `read`.
+    unsafe { buf.set_len(buf.len() + n as usize) };
+    Ok(())
+}
+
+/// Writes a frame, preceded by `hdr` if `offload` is set.
+fn write(fd: RawFd, offload: bool, hdr: &VnetHdr, packet: &[u8]) -> io::Result<()> {
+    let hdr = hdr.encode();
+    let iov = [
+        libc::iovec {
+            iov_base: hdr.as_ptr() as *mut _,
+            iov_len: hdr.len(),
+        },
+        libc::iovec {
+            iov_base: packet.as_ptr() as *mut _,
+            iov_len: packet.len(),
+        },
+    ];
+    let iov = if offload { &iov[..] } else { &iov[1..] };
+    // SAFETY: the buffers are valid for reads and are only read by `writev`.
+    let n = unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as libc::c_int) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Stream for Tun {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(packet) = self.recv_queue.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            ready!(self.poll_read(cx))?;
+        }
+    }
+}
+
+impl Sink<Packet> for Tun {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The super-packet being built is only finished by a flush.
+        self.get_mut().poll_write(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        let this = &mut *self;
+        if this.offload {
+            this.coalescer
+                .push(item, &mut this.pool, &mut this.send_queue);
+        } else {
+            this.send_queue.push_back((VnetHdr::default(), item));
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = &mut *self;
+        this.coalescer.finish(&mut this.send_queue);
+        this.poll_write(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncDevice for Tun {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+
+    fn poll_send_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        packets: &mut VecDeque<Packet>,
+    ) -> Poll<io::Result<()>> {
+        if self.offload {
+            for packet in packets.drain(..) {
+                self.coalescer
+                    .push(packet, &mut self.pool, &mut self.send_queue);
+            }
+        } else {
+            let packets = packets.drain(..).map(|p| (VnetHdr::default(), p));
+            self.send_queue.extend(packets);
+        }
+        Pin::new(self).poll_flush(cx)
+    }
+}
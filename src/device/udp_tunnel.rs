@@ -0,0 +1,163 @@
+use bytes::BufMut;
+use futures::{Sink, Stream, ready};
+use smoltcp::phy::DeviceCapabilities;
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+use crate::device::{AsyncDevice, DEFAULT_MAX_BURST_SIZE, Packet, PacketPool};
+
+/// A device that tunnels packets over a host UDP socket to a peer.
+///
+/// Each `Packet` is carried in a single datagram, framed according to
+/// `DeviceCapabilities::medium`. Datagrams from any address other than the peer
+/// are ignored. Datagrams longer than `DeviceCapabilities::max_transmission_unit`
+/// are dropped, as well as packets the socket fails to send, e.g. while the
+/// peer is unreachable.
+pub struct UdpTunnel {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    pool: PacketPool,
+    pending: Option<Packet>,
+    dropped: Arc<AtomicU64>,
+    caps: DeviceCapabilities,
+}
+
+impl UdpTunnel {
+    /// Make a new `UdpTunnel` sending to and receiving from `peer` over `socket`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn new(socket: UdpSocket, peer: SocketAddr, caps: DeviceCapabilities) -> Self {
+        let max_burst_size = caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        UdpTunnel {
+            socket,
+            peer,
+            pool: PacketPool::new(caps.max_transmission_unit * max_burst_size),
+            pending: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+            caps,
+        }
+    }
+
+    /// Bind a UDP socket to `local` and make a new `UdpTunnel` to `peer`.
+    pub async fn bind<A: ToSocketAddrs>(
+        local: A,
+        peer: SocketAddr,
+        caps: DeviceCapabilities,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(local).await?;
+        Ok(Self::new(socket, peer, caps))
+    }
+
+    /// Returns the local address of the underlying socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns the number of packets dropped so far, because they were too
+    /// long or failed to be sent.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped packets, which can be read after the
+    /// device is moved into a `Net`.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(packet) = &self.pending {
+            if ready!(self.socket.poll_send_to(cx, packet, self.peer)).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            self.pending = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for UdpTunnel {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            ready!(this.socket.poll_recv_ready(cx))?;
+
+            // One more byte than the MTU tells a datagram that was too long.
+            let mtu = this.caps.max_transmission_unit;
+            let buf = this.pool.buffer(mtu + 1);
+            match this.socket.try_recv_buf_from(&mut buf.limit(mtu + 1)) {
+                Ok((len, from)) if len > mtu => {
+                    if from == this.peer {
+                        this.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Ok((_, from)) => {
+                    let packet = buf.split();
+                    if from == this.peer {
+                        return Poll::Ready(Some(Ok(packet)));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+impl Sink<Packet> for UdpTunnel {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        if self.pending.is_some() {
+            return Err(io::Error::other(
+                "UdpTunnel::start_send called before poll_ready",
+            ));
+        }
+
+        match self.socket.try_send_to(&item, self.peer) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.pending = Some(item),
+            // The error may only last as long as the route to the peer, so
+            // the packet is lost instead of the whole `Net`.
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+}
+
+impl AsyncDevice for UdpTunnel {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+}
@@ -1,157 +1,488 @@
-use futures::{ready, Sink, Stream};
-use pin_project_lite::pin_project;
-use smoltcp::phy::DeviceCapabilities;
-use std::{
-    io,
-    os::unix::io::{AsRawFd, RawFd},
-    pin::Pin,
-    task::{Context, Poll},
-};
-use tokio::io::{unix::AsyncFd, Interest};
-
-use crate::device::AsyncDevice;
-
-pin_project! {
-    /// A device that uses a Unix raw socket to send and receive packets.
-    /// The socket is created with the `O_NONBLOCK` flag set.
-    pub struct AsyncCapture<T, R, S> {
-        obj: T,
-        recv: R,
-        send: S,
-        async_fd: AsyncFd<RawFd>,
-        temp: Option<Vec<u8>>,
-        poll_write: bool,
-        caps: DeviceCapabilities,
-    }
-}
-
-impl<T, R, S> AsyncCapture<T, R, S>
-where
-    T: AsRawFd,
-    R: Fn(&mut T) -> io::Result<Vec<u8>>,
-    S: Fn(&mut T, &[u8]) -> io::Result<()>,
-{
-    /// Make a new `AsyncCapture` with the given `obj` and `recv` and `send`
-    /// functions.
-    ///
-    ///
-    /// The `obj` is used to get the raw file descriptor.
-    ///
-    ///
-    /// The `recv` and `send` functions are used to read and write packets. They should
-    /// return Err(io::ErrorKind::WouldBlock) if the operation would block.
-    ///
-    ///
-    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
-    pub fn new(obj: T, recv: R, send: S, caps: DeviceCapabilities) -> io::Result<Self> {
-        let async_fd = AsyncFd::with_interest(obj.as_raw_fd(), Interest::READABLE)?;
-        Ok(AsyncCapture {
-            obj,
-            recv,
-            send,
-            async_fd,
-            temp: None,
-            poll_write: false,
-            caps,
-        })
-    }
-}
-
-impl<T, R, S> Stream for AsyncCapture<T, R, S>
-where
-    T: AsRawFd + Send,
-    R: Fn(&mut T) -> io::Result<Vec<u8>> + Send,
-    S: Fn(&mut T, &[u8]) -> io::Result<()> + Send,
-{
-    type Item = io::Result<Vec<u8>>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.project();
-        let obj = &mut this.obj;
-        let recv = this.recv;
-
-        loop {
-            match recv(obj) {
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    ready!(this.async_fd.poll_read_ready(cx))?.clear_ready()
-                }
-                r => return Poll::Ready(Some(r)),
-            };
-        }
-    }
-}
-
-impl<T, R, S> Sink<Vec<u8>> for AsyncCapture<T, R, S>
-where
-    T: AsRawFd + Send,
-    R: Fn(&mut T) -> io::Result<Vec<u8>> + Send,
-    S: Fn(&mut T, &[u8]) -> io::Result<()> + Send,
-{
-    type Error = io::Error;
-
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        assert!(self.temp.is_none());
-
-        if self.poll_write {
-            let this = self.project();
-
-            ready!(this.async_fd.poll_write_ready(cx))?.clear_ready();
-        }
-
-        Poll::Ready(Ok(()))
-    }
-
-    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        let mut this = self.project();
-        let obj = &mut this.obj;
-        let send = this.send;
-
-        match send(obj, &item) {
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                *this.temp = Some(item);
-                Ok(())
-            }
-            r => r,
-        }
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if !self.poll_write {
-            // drop packet
-            return Poll::Ready(Ok(()));
-        }
-        let mut this = self.project();
-        if let Some(p) = &this.temp {
-            let obj = &mut this.obj;
-            let send = this.send;
-
-            loop {
-                let mut guard = ready!(this.async_fd.poll_write_ready(cx))?;
-                match guard.try_io(|_| send(obj, &p)) {
-                    Ok(result) => {
-                        this.temp.take();
-                        return Poll::Ready(result);
-                    }
-                    Err(_) => continue,
-                }
-            }
-        } else {
-            Poll::Ready(Ok(()))
-        }
-    }
-
-    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
-    }
-}
-
-impl<T, R, S> AsyncDevice for AsyncCapture<T, R, S>
-where
-    T: AsRawFd + Send,
-    R: Fn(&mut T) -> io::Result<Vec<u8>> + Send,
-    S: Fn(&mut T, &[u8]) -> io::Result<()> + Send,
-{
-    fn capabilities(&self) -> &DeviceCapabilities {
-        &self.caps
-    }
-}
+use futures::{ready, Sink, Stream};
+use pin_project_lite::pin_project;
+use smoltcp::phy::DeviceCapabilities;
+use std::{
+    collections::VecDeque,
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::device::{
+    vec_adapter::packet_from_vec, AsyncDevice, OverflowPolicy, Packet, PacketPool,
+    DEFAULT_MAX_BURST_SIZE,
+};
+
+pin_project! {
+    /// A device that uses a Unix raw socket to send and receive packets.
+    /// The socket is created with the `O_NONBLOCK` flag set.
+    ///
+    /// Packets that can't be sent because the socket would block are queued
+    /// until it becomes writable. When the queue is full, the `OverflowPolicy`
+    /// decides whether to wait or to drop the oldest queued packet.
+    pub struct AsyncCapture<T, R, S> {
+        obj: T,
+        recv: R,
+        send: S,
+        async_fd: AsyncFd<RawFd>,
+        queue: VecDeque<Packet>,
+        queue_capacity: usize,
+        overflow: OverflowPolicy,
+        dropped: Arc<AtomicU64>,
+        mmsg: Option<mmsg::Buffers>,
+        caps: DeviceCapabilities,
+    }
+}
+
+impl<T, R, S> AsyncCapture<T, R, S>
+where
+    T: AsRawFd,
+    R: Fn(&mut T) -> io::Result<Vec<u8>>,
+    S: Fn(&mut T, &[u8]) -> io::Result<()>,
+{
+    /// Make a new `AsyncCapture` with the given `obj` and `recv` and `send`
+    /// functions.
+    ///
+    ///
+    /// The `obj` is used to get the raw file descriptor.
+    ///
+    ///
+    /// The `recv` and `send` functions are used to read and write packets. They should
+    /// return Err(io::ErrorKind::WouldBlock) if the operation would block.
+    ///
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn new(obj: T, recv: R, send: S, caps: DeviceCapabilities) -> io::Result<Self> {
+        // SAFETY: `obj` owns the file descriptor and lives as long as `async_fd`.
+        let async_fd = unsafe {
+            AsyncFd::register_with_interest(
+                obj.as_raw_fd(),
+                Interest::READABLE | Interest::WRITABLE,
+            )?
+        };
+        let queue_capacity = caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        Ok(AsyncCapture {
+            obj,
+            recv,
+            send,
+            async_fd,
+            queue: VecDeque::with_capacity(queue_capacity),
+            queue_capacity,
+            overflow: OverflowPolicy::default(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            mmsg: None,
+            caps,
+        })
+    }
+
+    /// Sets the number of packets queued while the socket is not writable.
+    /// Default to `DeviceCapabilities::max_burst_size`.
+    pub fn set_queue_capacity(&mut self, queue_capacity: usize) {
+        self.queue_capacity = queue_capacity.max(1);
+    }
+
+    /// Sets what to do when the send queue is full. Default to `OverflowPolicy::Block`.
+    pub fn set_overflow(&mut self, overflow: OverflowPolicy) {
+        self.overflow = overflow;
+    }
+
+    /// Returns the number of packets dropped so far, because the send queue
+    /// overflowed or they failed to be sent, or because they were received
+    /// truncated by `recvmmsg`.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped packets, which can be read after the
+    /// device is moved into a `Net`.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    /// Receives and sends batches of packets with `recvmmsg` and `sendmmsg`
+    /// on the file descriptor in `AsyncDevice::poll_recv_batch` and
+    /// `AsyncDevice::poll_send_batch`, which the reactor uses, instead of the
+    /// `recv` and `send` functions. The `Stream` and `Sink` implementations
+    /// still use the functions.
+    ///
+    /// Messages longer than `DeviceCapabilities::max_transmission_unit` are
+    /// dropped. Only enable it if `obj` is a socket where each message is a
+    /// packet and which can send without a destination address, such as an
+    /// `AF_PACKET` socket bound to an interface. Default to `false`.
+    #[cfg(target_os = "linux")]
+    pub fn set_mmsg(&mut self, mmsg: bool) {
+        self.mmsg = mmsg.then(|| {
+            mmsg::Buffers::new(PacketPool::new(
+                self.caps.max_transmission_unit * self.queue_capacity,
+            ))
+        });
+    }
+}
+
+/// Sends the queued packets until the queue is empty or the socket would block.
+///
+/// A packet failing to be sent is dropped and counted in `dropped`, and the
+/// rest of the queue is still sent. Only an error of the file descriptor
+/// itself is returned.
+fn poll_send_queue<T, S>(
+    obj: &mut T,
+    send: &S,
+    async_fd: &AsyncFd<RawFd>,
+    queue: &mut VecDeque<Packet>,
+    dropped: &AtomicU64,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>>
+where
+    S: Fn(&mut T, &[u8]) -> io::Result<()>,
+{
+    while let Some(packet) = queue.front() {
+        let mut guard = match async_fd.poll_write_ready(cx) {
+            Poll::Ready(guard) => guard?,
+            Poll::Pending => break,
+        };
+        if let Ok(result) = guard.try_io(|_| send(obj, packet)) {
+            queue.pop_front();
+            if result.is_err() {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    send_queue_result(queue)
+}
+
+/// Like `poll_send_queue`, but sends the queued packets with `sendmmsg`.
+#[cfg(target_os = "linux")]
+fn poll_send_queue_mmsg(
+    async_fd: &AsyncFd<RawFd>,
+    queue: &mut VecDeque<Packet>,
+    dropped: &AtomicU64,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    while !queue.is_empty() {
+        let mut guard = match async_fd.poll_write_ready(cx) {
+            Poll::Ready(guard) => guard?,
+            Poll::Pending => break,
+        };
+        if let Ok(Err(_)) = guard.try_io(|fd| mmsg::send(*fd.get_ref(), queue)) {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    send_queue_result(queue)
+}
+
+fn send_queue_result(queue: &VecDeque<Packet>) -> Poll<io::Result<()>> {
+    if queue.is_empty() {
+        Poll::Ready(Ok(()))
+    } else {
+        Poll::Pending
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::{collections::VecDeque, io, mem, os::unix::io::RawFd, ptr};
+
+    use crate::device::{Packet, PacketPool};
+
+    /// The maximum number of messages passed to a single syscall.
+    const MAX_BATCH: usize = 64;
+
+    /// The buffers `recv` receives into.
+    pub(super) struct Buffers {
+        pool: PacketPool,
+        /// Empty packets received into, kept across calls until they are filled.
+        spare: VecDeque<Packet>,
+    }
+
+    impl Buffers {
+        pub(super) fn new(pool: PacketPool) -> Self {
+            Buffers {
+                pool,
+                spare: VecDeque::new(),
+            }
+        }
+    }
+
+    /// Receives up to `max` packets of at most `mtu` bytes, appending them to `buf`.
+    ///
+    /// Returns the number of packets received and the number of messages
+    /// dropped because they were longer than `mtu`.
+    pub(super) fn recv(
+        fd: RawFd,
+        buffers: &mut Buffers,
+        mtu: usize,
+        buf: &mut VecDeque<Packet>,
+        max: usize,
+    ) -> io::Result<(usize, usize)> {
+        let max = max.min(MAX_BATCH);
+        while buffers.spare.len() < max {
+            let packet = buffers.pool.spare(mtu);
+            buffers.spare.push_back(packet);
+        }
+
+        // SAFETY: `iovec` and `mmsghdr` are plain C structs, for which zero is a valid value.
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        for (iov, packet) in iovecs.iter_mut().zip(buffers.spare.iter_mut()) {
+            let spare = packet.spare_capacity_mut();
+            iov.iov_base = spare.as_mut_ptr().cast();
+            iov.iov_len = spare.len().min(mtu);
+        }
+        let iov = iovecs.as_mut_ptr();
+        for (i, msg) in msgs.iter_mut().enumerate().take(max) {
+            // SAFETY: `i` is within `iovecs`.
+            msg.msg_hdr.msg_iov = unsafe { iov.add(i) };
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: the first `max` messages point to the spare capacity of
+        // packets owned by `buffers`, which outlive the call.
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                max as _,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut received = 0;
+        let mut truncated = 0;
+        for msg in &msgs[..n as usize] {
+            let mut packet = buffers.spare.pop_front().unwrap();
+            if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                // Receive into it again.
+                buffers.spare.push_back(packet);
+                truncated += 1;
+                continue;
+            }
+            // SAFETY: `recvmmsg` initialized the first `msg_len` bytes of the
+            // spare capacity, which is at least as long.
+            unsafe { packet.set_len(msg.msg_len as usize) };
+            buf.push_back(packet);
+            received += 1;
+        }
+        Ok((received, truncated))
+    }
+
+    /// Sends the packets at the front of `queue`, removing the ones sent.
+    ///
+    /// If the first packet fails to be sent for another reason than
+    /// `io::ErrorKind::WouldBlock`, it is removed as well.
+    pub(super) fn send(fd: RawFd, queue: &mut VecDeque<Packet>) -> io::Result<()> {
+        let n = queue.len().min(MAX_BATCH);
+
+        // SAFETY: `iovec` and `mmsghdr` are plain C structs, for which zero is a valid value.
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        for (iov, packet) in iovecs.iter_mut().zip(queue.iter()) {
+            iov.iov_base = packet.as_ptr() as *mut _;
+            iov.iov_len = packet.len();
+        }
+        let iov = iovecs.as_mut_ptr();
+        for (i, msg) in msgs.iter_mut().enumerate().take(n) {
+            // SAFETY: `i` is within `iovecs`.
+            msg.msg_hdr.msg_iov = unsafe { iov.add(i) };
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: the first `n` messages point to buffers owned by `queue`,
+        // which outlive the call. They are only read by `sendmmsg`.
+        let sent =
+            unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), n as _, libc::MSG_DONTWAIT as _) };
+        if sent < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                queue.pop_front();
+            }
+            return Err(e);
+        }
+        queue.drain(..sent as usize);
+        Ok(())
+    }
+}
+
+impl<T, R, S> Stream for AsyncCapture<T, R, S>
+where
+    T: AsRawFd + Send,
+    R: Fn(&mut T) -> io::Result<Vec<u8>> + Send,
+    S: Fn(&mut T, &[u8]) -> io::Result<()> + Send,
+{
+    type Item = io::Result<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let obj = &mut this.obj;
+        let recv = this.recv;
+
+        // Packets left behind by a non-blocking flush are sent from here, as
+        // the stream is polled whenever the reactor waits. Failed packets are
+        // counted as dropped.
+        let _ = poll_send_queue(
+            *obj,
+            this.send,
+            this.async_fd,
+            this.queue,
+            this.dropped,
+            cx,
+        );
+
+        loop {
+            match recv(obj) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(this.async_fd.poll_read_ready(cx))?.clear_ready()
+                }
+                r => return Poll::Ready(Some(r.map(packet_from_vec))),
+            };
+        }
+    }
+}
+
+impl<T, R, S> Sink<Packet> for AsyncCapture<T, R, S>
+where
+    T: AsRawFd + Send,
+    R: Fn(&mut T) -> io::Result<Vec<u8>> + Send,
+    S: Fn(&mut T, &[u8]) -> io::Result<()> + Send,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+
+        if let Poll::Ready(result) = poll_send_queue(
+            this.obj,
+            this.send,
+            this.async_fd,
+            this.queue,
+            this.dropped,
+            cx,
+        ) {
+            result?;
+        }
+        if this.queue.len() < *this.queue_capacity || *this.overflow == OverflowPolicy::DropOldest
+        {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        let this = self.project();
+
+        if this.queue.is_empty() {
+            match (this.send)(this.obj, &item) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // Like in the queue, a failed packet is dropped instead of
+                // stopping the `Net`.
+                Err(_) => {
+                    this.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            }
+        }
+        if this.queue.len() >= *this.queue_capacity {
+            this.queue.pop_front();
+            this.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        this.queue.push_back(item);
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+
+        match poll_send_queue(
+            this.obj,
+            this.send,
+            this.async_fd,
+            this.queue,
+            this.dropped,
+            cx,
+        ) {
+            // Don't hold the reactor back, the queue is drained when the stream is polled.
+            Poll::Pending if *this.overflow == OverflowPolicy::DropOldest => Poll::Ready(Ok(())),
+            r => r,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<T, R, S> AsyncDevice for AsyncCapture<T, R, S>
+where
+    T: AsRawFd + Send,
+    R: Fn(&mut T) -> io::Result<Vec<u8>> + Send,
+    S: Fn(&mut T, &[u8]) -> io::Result<()> + Send,
+{
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut VecDeque<Packet>,
+        max: usize,
+    ) -> Poll<Option<io::Result<usize>>> {
+        let Some(buffers) = &mut self.mmsg else {
+            return crate::device::poll_recv_batch(self, cx, buf, max);
+        };
+
+        let _ = poll_send_queue_mmsg(&self.async_fd, &mut self.queue, &self.dropped, cx);
+
+        let mtu = self.caps.max_transmission_unit;
+        loop {
+            let mut guard = ready!(self.async_fd.poll_read_ready(cx))?;
+            match guard.try_io(|fd| mmsg::recv(*fd.get_ref(), buffers, mtu, buf, max)) {
+                Ok(Ok((received, truncated))) => {
+                    self.dropped.fetch_add(truncated as u64, Ordering::Relaxed);
+                    if received > 0 || truncated == 0 {
+                        return Poll::Ready(Some(Ok(received)));
+                    }
+                }
+                Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Err(_would_block) => {}
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        packets: &mut VecDeque<Packet>,
+    ) -> Poll<io::Result<()>> {
+        if self.mmsg.is_none() {
+            return crate::device::poll_send_batch(self, cx, packets);
+        }
+
+        self.queue.append(packets);
+        if self.overflow == OverflowPolicy::DropOldest {
+            let excess = self.queue.len().saturating_sub(self.queue_capacity);
+            self.queue.drain(..excess);
+            self.dropped.fetch_add(excess as u64, Ordering::Relaxed);
+        }
+
+        match poll_send_queue_mmsg(&self.async_fd, &mut self.queue, &self.dropped, cx) {
+            // Don't hold the reactor back, the queue is drained when receiving.
+            Poll::Pending if self.overflow == OverflowPolicy::DropOldest => Poll::Ready(Ok(())),
+            r => r,
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, Stream, ready};
+use smoltcp::phy::DeviceCapabilities;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::device::{AsyncDevice, Packet};
+
+/// Converts a `Vec<u8>` into a `Packet` without copying.
+pub(crate) fn packet_from_vec(vec: Vec<u8>) -> Packet {
+    BytesMut::from(Bytes::from(vec))
+}
+
+/// An adapter for devices that send and receive packets as `Vec<u8>`.
+///
+/// Received vectors are turned into packets without copying, while
+/// transmitted packets are copied unless they own their whole buffer.
+pub struct VecAdapter<D> {
+    inner: D,
+    caps: DeviceCapabilities,
+}
+
+impl<D> VecAdapter<D>
+where
+    D: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Send + Unpin,
+{
+    /// Make a new `VecAdapter` wrapping `inner`.
+    ///
+    /// The `caps` is used to determine the device capabilities. `DeviceCapabilities::max_transmission_unit` must be set.
+    pub fn new(inner: D, caps: DeviceCapabilities) -> Self {
+        VecAdapter { inner, caps }
+    }
+
+    /// Returns a reference to the wrapped device.
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    /// Consumes the `VecAdapter`, returning the wrapped device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D> Stream for VecAdapter<D>
+where
+    D: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let packet = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        Poll::Ready(packet.map(|p| p.map(packet_from_vec)))
+    }
+}
+
+impl<D> Sink<Packet> for VecAdapter<D>
+where
+    D: Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item.into())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<D> AsyncDevice for VecAdapter<D>
+where
+    D: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Send + Unpin,
+{
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+}
@@ -0,0 +1,313 @@
+use bytes::Buf;
+use futures::{Sink, Stream, ready};
+use parking_lot::Mutex;
+use smoltcp::phy::{DeviceCapabilities, Medium};
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use crate::device::{AsyncDevice, DEFAULT_MAX_BURST_SIZE, Packet, PacketPool};
+
+const TPID_8021Q: u16 = 0x8100;
+const TAG_LEN: usize = 4;
+const ADDRESSES_LEN: usize = 12;
+
+#[derive(Default)]
+struct Port {
+    recv_queue: VecDeque<Packet>,
+    recv_waker: Option<Waker>,
+    send_waker: Option<Waker>,
+}
+
+struct Shared {
+    trunk: Box<dyn AsyncDevice>,
+    ports: HashMap<Option<u16>, Port>,
+    ended: bool,
+    max_queue_len: usize,
+}
+
+impl Shared {
+    /// Queues a frame received on the trunk for its port, or drops it if no
+    /// port is open for its VLAN.
+    ///
+    /// Priority-tagged frames, whose VLAN ID is 0, belong to the untagged port.
+    fn route(&mut self, mut frame: Packet, dropped: &AtomicU64) {
+        let vid = match frame.get(ADDRESSES_LEN..ADDRESSES_LEN + TAG_LEN) {
+            Some(tag) if u16::from_be_bytes([tag[0], tag[1]]) == TPID_8021Q => {
+                let vid = u16::from_be_bytes([tag[2], tag[3]]) & 0x0fff;
+                frame.copy_within(..ADDRESSES_LEN, TAG_LEN);
+                frame.advance(TAG_LEN);
+                Some(vid).filter(|&vid| vid != 0)
+            }
+            _ => None,
+        };
+        match self.ports.get_mut(&vid) {
+            Some(port) if port.recv_queue.len() < self.max_queue_len => {
+                port.recv_queue.push_back(frame);
+                if let Some(waker) = port.recv_waker.take() {
+                    waker.wake();
+                }
+            }
+            _ => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for port in self.ports.values_mut() {
+            if let Some(waker) = port.recv_waker.take() {
+                waker.wake();
+            }
+            if let Some(waker) = port.send_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Splits an Ethernet trunk device into one device per 802.1Q VLAN.
+///
+/// Frames received on the trunk are delivered untagged to the `VlanPort` of
+/// their VLAN, and frames sent on a `VlanPort` are tagged with its VLAN ID.
+/// Untagged and priority-tagged frames go to the untagged port. Frames for a
+/// VLAN without a port are dropped. The trunk is driven by the
+/// ports, so no task is needed, but every port should be polled, e.g. by being
+/// moved into a `Net`.
+pub struct Vlan {
+    shared: Arc<Mutex<Shared>>,
+    dropped: Arc<AtomicU64>,
+    caps: DeviceCapabilities,
+}
+
+impl Vlan {
+    /// Make a new `Vlan` over `trunk`, whose medium must be `Medium::Ethernet`.
+    pub fn new(trunk: impl AsyncDevice + 'static) -> io::Result<Vlan> {
+        let caps = trunk.capabilities().clone();
+        if caps.medium != Medium::Ethernet {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Vlan only supports Medium::Ethernet",
+            ));
+        }
+        let max_queue_len = caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        Ok(Vlan {
+            shared: Arc::new(Mutex::new(Shared {
+                trunk: Box::new(trunk),
+                ports: HashMap::new(),
+                ended: false,
+                max_queue_len,
+            })),
+            dropped: Arc::new(AtomicU64::new(0)),
+            caps,
+        })
+    }
+
+    /// Opens the port of VLAN `vid`.
+    ///
+    /// Its `max_transmission_unit` is the trunk's minus the size of the tag.
+    pub fn port(&self, vid: u16) -> io::Result<VlanPort> {
+        if !(1..=4094).contains(&vid) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid VLAN ID {}", vid),
+            ));
+        }
+        let mut caps = self.caps.clone();
+        caps.max_transmission_unit -= TAG_LEN;
+        self.open(Some(vid), caps)
+    }
+
+    /// Opens the port sending and receiving untagged frames.
+    pub fn untagged(&self) -> io::Result<VlanPort> {
+        self.open(None, self.caps.clone())
+    }
+
+    /// Returns the number of frames dropped so far, because no port is open
+    /// for their VLAN or the port's queue is full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter of dropped frames, which can be read after the
+    /// ports are moved into `Net`s.
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    fn open(&self, vid: Option<u16>, caps: DeviceCapabilities) -> io::Result<VlanPort> {
+        match self.shared.lock().ports.entry(vid) {
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "the VLAN port is already open",
+                ));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Port::default());
+            }
+        }
+        let max_burst_size = caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        Ok(VlanPort {
+            shared: self.shared.clone(),
+            dropped: self.dropped.clone(),
+            vid,
+            pool: PacketPool::new(self.caps.max_transmission_unit * max_burst_size),
+            send_queue: VecDeque::new(),
+            caps,
+        })
+    }
+}
+
+/// The device of one VLAN of a `Vlan`.
+pub struct VlanPort {
+    shared: Arc<Mutex<Shared>>,
+    dropped: Arc<AtomicU64>,
+    vid: Option<u16>,
+    pool: PacketPool,
+    send_queue: VecDeque<Packet>,
+    caps: DeviceCapabilities,
+}
+
+impl VlanPort {
+    /// Returns the VLAN ID of the port, or `None` for the untagged port.
+    pub fn vid(&self) -> Option<u16> {
+        self.vid
+    }
+
+    fn tag(&mut self, frame: Packet) -> Packet {
+        let Some(vid) = self.vid else {
+            return frame;
+        };
+        if frame.len() < ADDRESSES_LEN {
+            return frame;
+        }
+        self.pool.concat(&[
+            &frame[..ADDRESSES_LEN],
+            &TPID_8021Q.to_be_bytes(),
+            &vid.to_be_bytes(),
+            &frame[ADDRESSES_LEN..],
+        ])
+    }
+
+    /// Sends the queued frames on the trunk.
+    fn poll_send_queue(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock();
+        let shared = &mut *shared;
+        match shared.trunk.poll_send_batch(cx, &mut self.send_queue) {
+            Poll::Ready(r) => {
+                // The trunk only wakes the last port that polled it, let the
+                // others try again.
+                for port in shared.ports.values_mut() {
+                    if let Some(waker) = port.send_waker.take() {
+                        waker.wake();
+                    }
+                }
+                Poll::Ready(r)
+            }
+            Poll::Pending => {
+                if let Some(port) = shared.ports.get_mut(&self.vid) {
+                    port.send_waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for VlanPort {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.ports.remove(&self.vid);
+        // This port may be the one the trunk would wake.
+        shared.wake_all();
+    }
+}
+
+impl Stream for VlanPort {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut shared = this.shared.lock();
+
+        loop {
+            let port = shared.ports.get_mut(&this.vid).unwrap();
+            if let Some(frame) = port.recv_queue.pop_front() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            if shared.ended {
+                return Poll::Ready(None);
+            }
+
+            match Pin::new(&mut shared.trunk).poll_next(cx) {
+                Poll::Ready(Some(Ok(frame))) => {
+                    shared.route(frame, &this.dropped);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    shared.ended = true;
+                    shared.wake_all();
+                }
+                Poll::Pending => {
+                    let port = shared.ports.get_mut(&this.vid).unwrap();
+                    port.recv_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl Sink<Packet> for VlanPort {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let max_burst_size = this.caps.max_burst_size.unwrap_or(DEFAULT_MAX_BURST_SIZE);
+        if this.send_queue.len() >= max_burst_size {
+            ready!(this.poll_send_queue(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let frame = this.tag(item);
+        this.send_queue.push_back(frame);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_queue(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncDevice for VlanPort {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+
+    fn poll_send_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        packets: &mut VecDeque<Packet>,
+    ) -> Poll<io::Result<()>> {
+        while let Some(packet) = packets.pop_front() {
+            let frame = self.tag(packet);
+            self.send_queue.push_back(frame);
+        }
+        self.poll_send_queue(cx)
+    }
+}
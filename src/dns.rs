@@ -0,0 +1,121 @@
+//! A minimal DNS client, resolving domain names through a DNS server inside a
+//! `Net`.
+
+use crate::{Net, clock::timeout};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a DNS server is waited for, unless told otherwise.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Asks `server` for the IPv4, then the IPv6, addresses of `name`.
+pub(crate) async fn lookup(
+    net: &Net,
+    server: SocketAddr,
+    name: &str,
+    wait: Duration,
+) -> io::Result<Vec<IpAddr>> {
+    let socket = net.udp_bind((Ipv4Addr::UNSPECIFIED, 0).into()).await?;
+    let mut buf = vec![0; 1500];
+    for kind in [TYPE_A, TYPE_AAAA] {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos() as u16);
+        socket.send_to(&query(id, name, kind)?, server).await?;
+        let answer = timeout(&**net.clock(), wait, async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                if from == server && buf[..len].starts_with(&id.to_be_bytes()) {
+                    return io::Result::Ok(parse_answer(&buf[..len]));
+                }
+            }
+        })
+        .await
+        .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"))??;
+        if !answer.is_empty() {
+            return Ok(answer);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no address for the domain name",
+    ))
+}
+
+fn query(id: u16, name: &str, kind: u16) -> io::Result<Vec<u8>> {
+    // A recursive query with a single question.
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid domain name",
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&kind.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+/// Returns the addresses answered in `reply`.
+fn parse_answer(reply: &[u8]) -> Vec<IpAddr> {
+    parse_records(reply).unwrap_or_default()
+}
+
+fn parse_records(reply: &[u8]) -> Option<Vec<IpAddr>> {
+    let u16_at = |pos: usize| {
+        Some(u16::from_be_bytes(
+            reply.get(pos..pos + 2)?.try_into().ok()?,
+        ))
+    };
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 || flags & 0xf != 0 {
+        return None;
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut addrs = Vec::new();
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(reply, pos)? + 4;
+    }
+    for _ in 0..answers {
+        pos = skip_name(reply, pos)?;
+        let kind = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = reply.get(pos + 10..pos + 10 + len)?;
+        match (kind, data.len()) {
+            (TYPE_A, 4) => addrs.push(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (TYPE_AAAA, 16) => addrs.push(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Some(addrs)
+}
+
+/// Returns the position after the name at `pos`.
+fn skip_name(reply: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *reply.get(pos)?;
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        pos += 1 + len as usize;
+    }
+}
@@ -0,0 +1,548 @@
+//! Filters the packets exchanged between a `Net` and its device.
+//!
+//! A `PacketFilter` set in `NetConfig::filter` sees every packet the device
+//! receives before smoltcp does, and every packet smoltcp emits before the
+//! device sends it. `Rules` is a first-match rule list usable as one, e.g. to
+//! sandbox the traffic of an untrusted guest.
+
+use crate::device::Packet;
+use smoltcp::{
+    phy::Medium,
+    wire::{IpAddress, IpCidr, IpProtocol, Ipv4Address, Ipv6Address},
+};
+use std::{
+    ops::{BitOr, RangeInclusive},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// What to do with a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the packet through.
+    Accept,
+    /// Silently discard the packet.
+    Drop,
+}
+
+/// The way a packet goes through a `Net`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the device, on its way to smoltcp.
+    Ingress,
+    /// Emitted by smoltcp, on its way to the device.
+    Egress,
+}
+
+/// A hook called by the reactor on every packet between the device and
+/// smoltcp.
+///
+/// A filter may rewrite the packet in place before accepting it, it's then
+/// responsible for keeping the packet valid, e.g. its checksums. Both methods
+/// accept every packet by default.
+pub trait PacketFilter: Send {
+    /// Called on a packet received from the device, framed as `medium`.
+    fn ingress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        let _ = (medium, packet);
+        Verdict::Accept
+    }
+    /// Called on a packet emitted by smoltcp, framed as `medium`.
+    fn egress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        let _ = (medium, packet);
+        Verdict::Accept
+    }
+}
+
+/// TCP header flags, as matched by `Rule::tcp_flags`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TcpFlags(u8);
+
+impl TcpFlags {
+    pub const FIN: TcpFlags = TcpFlags(0x01);
+    pub const SYN: TcpFlags = TcpFlags(0x02);
+    pub const RST: TcpFlags = TcpFlags(0x04);
+    pub const PSH: TcpFlags = TcpFlags(0x08);
+    pub const ACK: TcpFlags = TcpFlags(0x10);
+    pub const URG: TcpFlags = TcpFlags(0x20);
+
+    /// Returns no flag.
+    pub const fn empty() -> TcpFlags {
+        TcpFlags(0)
+    }
+    /// Returns whether all the flags of `other` are set.
+    pub const fn contains(self, other: TcpFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for TcpFlags {
+    type Output = TcpFlags;
+
+    fn bitor(self, rhs: TcpFlags) -> TcpFlags {
+        TcpFlags(self.0 | rhs.0)
+    }
+}
+
+/// Counts the packets matched by a `Rule`. Clones share the same counts, so
+/// they can be read after the `Rules` are moved into a `NetConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct RuleCounter {
+    packets: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl RuleCounter {
+    /// Returns the number of packets matched so far.
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+    /// Returns the number of bytes matched so far, link-layer header included.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+    fn count(&self, packet: &[u8]) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+    }
+}
+
+/// A rule of `Rules`, matching the packets that meet all of its conditions.
+///
+/// A rule without condition matches every packet. Address conditions never
+/// match a packet that isn't IP, e.g. ARP, and port or flag conditions never
+/// match a packet without ports, e.g. a non-first IPv4 fragment. The protocol
+/// of an IPv6 packet is the next header of its fixed header, extension headers
+/// aren't skipped.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    verdict: Verdict,
+    direction: Option<Direction>,
+    protocol: Option<IpProtocol>,
+    src: Option<IpCidr>,
+    dst: Option<IpCidr>,
+    src_port: Option<RangeInclusive<u16>>,
+    dst_port: Option<RangeInclusive<u16>>,
+    tcp_flags: Option<(TcpFlags, TcpFlags)>,
+    counter: RuleCounter,
+}
+
+impl Rule {
+    /// Makes a rule applying `verdict` to every packet.
+    pub fn new(verdict: Verdict) -> Rule {
+        Rule {
+            verdict,
+            direction: None,
+            protocol: None,
+            src: None,
+            dst: None,
+            src_port: None,
+            dst_port: None,
+            tcp_flags: None,
+            counter: RuleCounter::default(),
+        }
+    }
+    /// Only matches the packets going `direction`.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+    /// Only matches the packets carrying `protocol`.
+    pub fn protocol(mut self, protocol: IpProtocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+    /// Only matches the packets from an address in `cidr`.
+    pub fn src(mut self, cidr: IpCidr) -> Self {
+        self.src = Some(cidr);
+        self
+    }
+    /// Only matches the packets to an address in `cidr`.
+    pub fn dst(mut self, cidr: IpCidr) -> Self {
+        self.dst = Some(cidr);
+        self
+    }
+    /// Only matches the TCP, UDP or SCTP packets from a port in `ports`.
+    pub fn src_port(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.src_port = Some(ports);
+        self
+    }
+    /// Only matches the TCP, UDP or SCTP packets to a port in `ports`.
+    pub fn dst_port(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.dst_port = Some(ports);
+        self
+    }
+    /// Only matches the TCP segments whose flags in `mask` are exactly
+    /// `flags`, e.g. `tcp_flags(TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK)`
+    /// matches the segments opening a connection.
+    pub fn tcp_flags(mut self, flags: TcpFlags, mask: TcpFlags) -> Self {
+        self.tcp_flags = Some((flags, mask));
+        self
+    }
+    /// Returns the counter of the packets matched by this rule.
+    pub fn counter(&self) -> RuleCounter {
+        self.counter.clone()
+    }
+
+    fn matches(&self, direction: Direction, packet: &Headers) -> bool {
+        if self.direction.is_some_and(|d| d != direction) {
+            return false;
+        }
+        let needs_ip = self.protocol.is_some()
+            || self.src.is_some()
+            || self.dst.is_some()
+            || self.needs_ports();
+        let Some(ip) = &packet.ip else {
+            return !needs_ip;
+        };
+        if self.protocol.is_some_and(|p| p != ip.protocol)
+            || self.src.is_some_and(|cidr| !cidr.contains_addr(&ip.src))
+            || self.dst.is_some_and(|cidr| !cidr.contains_addr(&ip.dst))
+        {
+            return false;
+        }
+        if !self.needs_ports() {
+            return true;
+        }
+        let Some((src_port, dst_port)) = ip.ports else {
+            return false;
+        };
+        let in_range = |ports: &Option<RangeInclusive<u16>>, port| {
+            ports.as_ref().is_none_or(|ports| ports.contains(&port))
+        };
+        if !in_range(&self.src_port, src_port) || !in_range(&self.dst_port, dst_port) {
+            return false;
+        }
+        match self.tcp_flags {
+            Some((flags, mask)) => ip
+                .tcp_flags
+                .is_some_and(|f| TcpFlags(f.0 & mask.0) == flags),
+            None => true,
+        }
+    }
+    fn needs_ports(&self) -> bool {
+        self.src_port.is_some() || self.dst_port.is_some() || self.tcp_flags.is_some()
+    }
+}
+
+/// A `PacketFilter` applying the verdict of the first matching `Rule`, or the
+/// default verdict if none matches.
+///
+/// ```
+/// use tokio_smoltcp::filter::{Direction, Rule, Rules, Verdict};
+/// use tokio_smoltcp::smoltcp::wire::IpProtocol;
+///
+/// // Only let the guest reach the world on HTTPS and DNS.
+/// let rules = Rules::new(Verdict::Drop)
+///     .rule(Rule::new(Verdict::Accept).protocol(IpProtocol::Tcp).dst_port(443..=443))
+///     .rule(Rule::new(Verdict::Accept).protocol(IpProtocol::Udp).dst_port(53..=53))
+///     .rule(Rule::new(Verdict::Accept).direction(Direction::Ingress));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    default: Verdict,
+    default_counter: RuleCounter,
+}
+
+impl Rules {
+    /// Makes an empty rule list, applying `default` to every packet.
+    pub fn new(default: Verdict) -> Rules {
+        Rules {
+            rules: Vec::new(),
+            default,
+            default_counter: RuleCounter::default(),
+        }
+    }
+    /// Appends `rule`, it applies to the packets no previous rule matched.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+    /// Returns the counters of the rules, in the order they were added.
+    pub fn counters(&self) -> Vec<RuleCounter> {
+        self.rules.iter().map(Rule::counter).collect()
+    }
+    /// Returns the counter of the packets no rule matched.
+    pub fn default_counter(&self) -> RuleCounter {
+        self.default_counter.clone()
+    }
+
+    fn apply(&self, direction: Direction, medium: Medium, packet: &[u8]) -> Verdict {
+        let headers = Headers::parse(medium, packet);
+        match self.rules.iter().find(|r| r.matches(direction, &headers)) {
+            Some(rule) => {
+                rule.counter.count(packet);
+                rule.verdict
+            }
+            None => {
+                self.default_counter.count(packet);
+                self.default
+            }
+        }
+    }
+}
+
+impl PacketFilter for Rules {
+    fn ingress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        self.apply(Direction::Ingress, medium, packet)
+    }
+    fn egress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        self.apply(Direction::Egress, medium, packet)
+    }
+}
+
+/// The headers of a packet matched by the rules.
+pub(crate) struct Headers {
+    pub(crate) ip: Option<IpHeaders>,
+}
+
+pub(crate) struct IpHeaders {
+    pub(crate) src: IpAddress,
+    pub(crate) dst: IpAddress,
+    pub(crate) protocol: IpProtocol,
+    pub(crate) ports: Option<(u16, u16)>,
+    pub(crate) tcp_flags: Option<TcpFlags>,
+    /// Whether the packet is an IPv4 fragment, the first one included.
+    pub(crate) fragment: bool,
+}
+
+impl Headers {
+    pub(crate) fn parse(medium: Medium, packet: &[u8]) -> Headers {
+        let (ethertype, ip) = match medium {
+            Medium::Ethernet if packet.len() >= 14 => {
+                (u16::from_be_bytes([packet[12], packet[13]]), &packet[14..])
+            }
+            Medium::Ip => match packet.first().map(|b| b >> 4) {
+                Some(4) => (0x0800, packet),
+                Some(6) => (0x86dd, packet),
+                _ => (0, packet),
+            },
+            #[allow(unreachable_patterns)]
+            _ => (0, packet),
+        };
+
+        let (src, dst, protocol, l4, fragment): (IpAddress, IpAddress, u8, &[u8], bool) =
+            match ethertype {
+                0x0800 if ip.len() >= 20 => {
+                    let header_len = ((ip[0] & 0x0f) as usize) * 4;
+                    let flags_offset = u16::from_be_bytes([ip[6], ip[7]]);
+                    // Only the first fragment carries the ports.
+                    let l4 = if flags_offset & 0x1fff != 0 {
+                        &[][..]
+                    } else {
+                        ip.get(header_len..).unwrap_or(&[])
+                    };
+                    let addr = |at: usize| -> [u8; 4] { ip[at..at + 4].try_into().unwrap() };
+                    (
+                        Ipv4Address::from(addr(12)).into(),
+                        Ipv4Address::from(addr(16)).into(),
+                        ip[9],
+                        l4,
+                        // More fragments, or a non-zero offset.
+                        flags_offset & 0x3fff != 0,
+                    )
+                }
+                0x86dd if ip.len() >= 40 => {
+                    let addr = |at: usize| -> [u8; 16] { ip[at..at + 16].try_into().unwrap() };
+                    (
+                        Ipv6Address::from(addr(8)).into(),
+                        Ipv6Address::from(addr(24)).into(),
+                        ip[6],
+                        &ip[40..],
+                        false,
+                    )
+                }
+                _ => return Headers { ip: None },
+            };
+
+        // TCP, UDP and SCTP have the ports at the start of the header.
+        let ports = (matches!(protocol, 6 | 17 | 132) && l4.len() >= 4).then(|| {
+            (
+                u16::from_be_bytes([l4[0], l4[1]]),
+                u16::from_be_bytes([l4[2], l4[3]]),
+            )
+        });
+        let tcp_flags = (protocol == 6 && l4.len() >= 20).then(|| TcpFlags(l4[13] & 0x3f));
+        Headers {
+            ip: Some(IpHeaders {
+                src,
+                dst,
+                protocol: IpProtocol::from(protocol),
+                ports,
+                tcp_flags,
+                fragment,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IPv4 packet of `protocol` from `src` to `dst`, with a 20-byte
+    /// transport header starting with the ports and holding `flags`.
+    fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], ports: (u16, u16), flags: u8) -> Vec<u8> {
+        let mut packet = vec![0; 40];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&40u16.to_be_bytes());
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dst);
+        packet[20..22].copy_from_slice(&ports.0.to_be_bytes());
+        packet[22..24].copy_from_slice(&ports.1.to_be_bytes());
+        packet[33] = flags;
+        packet
+    }
+
+    fn tcp(dst_port: u16, flags: TcpFlags) -> Vec<u8> {
+        ipv4(6, [10, 0, 0, 2], [10, 0, 0, 1], (40000, dst_port), flags.0)
+    }
+
+    fn udp(src: [u8; 4], dst_port: u16) -> Vec<u8> {
+        ipv4(17, src, [10, 0, 0, 1], (40000, dst_port), 0)
+    }
+
+    fn ingress(rules: &Rules, packet: &[u8]) -> Verdict {
+        rules.apply(Direction::Ingress, Medium::Ip, packet)
+    }
+
+    #[test]
+    fn cidr() {
+        let rules = Rules::new(Verdict::Drop)
+            .rule(Rule::new(Verdict::Accept).src("10.1.0.0/16".parse().unwrap()))
+            .rule(Rule::new(Verdict::Accept).dst("192.168.0.0/24".parse().unwrap()));
+
+        assert_eq!(ingress(&rules, &udp([10, 1, 2, 3], 53)), Verdict::Accept);
+        assert_eq!(ingress(&rules, &udp([10, 2, 2, 3], 53)), Verdict::Drop);
+        let to_lan = ipv4(17, [10, 2, 2, 3], [192, 168, 0, 9], (1, 2), 0);
+        assert_eq!(ingress(&rules, &to_lan), Verdict::Accept);
+        let to_wan = ipv4(17, [10, 2, 2, 3], [192, 168, 1, 9], (1, 2), 0);
+        assert_eq!(ingress(&rules, &to_wan), Verdict::Drop);
+    }
+
+    #[test]
+    fn port_range() {
+        let rules = Rules::new(Verdict::Drop).rule(
+            Rule::new(Verdict::Accept)
+                .protocol(IpProtocol::Udp)
+                .dst_port(50..=60),
+        );
+
+        for (port, verdict) in [
+            (49, Verdict::Drop),
+            (50, Verdict::Accept),
+            (53, Verdict::Accept),
+            (60, Verdict::Accept),
+            (61, Verdict::Drop),
+        ] {
+            assert_eq!(ingress(&rules, &udp([10, 0, 0, 2], port)), verdict);
+        }
+        // The protocol has to match too.
+        assert_eq!(ingress(&rules, &tcp(53, TcpFlags::SYN)), Verdict::Drop);
+    }
+
+    #[test]
+    fn tcp_flags_mask() {
+        // Drop the segments opening a connection, let the rest through.
+        let rules = Rules::new(Verdict::Accept).rule(
+            Rule::new(Verdict::Drop)
+                .protocol(IpProtocol::Tcp)
+                .tcp_flags(TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK),
+        );
+
+        assert_eq!(ingress(&rules, &tcp(80, TcpFlags::SYN)), Verdict::Drop);
+        // Flags outside the mask don't matter.
+        let syn_psh = TcpFlags::SYN | TcpFlags::PSH;
+        assert_eq!(ingress(&rules, &tcp(80, syn_psh)), Verdict::Drop);
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        assert_eq!(ingress(&rules, &tcp(80, syn_ack)), Verdict::Accept);
+        assert_eq!(ingress(&rules, &tcp(80, TcpFlags::ACK)), Verdict::Accept);
+        // UDP has no flags.
+        let rules = Rules::new(Verdict::Accept)
+            .rule(Rule::new(Verdict::Drop).tcp_flags(TcpFlags::empty(), TcpFlags::empty()));
+        assert_eq!(ingress(&rules, &udp([10, 0, 0, 2], 53)), Verdict::Accept);
+        assert_eq!(ingress(&rules, &tcp(80, TcpFlags::ACK)), Verdict::Drop);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = Rules::new(Verdict::Accept)
+            .rule(Rule::new(Verdict::Accept).dst_port(443..=443))
+            .rule(Rule::new(Verdict::Drop).protocol(IpProtocol::Tcp))
+            .rule(Rule::new(Verdict::Accept).dst_port(80..=80));
+
+        assert_eq!(ingress(&rules, &tcp(443, TcpFlags::SYN)), Verdict::Accept);
+        // The second rule matches before the third.
+        assert_eq!(ingress(&rules, &tcp(80, TcpFlags::SYN)), Verdict::Drop);
+        assert_eq!(ingress(&rules, &udp([10, 0, 0, 2], 80)), Verdict::Accept);
+    }
+
+    #[test]
+    fn direction_and_non_ip() {
+        let rules = Rules::new(Verdict::Accept)
+            .rule(Rule::new(Verdict::Drop).direction(Direction::Egress))
+            .rule(Rule::new(Verdict::Drop).protocol(IpProtocol::Udp));
+
+        let packet = tcp(80, TcpFlags::SYN);
+        assert_eq!(ingress(&rules, &packet), Verdict::Accept);
+        assert_eq!(
+            rules.apply(Direction::Egress, Medium::Ip, &packet),
+            Verdict::Drop
+        );
+
+        // An ARP frame only matches the rules without IP conditions.
+        let mut arp = vec![0; 42];
+        arp[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(
+            rules.apply(Direction::Ingress, Medium::Ethernet, &arp),
+            Verdict::Accept
+        );
+        assert_eq!(
+            rules.apply(Direction::Egress, Medium::Ethernet, &arp),
+            Verdict::Drop
+        );
+    }
+
+    #[test]
+    fn fragments() {
+        let rules = Rules::new(Verdict::Drop).rule(Rule::new(Verdict::Accept).dst_port(53..=53));
+
+        // The first fragment carries the ports.
+        let mut first = udp([10, 0, 0, 2], 53);
+        first[6] = 0x20;
+        assert_eq!(ingress(&rules, &first), Verdict::Accept);
+        let mut next = udp([10, 0, 0, 2], 53);
+        next[7] = 3;
+        assert_eq!(ingress(&rules, &next), Verdict::Drop);
+
+        let headers = |packet: &[u8]| Headers::parse(Medium::Ip, packet).ip.unwrap();
+        assert!(headers(&first).fragment && headers(&next).fragment);
+        assert!(!headers(&udp([10, 0, 0, 2], 53)).fragment);
+    }
+
+    #[test]
+    fn counters() {
+        let rules = Rules::new(Verdict::Drop)
+            .rule(Rule::new(Verdict::Accept).protocol(IpProtocol::Tcp))
+            .rule(Rule::new(Verdict::Accept).dst_port(53..=53));
+        let [tcp_counter, dns_counter] = rules.counters().try_into().unwrap();
+        let default_counter = rules.default_counter();
+
+        ingress(&rules, &tcp(80, TcpFlags::SYN));
+        ingress(&rules, &tcp(53, TcpFlags::SYN));
+        ingress(&rules, &udp([10, 0, 0, 2], 53));
+        let mut short = udp([10, 0, 0, 2], 123);
+        short.truncate(28);
+        ingress(&rules, &short);
+
+        assert_eq!((tcp_counter.packets(), tcp_counter.bytes()), (2, 80));
+        assert_eq!((dns_counter.packets(), dns_counter.bytes()), (1, 40));
+        assert_eq!(
+            (default_counter.packets(), default_counter.bytes()),
+            (1, 28)
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Forwards of host ports into a `Net`, see `Net::forward_tcp` and
+//! `Net::forward_udp`.
+
+use crate::{
+    TcpStream, UdpSocket,
+    clock::sleep,
+    next_port,
+    reactor::Reactor,
+    relay::{UdpRelay, Until},
+};
+use smoltcp::wire::IpAddress;
+use std::{
+    future, io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, UdpSocket as HostUdpSocket},
+    select,
+    task::{JoinHandle, JoinSet},
+};
+
+/// The default limit of connections, or UDP peers, of a forward.
+const MAX_CONNECTIONS: usize = 1024;
+/// How long a virtual TCP connection may take to be established.
+const CONNECT_TIMEOUT: smoltcp::time::Duration = smoltcp::time::Duration::from_secs(10);
+/// How long a UDP peer may stay silent, both ways, before it is forgotten.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait before accepting again after an error, e.g. when the
+/// process ran out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where the connections of a forward are made from.
+pub(crate) struct Origin {
+    pub(crate) reactor: Arc<Reactor>,
+    pub(crate) addr: IpAddress,
+    pub(crate) ports: Arc<AtomicU16>,
+}
+
+impl Origin {
+    fn local_endpoint(&self) -> (IpAddress, u16) {
+        (self.addr, next_port(&self.ports))
+    }
+}
+
+/// A host port forwarded into a `Net`, until dropped.
+///
+/// Dropping it closes the host socket and every connection it forwards.
+pub struct Forward {
+    local_addr: SocketAddr,
+    max_connections: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl Forward {
+    pub(crate) async fn tcp(
+        origin: Origin,
+        host_addr: SocketAddr,
+        virtual_addr: SocketAddr,
+    ) -> io::Result<Forward> {
+        let listener = TcpListener::bind(host_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let max_connections = Arc::new(AtomicUsize::new(MAX_CONNECTIONS));
+        let task = tokio::spawn(forward_tcp(
+            origin,
+            listener,
+            virtual_addr,
+            max_connections.clone(),
+        ));
+        Ok(Forward {
+            local_addr,
+            max_connections,
+            task,
+        })
+    }
+
+    pub(crate) async fn udp(
+        origin: Origin,
+        host_addr: SocketAddr,
+        virtual_addr: SocketAddr,
+    ) -> io::Result<Forward> {
+        let socket = HostUdpSocket::bind(host_addr).await?;
+        let local_addr = socket.local_addr()?;
+        let max_connections = Arc::new(AtomicUsize::new(MAX_CONNECTIONS));
+        let task = tokio::spawn(forward_udp(
+            origin,
+            socket,
+            virtual_addr,
+            max_connections.clone(),
+        ));
+        Ok(Forward {
+            local_addr,
+            max_connections,
+            task,
+        })
+    }
+
+    /// Returns the address of the host socket, with the port it was given if
+    /// bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Limits the connections forwarded at once, 1024 by default. Above it,
+    /// new TCP connections are closed as soon as accepted and the datagrams of
+    /// new UDP peers are dropped.
+    pub fn set_max_connections(&self, max: usize) {
+        self.max_connections.store(max, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn forward_tcp(
+    origin: Origin,
+    listener: TcpListener,
+    virtual_addr: SocketAddr,
+    max_connections: Arc<AtomicUsize>,
+) {
+    // Dropped with the task, which aborts every connection.
+    let mut connections = JoinSet::new();
+
+    loop {
+        select! {
+            r = listener.accept() => {
+                let Ok((mut host, _)) = r else {
+                    sleep(&**origin.reactor.clock(), ACCEPT_BACKOFF).await;
+                    continue;
+                };
+                if connections.len() >= max_connections.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let connect = TcpStream::connect(
+                    origin.reactor.clone(),
+                    origin.local_endpoint().into(),
+                    virtual_addr.into(),
+                    Some(CONNECT_TIMEOUT),
+                );
+                connections.spawn(async move {
+                    let Ok(mut stream) = connect.await else {
+                        return;
+                    };
+                    // Each side is shut down once the other one sent its FIN,
+                    // so half-closed connections keep flowing the other way.
+                    let _ = copy_bidirectional(&mut host, &mut stream).await;
+                });
+            }
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+async fn forward_udp(
+    origin: Origin,
+    host: HostUdpSocket,
+    virtual_addr: SocketAddr,
+    max_connections: Arc<AtomicUsize>,
+) {
+    let relay = UdpRelay {
+        socket: Arc::new(host),
+        target: virtual_addr,
+        timeout: UDP_TIMEOUT,
+        max_peers: max_connections,
+        until: Until::Dropped,
+        clock: origin.reactor.clock().clone(),
+    };
+    relay
+        .run(|| {
+            future::ready(UdpSocket::new(
+                origin.reactor.clone(),
+                origin.local_endpoint().into(),
+            ))
+        })
+        .await
+}
@@ -0,0 +1,248 @@
+//! Per-socket buffers that let `TcpStream` read and write without locking the
+//! `SocketSet`.
+//!
+//! Each stream owns a pair of ring buffers guarded by its own mutex. The
+//! reactor moves data between them and the smoltcp socket while it holds the
+//! `SocketSet`, but only for the sockets queued in the `SyncQueue`, either by
+//! the stream after it touched its buffers or by smoltcp through the socket's
+//! wakers.
+
+use parking_lot::Mutex;
+use smoltcp::{
+    iface::{SocketHandle, SocketSet},
+    socket::tcp,
+    storage::RingBuffer,
+};
+use std::{
+    mem,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Wake, Waker},
+};
+
+/// The sockets whose buffers need to be synced by the reactor.
+#[derive(Default)]
+pub(crate) struct SyncQueue {
+    queue: Mutex<Vec<Arc<TcpHandoff>>>,
+    /// Every handoff made, for the statistics of their buffers.
+    handoffs: Mutex<Vec<Weak<TcpHandoff>>>,
+}
+
+impl SyncQueue {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+
+    /// Returns the socket of each open stream, with the bytes written to its
+    /// buffers but not synced to the socket yet, and the bytes synced but not
+    /// read yet.
+    pub(crate) fn buffered(&self) -> Vec<(SocketHandle, (usize, usize))> {
+        let mut buffered = Vec::new();
+        self.handoffs.lock().retain(|handoff| {
+            let Some(handoff) = handoff.upgrade() else {
+                return false;
+            };
+            if handoff.closed.load(Ordering::Acquire) {
+                return false;
+            }
+            let buffers = handoff.buffers();
+            buffered.push((handoff.handle, (buffers.tx.len(), buffers.rx.len())));
+            true
+        });
+        buffered
+    }
+
+    /// Syncs every queued socket, returns `false` if none was.
+    pub(crate) fn sync(&self, sockets: &mut SocketSet<'static>) -> bool {
+        let queue = mem::take(&mut *self.queue.lock());
+        let synced = !queue.is_empty();
+        for handoff in queue {
+            handoff.queued.store(false, Ordering::Release);
+            // The socket is removed from the set once the stream is dropped,
+            // and its handle may already be reused.
+            if handoff.closed.load(Ordering::Acquire) {
+                continue;
+            }
+            handoff.sync(sockets.get_mut::<tcp::Socket>(handoff.handle));
+        }
+        synced
+    }
+}
+
+/// The state of a TCP socket as last seen by the reactor, and the buffers
+/// exchanged with it.
+pub(crate) struct TcpBuffers {
+    pub rx: RingBuffer<'static, u8>,
+    pub tx: RingBuffer<'static, u8>,
+    pub rx_waker: Option<Waker>,
+    pub tx_waker: Option<Waker>,
+    /// Whether the socket has received data that didn't fit in `rx`.
+    pub rx_pending: bool,
+    pub may_recv: bool,
+    pub may_send: bool,
+    pub send_queue: usize,
+    pub state: tcp::State,
+    pub shutdown: bool,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// The buffers of a TCP socket, shared between its `TcpStream` and the reactor.
+pub(crate) struct TcpHandoff {
+    handle: SocketHandle,
+    buffers: Mutex<TcpBuffers>,
+    queued: AtomicBool,
+    closed: AtomicBool,
+    sync_queue: Arc<SyncQueue>,
+    /// Queues this handoff when smoltcp wakes it.
+    waker: Waker,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl TcpHandoff {
+    /// Makes the buffers of `socket`, which is synced right away.
+    pub(crate) fn new(
+        handle: SocketHandle,
+        socket: &mut tcp::Socket<'static>,
+        rx_size: usize,
+        tx_size: usize,
+        sync_queue: Arc<SyncQueue>,
+    ) -> Arc<TcpHandoff> {
+        let handoff = Arc::new_cyclic(|weak| TcpHandoff {
+            handle,
+            buffers: Mutex::new(TcpBuffers {
+                rx: RingBuffer::new(vec![0; rx_size]),
+                tx: RingBuffer::new(vec![0; tx_size]),
+                rx_waker: None,
+                tx_waker: None,
+                rx_pending: false,
+                may_recv: false,
+                may_send: false,
+                send_queue: 0,
+                state: socket.state(),
+                shutdown: false,
+                bytes_sent: 0,
+                bytes_received: 0,
+            }),
+            queued: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            sync_queue,
+            waker: Waker::from(Arc::new(SyncWaker(weak.clone()))),
+            #[cfg(feature = "tracing")]
+            span: match (socket.local_endpoint(), socket.remote_endpoint()) {
+                (Some(local), Some(peer)) => {
+                    tracing::debug_span!("tcp", %handle, %local, %peer)
+                }
+                _ => tracing::debug_span!("tcp", %handle),
+            },
+        });
+        handoff.sync(socket);
+        handoff
+            .sync_queue
+            .handoffs
+            .lock()
+            .push(Arc::downgrade(&handoff));
+        handoff
+    }
+
+    pub(crate) fn buffers(&self) -> parking_lot::MutexGuard<'_, TcpBuffers> {
+        self.buffers.lock()
+    }
+
+    /// The span of the socket, with its handle and addresses.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Queues the socket to be synced, returns `false` if it already was.
+    pub(crate) fn schedule(self: &Arc<Self>) -> bool {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.sync_queue.queue.lock().push(self.clone());
+        true
+    }
+
+    /// Stops syncing the socket, which is about to be removed.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Moves data between the buffers and `socket`, and wakes the stream if
+    /// it can make progress.
+    fn sync(self: &Arc<Self>, socket: &mut tcp::Socket<'static>) {
+        let mut buffers = self.buffers.lock();
+        let buffers = &mut *buffers;
+
+        while !buffers.tx.is_empty() && socket.can_send() {
+            let sent = buffers
+                .tx
+                .dequeue_many_with(|data| (socket.send_slice(data).unwrap_or(0), ()))
+                .0;
+            if sent == 0 {
+                break;
+            }
+            buffers.bytes_sent += sent as u64;
+        }
+        if buffers.shutdown && buffers.tx.is_empty() && socket.may_send() {
+            socket.close();
+        }
+
+        while !buffers.rx.is_full() && socket.can_recv() {
+            let received = buffers
+                .rx
+                .enqueue_many_with(|free| (socket.recv_slice(free).unwrap_or(0), ()))
+                .0;
+            if received == 0 {
+                break;
+            }
+            buffers.bytes_received += received as u64;
+        }
+
+        let state = socket.state();
+        let changed = state != buffers.state;
+        #[cfg(feature = "tracing")]
+        if changed {
+            tracing::debug!(parent: &self.span, from = %buffers.state, to = %state, "state changed");
+        }
+        buffers.state = state;
+        buffers.rx_pending = socket.recv_queue() > 0;
+        buffers.may_recv = socket.may_recv();
+        buffers.may_send = socket.may_send();
+        buffers.send_queue = socket.send_queue();
+
+        if (changed || !buffers.rx.is_empty() || !buffers.may_recv)
+            && let Some(waker) = buffers.rx_waker.take()
+        {
+            waker.wake();
+        }
+        if (changed || !buffers.tx.is_full() || !buffers.may_send)
+            && let Some(waker) = buffers.tx_waker.take()
+        {
+            waker.wake();
+        }
+
+        // smoltcp wakes these when data arrives, space is freed in its
+        // transmit buffer, or the state changes.
+        socket.register_recv_waker(&self.waker);
+        socket.register_send_waker(&self.waker);
+    }
+}
+
+struct SyncWaker(Weak<TcpHandoff>);
+
+impl Wake for SyncWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(handoff) = self.0.upgrade() {
+            handoff.schedule();
+        }
+    }
+}
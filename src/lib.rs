@@ -1,17 +1,21 @@
 //! An asynchronous wrapper for smoltcp.
 
 use std::{
+    collections::HashMap,
     io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicU16, Ordering},
         Arc,
     },
 };
 
+pub use clock::{Clock, TokioClock, VirtualClock};
 use device::BufferDevice;
+pub use forward::Forward;
 use futures::Future;
 use reactor::Reactor;
+pub use bytes;
 pub use smoltcp;
 use smoltcp::{
     iface::{Config, Interface, Routes},
@@ -20,18 +24,38 @@ use smoltcp::{
 };
 pub use socket::{RawSocket, TcpListener, TcpStream, UdpSocket};
 pub use socket_allocator::BufferSize;
+pub use stats::{NetStats, SocketInfo, TcpInfo};
 use tokio::sync::Notify;
 
+#[macro_use]
+mod trace;
+
+pub mod bridge;
+mod clock;
+#[cfg(feature = "hyper")]
+pub mod connector;
 /// The async devices.
 pub mod device;
+mod dns;
+pub mod filter;
+mod forward;
+mod handoff;
+#[cfg(feature = "metrics")]
+mod metrics;
+pub mod nat;
 mod reactor;
+mod relay;
 mod socket;
 mod socket_allocator;
+#[cfg(feature = "socks5")]
+pub mod socks5;
+pub mod stack;
+mod stats;
 
 /// Can be used to create a forever timestamp in neighbor.
 // The 60_000 is the same as NeighborCache::ENTRY_LIFETIME.
 pub const FOREVER: Instant =
-    Instant::from_micros_const(i64::max_value() - Duration::from_millis(60_000).micros() as i64);
+    Instant::from_micros_const(i64::MAX - Duration::from_millis(60_000).micros() as i64);
 
 pub struct Neighbor {
     pub protocol_addr: IpAddress,
@@ -48,6 +72,19 @@ pub struct NetConfig {
     pub ip_addr: IpCidr,
     pub gateway: Vec<IpAddress>,
     pub buffer_size: BufferSize,
+    /// The source of time of the `Net`, a `TokioClock` by default.
+    pub clock: Arc<dyn Clock>,
+    /// The name of the `Net`, labelling its metrics and tracing span. It
+    /// defaults to `ip_addr`.
+    pub name: String,
+    /// Called on every packet between the device and the interface, none by
+    /// default.
+    pub filter: Option<Box<dyn filter::PacketFilter>>,
+    /// The DNS server `Net::lookup` asks, inside the network, none by default.
+    pub dns: Option<SocketAddr>,
+    /// How long `Net::tcp_connect` waits for an answer to its SYN before
+    /// failing with `TimedOut`, 75 seconds by default.
+    pub connect_timeout: Option<Duration>,
 }
 
 impl NetConfig {
@@ -57,6 +94,11 @@ impl NetConfig {
             ip_addr,
             gateway,
             buffer_size: Default::default(),
+            clock: Arc::new(TokioClock::new()),
+            name: ip_addr.to_string(),
+            filter: None,
+            dns: None,
+            connect_timeout: Some(Duration::from_secs(75)),
         }
     }
 }
@@ -68,8 +110,11 @@ impl NetConfig {
 pub struct Net {
     reactor: Arc<Reactor>,
     ip_addr: IpCidr,
-    from_port: AtomicU16,
+    from_port: Arc<AtomicU16>,
     stopper: Arc<Notify>,
+    name: String,
+    dns: Option<SocketAddr>,
+    connect_timeout: Option<Duration>,
 }
 
 impl Net {
@@ -85,7 +130,11 @@ impl Net {
         config: NetConfig,
     ) -> (Net, impl Future<Output = io::Result<()>> + Send) {
         let mut buffer_device = BufferDevice::new(device.capabilities().clone());
-        let mut iface = Interface::new(config.interface_config, &mut buffer_device, Instant::now());
+        let mut iface = Interface::new(
+            config.interface_config,
+            &mut buffer_device,
+            config.clock.now(),
+        );
         let ip_addr = config.ip_addr;
         iface.update_ip_addrs(|ip_addrs| {
             ip_addrs.push(ip_addr).unwrap();
@@ -109,44 +158,66 @@ impl Net {
             iface,
             buffer_device,
             config.buffer_size,
+            config.clock,
+            config.filter,
+            #[cfg(feature = "metrics")]
+            metrics::Metrics::new(&config.name),
             stopper.clone(),
         );
 
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(
+            fut,
+            tracing::debug_span!("net", name = %config.name),
+        );
+
         (
             Net {
                 reactor: Arc::new(reactor),
                 ip_addr: config.ip_addr,
-                from_port: AtomicU16::new(10001),
+                from_port: Arc::new(AtomicU16::new(10001)),
                 stopper,
+                name: config.name,
+                dns: config.dns,
+                connect_timeout: config.connect_timeout,
             },
             fut,
         )
     }
+    /// Returns the name of the `Net`, as set in its `NetConfig`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Returns the source of time of the `Net`, as set in its `NetConfig`.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.reactor.clock()
+    }
     fn get_port(&self) -> u16 {
-        self.from_port
-            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
-                Some(if x > 60000 { 10000 } else { x + 1 })
-            })
-            .unwrap()
+        next_port(&self.from_port)
     }
     /// Creates a new TcpListener, which will be bound to the specified address.
     pub async fn tcp_bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
         let addr = self.set_address(addr);
-        TcpListener::new(self.reactor.clone(), addr.into()).await
+        TcpListener::new(self.reactor.clone(), addr.into())
     }
     /// Opens a TCP connection to a remote host.
+    ///
+    /// It fails with `ConnectionRefused` if the host resets the connection, and
+    /// `TimedOut` after the `connect_timeout` of the `NetConfig`. Dropping the
+    /// future resets the connection.
     pub async fn tcp_connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
         TcpStream::connect(
             self.reactor.clone(),
             (self.ip_addr.address(), self.get_port()).into(),
             addr.into(),
+            self.connect_timeout,
         )
         .await
     }
     /// This function will create a new UDP socket and attempt to bind it to the `addr` provided.
     pub async fn udp_bind(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
         let addr = self.set_address(addr);
-        UdpSocket::new(self.reactor.clone(), addr.into()).await
+        UdpSocket::new(self.reactor.clone(), addr.into())
     }
     /// Creates a new raw socket.
     pub async fn raw_socket(
@@ -156,11 +227,25 @@ impl Net {
     ) -> io::Result<RawSocket> {
         RawSocket::new(self.reactor.clone(), ip_version, ip_protocol).await
     }
+    /// Resolves `host` with the DNS server of the `NetConfig`, unless it is an
+    /// IP address.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+        let Some(dns) = self.dns else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a host name needs a DNS server",
+            ));
+        };
+        dns::lookup(self, dns, host, dns::TIMEOUT).await
+    }
     fn set_address(&self, mut addr: SocketAddr) -> SocketAddr {
         if addr.ip().is_unspecified() {
             addr.set_ip(match self.ip_addr.address() {
-                IpAddress::Ipv4(ip) => Ipv4Addr::from(ip).into(),
-                IpAddress::Ipv6(ip) => Ipv6Addr::from(ip).into(),
+                IpAddress::Ipv4(ip) => ip.into(),
+                IpAddress::Ipv6(ip) => ip.into(),
                 #[allow(unreachable_patterns)]
                 _ => panic!("address must not be unspecified"),
             });
@@ -171,6 +256,33 @@ impl Net {
         addr
     }
 
+    /// Accepts TCP connections on `host_addr`, a real address of the host, and
+    /// forwards each one to `virtual_addr` through a new `TcpStream`.
+    pub async fn forward_tcp(
+        &self,
+        host_addr: SocketAddr,
+        virtual_addr: SocketAddr,
+    ) -> io::Result<Forward> {
+        Forward::tcp(self.origin(), host_addr, virtual_addr).await
+    }
+    /// Receives datagrams on `host_addr`, a real address of the host, and
+    /// forwards them to `virtual_addr` through a new `UdpSocket` per sender,
+    /// which the replies come back through.
+    pub async fn forward_udp(
+        &self,
+        host_addr: SocketAddr,
+        virtual_addr: SocketAddr,
+    ) -> io::Result<Forward> {
+        Forward::udp(self.origin(), host_addr, virtual_addr).await
+    }
+    fn origin(&self) -> forward::Origin {
+        forward::Origin {
+            reactor: self.reactor.clone(),
+            addr: self.ip_addr.address(),
+            ports: self.from_port.clone(),
+        }
+    }
+
     /// Enable or disable the AnyIP capability.
     pub fn set_any_ip(&self, any_ip: bool) {
         let iface = self.reactor.iface().clone();
@@ -199,6 +311,35 @@ impl Net {
         let routes = iface.routes_mut();
         f(routes)
     }
+
+    /// Returns the counters of the device and the reactor.
+    pub fn stats(&self) -> NetStats {
+        self.reactor.stats().snapshot()
+    }
+
+    /// Lists the sockets of the `Net`, including the ones listening. The TCP
+    /// queues include the bytes in the buffers of the `TcpStream`s, as in
+    /// `TcpStream::info`.
+    pub fn sockets(&self) -> Vec<SocketInfo> {
+        let allocator = self.reactor.socket_allocator();
+        // The reactor locks the sockets before the buffers.
+        let sockets = allocator.sockets().lock();
+        let buffered: HashMap<_, _> = allocator.sync_queue().buffered().into_iter().collect();
+        sockets
+            .iter()
+            .filter_map(|(handle, socket)| {
+                SocketInfo::new(socket, buffered.get(&handle).copied().unwrap_or_default())
+            })
+            .collect()
+    }
+}
+
+fn next_port(from_port: &AtomicU16) -> u16 {
+    from_port
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+            Some(if x > 60000 { 10000 } else { x + 1 })
+        })
+        .unwrap()
 }
 
 impl Drop for Net {
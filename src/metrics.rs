@@ -0,0 +1,253 @@
+//! Exports the statistics of a `Net` through the `metrics` facade, labelled
+//! with the name of the `Net`.
+//!
+//! The counters and the socket gauges are published after a poll, at most once
+//! per `EXPORT_INTERVAL`. The reactor wakes up for the last export of a burst,
+//! so an idle `Net` doesn't keep stale values. The TCP queues include the bytes
+//! waiting in the buffers of the `TcpStream`s, on top of the sockets' own.
+//! smoltcp doesn't expose how many segments a TCP socket retransmitted, so they
+//! aren't exported.
+
+use crate::{handoff::SyncQueue, stats::Counters};
+use ::metrics::{Counter, Gauge, Unit, counter, describe_counter, describe_gauge, gauge};
+use smoltcp::{
+    iface::SocketSet,
+    socket::{Socket, tcp},
+    time::{Duration, Instant},
+};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+const TCP_STATES: [tcp::State; 11] = [
+    tcp::State::Closed,
+    tcp::State::Listen,
+    tcp::State::SynSent,
+    tcp::State::SynReceived,
+    tcp::State::Established,
+    tcp::State::FinWait1,
+    tcp::State::FinWait2,
+    tcp::State::CloseWait,
+    tcp::State::Closing,
+    tcp::State::LastAck,
+    tcp::State::TimeWait,
+];
+
+struct Queues {
+    send: Gauge,
+    recv: Gauge,
+}
+
+impl Queues {
+    fn new(name: &str, protocol: &'static str) -> Queues {
+        Queues {
+            send: gauge!(
+                "tokio_smoltcp_socket_send_queue_bytes",
+                "net" => name.to_owned(),
+                "protocol" => protocol
+            ),
+            recv: gauge!(
+                "tokio_smoltcp_socket_recv_queue_bytes",
+                "net" => name.to_owned(),
+                "protocol" => protocol
+            ),
+        }
+    }
+    fn set(&self, (send, recv): (usize, usize)) {
+        self.send.set(send as f64);
+        self.recv.set(recv as f64);
+    }
+}
+
+pub(crate) struct Metrics {
+    rx_packets: Counter,
+    rx_bytes: Counter,
+    rx_errors: Counter,
+    rx_filtered: Counter,
+    tx_packets: Counter,
+    tx_bytes: Counter,
+    tx_filtered: Counter,
+    wakeups: Counter,
+    polls: Counter,
+    tcp_sockets: [Gauge; TCP_STATES.len()],
+    udp_sockets: Gauge,
+    raw_sockets: Gauge,
+    tcp_queues: Queues,
+    udp_queues: Queues,
+    raw_queues: Queues,
+    /// The earliest time of the next export.
+    next_export: Instant,
+    /// Whether something was polled since the last export.
+    dirty: bool,
+}
+
+impl Metrics {
+    pub(crate) fn new(name: &str) -> Metrics {
+        describe();
+        let counter = |key: &'static str| counter!(key, "net" => name.to_owned());
+        Metrics {
+            rx_packets: counter("tokio_smoltcp_rx_packets_total"),
+            rx_bytes: counter("tokio_smoltcp_rx_bytes_total"),
+            rx_errors: counter("tokio_smoltcp_rx_errors_total"),
+            rx_filtered: counter("tokio_smoltcp_rx_filtered_total"),
+            tx_packets: counter("tokio_smoltcp_tx_packets_total"),
+            tx_bytes: counter("tokio_smoltcp_tx_bytes_total"),
+            tx_filtered: counter("tokio_smoltcp_tx_filtered_total"),
+            wakeups: counter("tokio_smoltcp_reactor_wakeups_total"),
+            polls: counter("tokio_smoltcp_reactor_polls_total"),
+            tcp_sockets: TCP_STATES.map(|state| {
+                gauge!(
+                    "tokio_smoltcp_tcp_sockets",
+                    "net" => name.to_owned(),
+                    "state" => state.to_string()
+                )
+            }),
+            udp_sockets: gauge!("tokio_smoltcp_udp_sockets", "net" => name.to_owned()),
+            raw_sockets: gauge!("tokio_smoltcp_raw_sockets", "net" => name.to_owned()),
+            tcp_queues: Queues::new(name, "tcp"),
+            udp_queues: Queues::new(name, "udp"),
+            raw_queues: Queues::new(name, "raw"),
+            next_export: Instant::ZERO,
+            dirty: false,
+        }
+    }
+
+    /// Returns when the reactor should wake up to export what was polled.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.dirty.then_some(self.next_export)
+    }
+
+    /// Exports the metrics after a poll, unless it was done less than
+    /// `EXPORT_INTERVAL` ago.
+    pub(crate) fn polled(
+        &mut self,
+        now: Instant,
+        stats: &Counters,
+        sockets: &SocketSet<'_>,
+        sync_queue: &SyncQueue,
+    ) {
+        if now < self.next_export {
+            self.dirty = true;
+            return;
+        }
+        self.dirty = false;
+        self.next_export = now + EXPORT_INTERVAL;
+
+        let stats = stats.snapshot();
+        self.rx_packets.absolute(stats.rx_packets);
+        self.rx_bytes.absolute(stats.rx_bytes);
+        self.rx_errors.absolute(stats.rx_errors);
+        self.rx_filtered.absolute(stats.rx_filtered);
+        self.tx_packets.absolute(stats.tx_packets);
+        self.tx_bytes.absolute(stats.tx_bytes);
+        self.tx_filtered.absolute(stats.tx_filtered);
+        self.wakeups.absolute(stats.wakeups);
+        self.polls.absolute(stats.polls);
+
+        let mut tcp_sockets = [0usize; TCP_STATES.len()];
+        let (mut udp_sockets, mut raw_sockets) = (0usize, 0usize);
+        let (mut udp_queues, mut raw_queues) = ((0, 0), (0, 0));
+        // The streams' buffers hold what the sockets haven't taken yet.
+        let mut tcp_queues = sync_queue
+            .buffered()
+            .into_iter()
+            .fold((0, 0), |(send, recv), (_, buffered)| {
+                (send + buffered.0, recv + buffered.1)
+            });
+        for (_, socket) in sockets.iter() {
+            match socket {
+                Socket::Tcp(tcp) => {
+                    if let Some(i) = TCP_STATES.iter().position(|s| *s == tcp.state()) {
+                        tcp_sockets[i] += 1;
+                    }
+                    tcp_queues.0 += tcp.send_queue();
+                    tcp_queues.1 += tcp.recv_queue();
+                }
+                Socket::Udp(udp) => {
+                    udp_sockets += 1;
+                    udp_queues.0 += udp.send_queue();
+                    udp_queues.1 += udp.recv_queue();
+                }
+                Socket::Raw(raw) => {
+                    raw_sockets += 1;
+                    raw_queues.0 += raw.send_queue();
+                    raw_queues.1 += raw.recv_queue();
+                }
+                #[allow(unreachable_patterns)]
+                _ => {}
+            }
+        }
+        for (gauge, count) in self.tcp_sockets.iter().zip(tcp_sockets) {
+            gauge.set(count as f64);
+        }
+        self.udp_sockets.set(udp_sockets as f64);
+        self.raw_sockets.set(raw_sockets as f64);
+        self.tcp_queues.set(tcp_queues);
+        self.udp_queues.set(udp_queues);
+        self.raw_queues.set(raw_queues);
+    }
+}
+
+fn describe() {
+    describe_counter!(
+        "tokio_smoltcp_rx_packets_total",
+        Unit::Count,
+        "Packets received from the device"
+    );
+    describe_counter!(
+        "tokio_smoltcp_rx_bytes_total",
+        Unit::Bytes,
+        "Bytes received from the device"
+    );
+    describe_counter!(
+        "tokio_smoltcp_rx_errors_total",
+        Unit::Count,
+        "Errors returned by the device's stream"
+    );
+    describe_counter!(
+        "tokio_smoltcp_rx_filtered_total",
+        Unit::Count,
+        "Received packets dropped by the packet filter"
+    );
+    describe_counter!(
+        "tokio_smoltcp_tx_packets_total",
+        Unit::Count,
+        "Packets sent to the device"
+    );
+    describe_counter!(
+        "tokio_smoltcp_tx_bytes_total",
+        Unit::Bytes,
+        "Bytes sent to the device"
+    );
+    describe_counter!(
+        "tokio_smoltcp_tx_filtered_total",
+        Unit::Count,
+        "Packets to send dropped by the packet filter"
+    );
+    describe_counter!(
+        "tokio_smoltcp_reactor_wakeups_total",
+        Unit::Count,
+        "Times the reactor woke up"
+    );
+    describe_counter!(
+        "tokio_smoltcp_reactor_polls_total",
+        Unit::Count,
+        "Times the reactor polled the interface"
+    );
+    describe_gauge!(
+        "tokio_smoltcp_tcp_sockets",
+        Unit::Count,
+        "TCP sockets by state"
+    );
+    describe_gauge!("tokio_smoltcp_udp_sockets", Unit::Count, "UDP sockets");
+    describe_gauge!("tokio_smoltcp_raw_sockets", Unit::Count, "Raw sockets");
+    describe_gauge!(
+        "tokio_smoltcp_socket_send_queue_bytes",
+        Unit::Bytes,
+        "Bytes queued in the sockets, and the TCP streams, to be sent"
+    );
+    describe_gauge!(
+        "tokio_smoltcp_socket_recv_queue_bytes",
+        Unit::Bytes,
+        "Bytes received by the sockets, and the TCP streams, and not read yet"
+    );
+}
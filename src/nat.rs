@@ -0,0 +1,1046 @@
+//! A source NAT from an inner device to an outer `Net`.
+//!
+//! The IPv4 TCP, UDP and ICMP echo packets of the inner device leave through
+//! the outer `Net` from its address, each connection on its own outer port,
+//! and the replies are translated back. A connection is forgotten after a
+//! timeout without traffic. Static forwards let outer peers reach a port of the
+//! inner network.
+//!
+//! The outer side is always a `Net`, packets are translated, not proxied
+//! through sockets of the host. To reach the host's network from its sockets,
+//! use a `bridge::Bridge` instead.
+//!
+//! ```no_run
+//! # use tokio_smoltcp::device::AsyncDevice;
+//! # async fn nat(guest: impl AsyncDevice + 'static, uplink: impl AsyncDevice + 'static) -> std::io::Result<()> {
+//! use tokio_smoltcp::nat::{Nat, NatConfig};
+//! use tokio_smoltcp::smoltcp::{iface::Config, wire::*};
+//! use tokio_smoltcp::{Net, NetConfig};
+//!
+//! let nat = Nat::new(NatConfig::new(Ipv4Address::new(192, 168, 1, 10)));
+//! let mut config = NetConfig::new(
+//!     Config::new(HardwareAddress::Ip),
+//!     "192.168.1.10/24".parse().unwrap(),
+//!     vec![IpAddress::v4(192, 168, 1, 1)],
+//! );
+//! config.filter = Some(Box::new(nat.filter()));
+//! let outer = Net::new(uplink, config);
+//! nat.handle()
+//!     .add_forward(IpProtocol::Tcp, 8080, "10.0.0.2:80".parse().unwrap())?;
+//! tokio::spawn(nat.run(guest, &outer));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    Clock, Net, TokioClock,
+    device::{AsyncDevice, Packet},
+    filter::{PacketFilter, Verdict},
+    reactor::Reactor,
+    socket::RawSocket,
+};
+use futures::{SinkExt, StreamExt, channel::mpsc};
+use parking_lot::Mutex;
+use smoltcp::{
+    phy::Medium,
+    time::{Duration, Instant},
+    wire::{
+        ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+        EthernetRepr, Icmpv4Message, Icmpv4Packet, IpAddress, IpProtocol, IpVersion, Ipv4Address,
+        Ipv4Packet, TcpPacket, UdpPacket,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io,
+    net::SocketAddrV4,
+    ops::RangeInclusive,
+    sync::Arc,
+};
+use tokio::select;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// A config for a `Nat`.
+#[non_exhaustive]
+pub struct NatConfig {
+    /// The address of the outer `Net`, translated packets are sent from it.
+    pub outer_addr: Ipv4Address,
+    /// The outer ports, and ICMP echo identifiers, given to the connections.
+    /// It defaults to 61000 to 65535, which the outer `Net` doesn't pick for
+    /// its own sockets.
+    pub ports: RangeInclusive<u16>,
+    /// How long an established TCP connection is kept without traffic, 2
+    /// hours and 4 minutes by default.
+    pub tcp_established_timeout: Duration,
+    /// How long a TCP connection is kept without traffic while it opens or
+    /// after it closed, 4 minutes by default.
+    pub tcp_transitory_timeout: Duration,
+    /// How long a UDP flow is kept without traffic, 2 minutes by default.
+    pub udp_timeout: Duration,
+    /// How long an ICMP echo is kept without traffic, 60 seconds by default.
+    pub icmp_timeout: Duration,
+    /// The hardware address of the NAT on an Ethernet inner device. It answers
+    /// the ARP requests of the inner network.
+    pub ethernet_addr: EthernetAddress,
+    /// The number of packets queued from the outer `Net` to the inner device,
+    /// more are dropped. Default to 256.
+    pub queue_size: usize,
+    /// The source of time of the timeouts, a `TokioClock` by default.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl NatConfig {
+    pub fn new(outer_addr: Ipv4Address) -> Self {
+        Self {
+            outer_addr,
+            ports: 61000..=65535,
+            tcp_established_timeout: Duration::from_secs(2 * 60 * 60 + 4 * 60),
+            tcp_transitory_timeout: Duration::from_secs(4 * 60),
+            udp_timeout: Duration::from_secs(2 * 60),
+            icmp_timeout: Duration::from_secs(60),
+            ethernet_addr: EthernetAddress([0x02, 0, 0, 0, 0, 0x01]),
+            queue_size: 256,
+            clock: Arc::new(TokioClock::new()),
+        }
+    }
+}
+
+/// A connection translated by a `Nat`, as listed by `NatHandle::connections`.
+///
+/// The ports of an ICMP echo are its identifier on each side, and 0 for the
+/// remote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct NatConnection {
+    pub protocol: IpProtocol,
+    /// The address of the inner host.
+    pub inner: SocketAddrV4,
+    /// The port the connection uses on the outer address.
+    pub outer_port: u16,
+    /// The address of the outer peer.
+    pub remote: SocketAddrV4,
+    /// Whether it was opened by the outer peer through a forward.
+    pub forwarded: bool,
+}
+
+/// A source NAT from an inner device to an outer `Net`.
+///
+/// The filter returned by `filter` must be set in the `NetConfig` of the
+/// outer `Net`, it takes the replies before the outer interface sees them.
+/// `run` then sends the translated packets through raw sockets of the outer
+/// `Net`. Those raw sockets match every TCP, UDP and ICMP packet of the outer
+/// `Net`, which they drop, so it no longer answers UDP packets to a closed
+/// port with an ICMP error.
+///
+/// Only IPv4 is translated, other packets from the inner device are dropped,
+/// as well as fragmented packets and ICMP messages other than echo requests.
+pub struct Nat {
+    state: Arc<Mutex<State>>,
+    sender: mpsc::Sender<Packet>,
+    receiver: mpsc::Receiver<Packet>,
+}
+
+/// The `PacketFilter` of the outer `Net` of a `Nat`.
+pub struct NatFilter {
+    state: Arc<Mutex<State>>,
+    sender: mpsc::Sender<Packet>,
+}
+
+/// A handle to control a `Nat` after it is running.
+#[derive(Clone)]
+pub struct NatHandle {
+    state: Arc<Mutex<State>>,
+}
+
+impl Nat {
+    /// Makes a new `Nat` without any connection nor forward.
+    pub fn new(config: NatConfig) -> Nat {
+        let (sender, receiver) = mpsc::channel(config.queue_size);
+        let now = config.clock.now();
+        Nat {
+            state: Arc::new(Mutex::new(State {
+                config,
+                entries: HashMap::new(),
+                by_outer: HashMap::new(),
+                ports: HashSet::new(),
+                forwards: HashMap::new(),
+                next_port: 0,
+                next_sweep: now,
+            })),
+            sender,
+            receiver,
+        }
+    }
+    /// Returns the filter to set in the `NetConfig` of the outer `Net`.
+    pub fn filter(&self) -> NatFilter {
+        NatFilter {
+            state: self.state.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+    /// Returns a handle to control the forwards and list the connections.
+    pub fn handle(&self) -> NatHandle {
+        NatHandle {
+            state: self.state.clone(),
+        }
+    }
+    /// Translates the packets of `inner` to `outer` and back, until the stream
+    /// of `inner` ends. The medium of `inner` must be IP or Ethernet.
+    pub fn run(
+        self,
+        inner: impl AsyncDevice + 'static,
+        outer: &Net,
+    ) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        run(self, inner, outer.reactor.clone())
+    }
+}
+
+async fn run(nat: Nat, mut inner: impl AsyncDevice, reactor: Arc<Reactor>) -> io::Result<()> {
+    let Nat {
+        state,
+        sender,
+        mut receiver,
+    } = nat;
+    // Only the filters send, so the receiver ends with them.
+    drop(sender);
+
+    let medium = inner.capabilities().medium;
+    if !matches!(medium, Medium::Ip | Medium::Ethernet) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the inner device must be IP or Ethernet",
+        ));
+    }
+    let max_len = reactor.socket_allocator().buffer_size().raw_tx_size;
+    // They are only sent through, the replies are taken by the filter.
+    let raw = |protocol| RawSocket::send_only(reactor.clone(), IpVersion::Ipv4, protocol);
+    let tcp = raw(IpProtocol::Tcp);
+    let udp = raw(IpProtocol::Udp);
+    let icmp = raw(IpProtocol::Icmp);
+    let ethernet_addr = state.lock().config.ethernet_addr;
+    // The hardware addresses of the inner hosts, on an Ethernet device.
+    let mut neighbors = HashMap::new();
+
+    loop {
+        select! {
+            packet = inner.next() => {
+                let mut packet = match packet {
+                    Some(Ok(packet)) => packet,
+                    Some(Err(_)) => continue,
+                    None => break,
+                };
+                if medium == Medium::Ethernet {
+                    let Ok(frame) = EthernetFrame::new_checked(&packet[..]) else {
+                        continue;
+                    };
+                    match frame.ethertype() {
+                        EthernetProtocol::Arp => {
+                            if let Some(reply) = arp_reply(ethernet_addr, frame.payload()) {
+                                inner.send(reply).await?;
+                            }
+                            continue;
+                        }
+                        EthernetProtocol::Ipv4 => {
+                            let src = frame.src_addr();
+                            let mut ip = packet.split_off(ETHERNET_HEADER_LEN);
+                            if let Ok(ip) = Ipv4Packet::new_checked(&mut ip[..]) {
+                                neighbors.insert(ip.src_addr(), src);
+                            }
+                            packet = ip;
+                        }
+                        _ => continue,
+                    }
+                }
+                if packet.len() > max_len || !state.lock().outbound(&mut packet) {
+                    continue;
+                }
+                let socket = match Ipv4Packet::new_unchecked(&packet[..]).next_header() {
+                    IpProtocol::Tcp => &tcp,
+                    IpProtocol::Udp => &udp,
+                    _ => &icmp,
+                };
+                socket.send(&packet).await?;
+            }
+            Some(packet) = receiver.next() => {
+                let packet = match medium {
+                    Medium::Ethernet => {
+                        let dst = Ipv4Packet::new_unchecked(&packet[..]).dst_addr();
+                        let dst = neighbors
+                            .get(&dst)
+                            .copied()
+                            .unwrap_or(EthernetAddress::BROADCAST);
+                        ethernet_frame(ethernet_addr, dst, EthernetProtocol::Ipv4, &packet)
+                    }
+                    _ => packet,
+                };
+                inner.send(packet).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl NatHandle {
+    /// Forwards the `protocol` packets sent to `outer_port` of the outer
+    /// address to `inner`. `protocol` must be TCP or UDP.
+    ///
+    /// The forward takes precedence over the sockets of the outer `Net` bound
+    /// to the same port.
+    pub fn add_forward(
+        &self,
+        protocol: IpProtocol,
+        outer_port: u16,
+        inner: SocketAddrV4,
+    ) -> io::Result<()> {
+        if !matches!(protocol, IpProtocol::Tcp | IpProtocol::Udp) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only TCP and UDP can be forwarded",
+            ));
+        }
+        let mut state = self.state.lock();
+        if state.ports.contains(&(protocol, outer_port))
+            || state.forwards.contains_key(&(protocol, outer_port))
+        {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        state.forwards.insert((protocol, outer_port), inner);
+        Ok(())
+    }
+    /// Removes the forward of `outer_port`, returning whether there was one.
+    /// The connections it opened go on until they time out.
+    pub fn remove_forward(&self, protocol: IpProtocol, outer_port: u16) -> bool {
+        self.state
+            .lock()
+            .forwards
+            .remove(&(protocol, outer_port))
+            .is_some()
+    }
+    /// Lists the connections currently translated.
+    pub fn connections(&self) -> Vec<NatConnection> {
+        let state = self.state.lock();
+        state
+            .entries
+            .iter()
+            .map(|(flow, entry)| NatConnection {
+                protocol: flow.protocol,
+                inner: flow.inner,
+                outer_port: entry.outer_port,
+                remote: flow.remote,
+                forwarded: entry.forwarded,
+            })
+            .collect()
+    }
+}
+
+impl PacketFilter for NatFilter {
+    fn ingress(&mut self, medium: Medium, packet: &mut Packet) -> Verdict {
+        let offset = match medium {
+            Medium::Ethernet
+                if packet.len() >= ETHERNET_HEADER_LEN
+                    && packet[12..ETHERNET_HEADER_LEN] == [0x08, 0x00] =>
+            {
+                ETHERNET_HEADER_LEN
+            }
+            Medium::Ip => 0,
+            _ => return Verdict::Accept,
+        };
+        match self.state.lock().inbound(&mut packet[offset..]) {
+            Inbound::Pass => return Verdict::Accept,
+            Inbound::Drop => return Verdict::Drop,
+            Inbound::Deliver => {}
+        }
+        let mut packet = packet.split_off(offset);
+        let total_len = Ipv4Packet::new_unchecked(&packet[..]).total_len() as usize;
+        packet.truncate(total_len);
+        // The inner device is too slow, drop like a full queue would.
+        let _ = self.sender.try_send(packet);
+        Verdict::Drop
+    }
+}
+
+/// What to do with a packet received by the outer `Net`.
+enum Inbound {
+    /// It isn't a reply to a connection, let the outer `Net` have it.
+    Pass,
+    /// It belongs to the NAT, but can't be delivered.
+    Drop,
+    /// It was translated, send it to the inner device.
+    Deliver,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Flow {
+    protocol: IpProtocol,
+    inner: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+struct Entry {
+    outer_port: u16,
+    forwarded: bool,
+    expires: Instant,
+    seen_inner: bool,
+    seen_remote: bool,
+    /// Whether the TCP connection was reset or closed by both sides.
+    closed: bool,
+    fin_inner: bool,
+    fin_remote: bool,
+}
+
+impl Entry {
+    fn touch(&mut self, config: &NatConfig, protocol: IpProtocol, flags: u8, from_inner: bool) {
+        let now = config.clock.now();
+        let timeout = match protocol {
+            IpProtocol::Tcp => {
+                let (fin, rst) = (flags & 0x01 != 0, flags & 0x04 != 0);
+                if from_inner {
+                    self.seen_inner = true;
+                    self.fin_inner |= fin;
+                } else {
+                    self.seen_remote = true;
+                    self.fin_remote |= fin;
+                }
+                self.closed |= rst || (self.fin_inner && self.fin_remote);
+                if self.closed || !(self.seen_inner && self.seen_remote) {
+                    config.tcp_transitory_timeout
+                } else {
+                    config.tcp_established_timeout
+                }
+            }
+            IpProtocol::Udp => config.udp_timeout,
+            _ => config.icmp_timeout,
+        };
+        self.expires = now + timeout;
+    }
+}
+
+struct State {
+    config: NatConfig,
+    entries: HashMap<Flow, Entry>,
+    /// The flows by protocol, outer port and remote address.
+    by_outer: HashMap<(IpProtocol, u16, SocketAddrV4), Flow>,
+    /// The outer ports given to the flows opened from the inner side.
+    ports: HashSet<(IpProtocol, u16)>,
+    forwards: HashMap<(IpProtocol, u16), SocketAddrV4>,
+    next_port: u32,
+    next_sweep: Instant,
+}
+
+impl State {
+    /// Translates a packet of the inner device, returns `false` if it must be
+    /// dropped.
+    fn outbound(&mut self, packet: &mut [u8]) -> bool {
+        self.sweep();
+        let Ok(mut ip) = Ipv4Packet::new_checked(packet) else {
+            return false;
+        };
+        if ip.version() != 4 || ip.more_frags() || ip.frag_offset() != 0 || ip.hop_limit() <= 1 {
+            return false;
+        }
+        let protocol = ip.next_header();
+        let payload: &[u8] = ip.payload_mut();
+        let Some((src_port, dst_port)) = ports(protocol, payload, true) else {
+            return false;
+        };
+        if !complete(protocol, payload) {
+            return false;
+        }
+        let flags = tcp_flags(protocol, payload);
+        let flow = Flow {
+            protocol,
+            inner: SocketAddrV4::new(ip.src_addr(), src_port),
+            remote: SocketAddrV4::new(ip.dst_addr(), dst_port),
+        };
+        if !self.entries.contains_key(&flow) {
+            // Only a SYN opens a TCP connection, other segments belong to a
+            // forgotten one.
+            if protocol == IpProtocol::Tcp && flags & 0x12 != 0x02 {
+                return false;
+            }
+            let Some(port) = self.allocate(protocol) else {
+                return false;
+            };
+            self.insert(flow, port, false);
+        }
+        let entry = self.entries.get_mut(&flow).unwrap();
+        entry.touch(&self.config, protocol, flags, true);
+        let outer_port = entry.outer_port;
+
+        ip.set_src_addr(self.config.outer_addr);
+        ip.set_hop_limit(ip.hop_limit() - 1);
+        set_ports(protocol, ip.payload_mut(), outer_port, dst_port, true);
+        fill_checksums(&mut ip);
+        true
+    }
+
+    /// Translates a packet received by the outer `Net`.
+    fn inbound(&mut self, packet: &mut [u8]) -> Inbound {
+        let Ok(mut ip) = Ipv4Packet::new_checked(packet) else {
+            return Inbound::Pass;
+        };
+        if ip.version() != 4
+            || ip.dst_addr() != self.config.outer_addr
+            || ip.more_frags()
+            || ip.frag_offset() != 0
+        {
+            return Inbound::Pass;
+        }
+        self.sweep();
+        let protocol = ip.next_header();
+        if protocol == IpProtocol::Icmp {
+            let Ok(icmp) = Icmpv4Packet::new_checked(&*ip.payload_mut()) else {
+                return Inbound::Pass;
+            };
+            if matches!(
+                icmp.msg_type(),
+                Icmpv4Message::DstUnreachable | Icmpv4Message::TimeExceeded
+            ) {
+                return self.inbound_error(ip);
+            }
+        }
+        let payload: &[u8] = ip.payload_mut();
+        let Some((src_port, dst_port)) = ports(protocol, payload, false) else {
+            return Inbound::Pass;
+        };
+        if !complete(protocol, payload) {
+            return Inbound::Pass;
+        }
+        let flags = tcp_flags(protocol, payload);
+        let remote = SocketAddrV4::new(ip.src_addr(), src_port);
+        let flow = match self.by_outer.get(&(protocol, dst_port, remote)) {
+            Some(flow) => *flow,
+            None => {
+                let Some(inner) = self.forwards.get(&(protocol, dst_port)) else {
+                    return Inbound::Pass;
+                };
+                // Let the outer `Net` reset the segments of unknown connections.
+                if protocol == IpProtocol::Tcp && flags & 0x12 != 0x02 {
+                    return Inbound::Pass;
+                }
+                let flow = Flow {
+                    protocol,
+                    inner: *inner,
+                    remote,
+                };
+                if self.entries.contains_key(&flow) {
+                    return Inbound::Drop;
+                }
+                self.insert(flow, dst_port, true);
+                flow
+            }
+        };
+        if ip.hop_limit() <= 1 {
+            return Inbound::Drop;
+        }
+        let entry = self.entries.get_mut(&flow).unwrap();
+        entry.touch(&self.config, protocol, flags, false);
+
+        ip.set_dst_addr(*flow.inner.ip());
+        ip.set_hop_limit(ip.hop_limit() - 1);
+        set_ports(
+            protocol,
+            ip.payload_mut(),
+            src_port,
+            flow.inner.port(),
+            false,
+        );
+        fill_checksums(&mut ip);
+        Inbound::Deliver
+    }
+
+    /// Translates an ICMP error about a packet sent by a connection.
+    fn inbound_error(&mut self, mut ip: Ipv4Packet<&mut [u8]>) -> Inbound {
+        let mut icmp = Icmpv4Packet::new_unchecked(ip.payload_mut());
+        // The IP header and the first 8 bytes of the packet which caused the
+        // error.
+        let original = icmp.data_mut();
+        let Some(header_len) = original.first().map(|b| ((b & 0x0f) as usize) * 4) else {
+            return Inbound::Pass;
+        };
+        if header_len < 20 || original.len() < header_len + 8 {
+            return Inbound::Pass;
+        }
+        let protocol = IpProtocol::from(original[9]);
+        let remote_addr = Ipv4Address::from_octets(original[16..20].try_into().unwrap());
+        let Some((src_port, dst_port)) = ports(protocol, &original[header_len..], true) else {
+            return Inbound::Pass;
+        };
+        let remote = SocketAddrV4::new(remote_addr, dst_port);
+        let Some(flow) = self.by_outer.get(&(protocol, src_port, remote)).copied() else {
+            return Inbound::Pass;
+        };
+
+        original[12..16].copy_from_slice(&flow.inner.ip().octets());
+        Ipv4Packet::new_unchecked(&mut original[..header_len]).fill_checksum();
+        let l4 = &mut original[header_len..];
+        match protocol {
+            IpProtocol::Icmp => l4[4..6].copy_from_slice(&flow.inner.port().to_be_bytes()),
+            _ => l4[0..2].copy_from_slice(&flow.inner.port().to_be_bytes()),
+        }
+        icmp.fill_checksum();
+        if ip.hop_limit() <= 1 {
+            return Inbound::Drop;
+        }
+        ip.set_dst_addr(*flow.inner.ip());
+        ip.set_hop_limit(ip.hop_limit() - 1);
+        ip.fill_checksum();
+        Inbound::Deliver
+    }
+
+    fn insert(&mut self, flow: Flow, outer_port: u16, forwarded: bool) {
+        if !forwarded {
+            self.ports.insert((flow.protocol, outer_port));
+        }
+        self.by_outer
+            .insert((flow.protocol, outer_port, flow.remote), flow);
+        self.entries.insert(
+            flow,
+            Entry {
+                outer_port,
+                forwarded,
+                expires: self.config.clock.now(),
+                seen_inner: false,
+                seen_remote: false,
+                closed: false,
+                fin_inner: false,
+                fin_remote: false,
+            },
+        );
+    }
+
+    /// Returns a free outer port for a new `protocol` connection.
+    fn allocate(&mut self, protocol: IpProtocol) -> Option<u16> {
+        let (start, end) = (*self.config.ports.start(), *self.config.ports.end());
+        if start > end {
+            return None;
+        }
+        let len = (end - start) as u32 + 1;
+        for i in 0..len {
+            let offset = (self.next_port + i) % len;
+            let port = start + offset as u16;
+            if !self.ports.contains(&(protocol, port))
+                && !self.forwards.contains_key(&(protocol, port))
+            {
+                self.next_port = (offset + 1) % len;
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    /// Forgets the timed out connections, at most once per second.
+    fn sweep(&mut self) {
+        let now = self.config.clock.now();
+        if now < self.next_sweep {
+            return;
+        }
+        self.next_sweep = now + Duration::from_secs(1);
+        let (by_outer, ports) = (&mut self.by_outer, &mut self.ports);
+        self.entries.retain(|flow, entry| {
+            if entry.expires > now {
+                return true;
+            }
+            by_outer.remove(&(flow.protocol, entry.outer_port, flow.remote));
+            if !entry.forwarded {
+                ports.remove(&(flow.protocol, entry.outer_port));
+            }
+            false
+        });
+    }
+}
+
+/// Returns the source and destination ports of a packet, the identifier of an
+/// ICMP echo request from the inner side or reply from the outer side.
+fn ports(protocol: IpProtocol, payload: &[u8], from_inner: bool) -> Option<(u16, u16)> {
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    match protocol {
+        IpProtocol::Tcp | IpProtocol::Udp if payload.len() >= 8 => Some((port(0), port(2))),
+        IpProtocol::Icmp if payload.len() >= 8 => {
+            match (Icmpv4Message::from(payload[0]), from_inner) {
+                (Icmpv4Message::EchoRequest, true) => Some((port(4), 0)),
+                (Icmpv4Message::EchoReply, false) => Some((0, port(4))),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether the TCP or UDP header of a packet is complete, so its
+/// checksum can be filled. `ports` only needs the first 8 bytes, which is all
+/// an ICMP error quotes.
+fn complete(protocol: IpProtocol, payload: &[u8]) -> bool {
+    match protocol {
+        IpProtocol::Tcp => TcpPacket::new_checked(payload).is_ok(),
+        IpProtocol::Udp => UdpPacket::new_checked(payload).is_ok(),
+        _ => true,
+    }
+}
+
+fn set_ports(protocol: IpProtocol, payload: &mut [u8], src: u16, dst: u16, from_inner: bool) {
+    match protocol {
+        IpProtocol::Icmp => {
+            let ident = if from_inner { src } else { dst };
+            payload[4..6].copy_from_slice(&ident.to_be_bytes());
+        }
+        _ => {
+            payload[0..2].copy_from_slice(&src.to_be_bytes());
+            payload[2..4].copy_from_slice(&dst.to_be_bytes());
+        }
+    }
+}
+
+fn tcp_flags(protocol: IpProtocol, payload: &[u8]) -> u8 {
+    match protocol {
+        IpProtocol::Tcp if payload.len() >= 20 => payload[13],
+        _ => 0,
+    }
+}
+
+fn fill_checksums(ip: &mut Ipv4Packet<&mut [u8]>) {
+    let (src, dst) = (
+        IpAddress::Ipv4(ip.src_addr()),
+        IpAddress::Ipv4(ip.dst_addr()),
+    );
+    match ip.next_header() {
+        IpProtocol::Tcp => TcpPacket::new_unchecked(ip.payload_mut()).fill_checksum(&src, &dst),
+        IpProtocol::Udp => {
+            let mut udp = UdpPacket::new_unchecked(ip.payload_mut());
+            // A zero checksum means there is none.
+            if udp.checksum() != 0 {
+                udp.fill_checksum(&src, &dst);
+            }
+        }
+        _ => Icmpv4Packet::new_unchecked(ip.payload_mut()).fill_checksum(),
+    }
+    ip.fill_checksum();
+}
+
+/// Answers an ARP request of the inner network with the address of the NAT,
+/// except the ones probing for the sender's own address.
+fn arp_reply(ethernet_addr: EthernetAddress, payload: &[u8]) -> Option<Packet> {
+    let packet = ArpPacket::new_checked(payload).ok()?;
+    let ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr,
+        source_protocol_addr,
+        target_protocol_addr,
+        ..
+    } = ArpRepr::parse(&packet).ok()?
+    else {
+        return None;
+    };
+    if source_protocol_addr == target_protocol_addr || source_protocol_addr.is_unspecified() {
+        return None;
+    }
+    let reply = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: ethernet_addr,
+        source_protocol_addr: target_protocol_addr,
+        target_hardware_addr: source_hardware_addr,
+        target_protocol_addr: source_protocol_addr,
+    };
+    let mut arp = vec![0; reply.buffer_len()];
+    reply.emit(&mut ArpPacket::new_unchecked(&mut arp[..]));
+    Some(ethernet_frame(
+        ethernet_addr,
+        source_hardware_addr,
+        EthernetProtocol::Arp,
+        &arp,
+    ))
+}
+
+fn ethernet_frame(
+    src: EthernetAddress,
+    dst: EthernetAddress,
+    ethertype: EthernetProtocol,
+    payload: &[u8],
+) -> Packet {
+    let mut frame = Packet::zeroed(ETHERNET_HEADER_LEN + payload.len());
+    let repr = EthernetRepr {
+        src_addr: src,
+        dst_addr: dst,
+        ethertype,
+    };
+    let mut ethernet = EthernetFrame::new_unchecked(&mut frame[..]);
+    repr.emit(&mut ethernet);
+    ethernet.payload_mut().copy_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtualClock;
+
+    const OUTER: Ipv4Address = Ipv4Address::new(192, 168, 1, 2);
+    const INNER: SocketAddrV4 = SocketAddrV4::new(Ipv4Address::new(10, 0, 0, 2), 40000);
+    const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Address::new(1, 1, 1, 1), 80);
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+
+    fn nat() -> (Nat, VirtualClock) {
+        let clock = VirtualClock::new();
+        let mut config = NatConfig::new(OUTER);
+        config.clock = Arc::new(clock.clone());
+        (Nat::new(config), clock)
+    }
+
+    /// An IPv4 packet with valid checksums, whose transport header is
+    /// `payload` with the ports, or ICMP identifier, of `src` and `dst` set.
+    fn packet(
+        protocol: IpProtocol,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        mut payload: Vec<u8>,
+    ) -> Vec<u8> {
+        let from_inner = src.ip() == INNER.ip();
+        if payload.len() >= 6 {
+            set_ports(protocol, &mut payload, src.port(), dst.port(), from_inner);
+        }
+        let mut packet = vec![0; 20];
+        packet.extend_from_slice(&payload);
+        let mut ip = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.set_version(4);
+        ip.set_header_len(20);
+        ip.set_total_len((20 + payload.len()) as u16);
+        ip.set_hop_limit(64);
+        ip.set_next_header(protocol);
+        ip.set_src_addr(*src.ip());
+        ip.set_dst_addr(*dst.ip());
+        if payload.len() >= 20 || protocol != IpProtocol::Tcp {
+            fill_checksums(&mut ip);
+        } else {
+            ip.fill_checksum();
+        }
+        packet
+    }
+
+    fn tcp(src: SocketAddrV4, dst: SocketAddrV4, flags: u8) -> Vec<u8> {
+        let mut header = vec![0; 20];
+        header[12] = 5 << 4;
+        header[13] = flags;
+        packet(IpProtocol::Tcp, src, dst, header)
+    }
+
+    fn udp(src: SocketAddrV4, dst: SocketAddrV4) -> Vec<u8> {
+        // A non-zero checksum, to be filled.
+        let header = vec![0, 0, 0, 0, 0, 12, 0xff, 0xff, 1, 2, 3, 4];
+        packet(IpProtocol::Udp, src, dst, header)
+    }
+
+    /// Returns the addresses and ports of `packet`, checking its checksums.
+    fn endpoints(packet: &[u8]) -> (SocketAddrV4, SocketAddrV4) {
+        let ip = Ipv4Packet::new_checked(packet).unwrap();
+        assert!(ip.verify_checksum());
+        let (src, dst) = (
+            IpAddress::Ipv4(ip.src_addr()),
+            IpAddress::Ipv4(ip.dst_addr()),
+        );
+        let (src_port, dst_port) = match ip.next_header() {
+            IpProtocol::Tcp => {
+                let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+                assert!(tcp.verify_checksum(&src, &dst));
+                (tcp.src_port(), tcp.dst_port())
+            }
+            IpProtocol::Udp => {
+                let udp = UdpPacket::new_checked(ip.payload()).unwrap();
+                assert!(udp.checksum() == 0 || udp.verify_checksum(&src, &dst));
+                (udp.src_port(), udp.dst_port())
+            }
+            _ => {
+                let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+                assert!(icmp.verify_checksum());
+                (icmp.echo_ident(), icmp.echo_ident())
+            }
+        };
+        (
+            SocketAddrV4::new(ip.src_addr(), src_port),
+            SocketAddrV4::new(ip.dst_addr(), dst_port),
+        )
+    }
+
+    fn delivered(result: Inbound) -> bool {
+        matches!(result, Inbound::Deliver)
+    }
+
+    fn passed(result: Inbound) -> bool {
+        matches!(result, Inbound::Pass)
+    }
+
+    #[test]
+    fn tcp_connection() {
+        let (nat, _) = nat();
+        let mut state = nat.state.lock();
+
+        let mut syn = tcp(INNER, REMOTE, SYN);
+        assert!(state.outbound(&mut syn));
+        let (src, dst) = endpoints(&syn);
+        assert_eq!(*src.ip(), OUTER);
+        assert_eq!(dst, REMOTE);
+        assert_eq!(Ipv4Packet::new_unchecked(&syn).hop_limit(), 63);
+        let outer = src;
+
+        let mut syn_ack = tcp(REMOTE, outer, SYN | ACK);
+        assert!(delivered(state.inbound(&mut syn_ack)));
+        assert_eq!(endpoints(&syn_ack), (REMOTE, INNER));
+
+        // The same flow keeps its outer port.
+        let mut ack = tcp(INNER, REMOTE, ACK);
+        assert!(state.outbound(&mut ack));
+        assert_eq!(endpoints(&ack).0, outer);
+
+        // Another remote port is another flow.
+        let other = SocketAddrV4::new(*REMOTE.ip(), 81);
+        let mut syn = tcp(INNER, other, SYN);
+        assert!(state.outbound(&mut syn));
+        assert_ne!(endpoints(&syn).0, outer);
+        assert_eq!(state.entries.len(), 2);
+    }
+
+    #[test]
+    fn unknown_connections() {
+        let (nat, _) = nat();
+        let mut state = nat.state.lock();
+
+        // Only a SYN opens a connection.
+        assert!(!state.outbound(&mut tcp(INNER, REMOTE, ACK)));
+        let outer = SocketAddrV4::new(OUTER, 61000);
+        assert!(passed(state.inbound(&mut tcp(REMOTE, outer, SYN | ACK))));
+        // Packets to other addresses are left to the outer `Net`.
+        let other = SocketAddrV4::new(Ipv4Address::new(192, 168, 1, 3), 61000);
+        assert!(passed(state.inbound(&mut udp(REMOTE, other))));
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn udp_and_icmp_echo() {
+        let (nat, _) = nat();
+        let mut state = nat.state.lock();
+
+        let mut query = udp(INNER, REMOTE);
+        assert!(state.outbound(&mut query));
+        let (outer, _) = endpoints(&query);
+        let mut reply = udp(REMOTE, outer);
+        assert!(delivered(state.inbound(&mut reply)));
+        assert_eq!(endpoints(&reply), (REMOTE, INNER));
+
+        let echo = |ident: u16, message: Icmpv4Message| {
+            let mut icmp = vec![0; 16];
+            icmp[0] = message.into();
+            icmp[4..6].copy_from_slice(&ident.to_be_bytes());
+            icmp
+        };
+        let inner = SocketAddrV4::new(*INNER.ip(), 7);
+        let remote = SocketAddrV4::new(*REMOTE.ip(), 0);
+        let mut request = packet(
+            IpProtocol::Icmp,
+            inner,
+            remote,
+            echo(7, Icmpv4Message::EchoRequest),
+        );
+        assert!(state.outbound(&mut request));
+        let (outer, _) = endpoints(&request);
+        assert_ne!(outer.port(), 7);
+        let mut reply = packet(
+            IpProtocol::Icmp,
+            remote,
+            outer,
+            echo(outer.port(), Icmpv4Message::EchoReply),
+        );
+        assert!(delivered(state.inbound(&mut reply)));
+        assert_eq!(endpoints(&reply).1, inner);
+    }
+
+    #[test]
+    fn forward() {
+        let (nat, _) = nat();
+        let inner = SocketAddrV4::new(*INNER.ip(), 8080);
+        nat.handle()
+            .add_forward(IpProtocol::Tcp, 8000, inner)
+            .unwrap();
+        let mut state = nat.state.lock();
+
+        let outer = SocketAddrV4::new(OUTER, 8000);
+        assert!(passed(state.inbound(&mut tcp(REMOTE, outer, ACK))));
+        let mut syn = tcp(REMOTE, outer, SYN);
+        assert!(delivered(state.inbound(&mut syn)));
+        assert_eq!(endpoints(&syn), (REMOTE, inner));
+
+        let mut syn_ack = tcp(inner, REMOTE, SYN | ACK);
+        assert!(state.outbound(&mut syn_ack));
+        assert_eq!(endpoints(&syn_ack), (outer, REMOTE));
+    }
+
+    #[test]
+    fn timeout() {
+        let (nat, clock) = nat();
+        let mut state = nat.state.lock();
+
+        let mut query = udp(INNER, REMOTE);
+        assert!(state.outbound(&mut query));
+        let (outer, _) = endpoints(&query);
+
+        clock.advance(state.config.udp_timeout - Duration::from_secs(1));
+        assert!(delivered(state.inbound(&mut udp(REMOTE, outer))));
+        clock.advance(state.config.udp_timeout);
+        assert!(passed(state.inbound(&mut udp(REMOTE, outer))));
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        let (nat, _) = nat();
+        let mut state = nat.state.lock();
+
+        // Open a TCP and a UDP flow, so the malformed packets match them.
+        let mut syn = tcp(INNER, REMOTE, SYN);
+        assert!(state.outbound(&mut syn));
+        let (tcp_outer, _) = endpoints(&syn);
+        let mut query = udp(INNER, REMOTE);
+        assert!(state.outbound(&mut query));
+        let (udp_outer, _) = endpoints(&query);
+
+        // TCP segments cut in their header.
+        for len in [8, 12, 17, 19] {
+            let mut header = vec![0; len];
+            if len >= 14 {
+                header[12..14].copy_from_slice(&[5 << 4, ACK]);
+            }
+            let mut outbound = packet(IpProtocol::Tcp, INNER, REMOTE, header.clone());
+            assert!(!state.outbound(&mut outbound));
+            let mut inbound = packet(IpProtocol::Tcp, REMOTE, tcp_outer, header);
+            assert!(passed(state.inbound(&mut inbound)));
+        }
+        // A TCP data offset past the end of the segment.
+        let mut segment = tcp(INNER, REMOTE, ACK);
+        segment[20 + 12] = 15 << 4;
+        assert!(!state.outbound(&mut segment));
+
+        // A UDP length longer than the datagram.
+        let mut datagram = udp(REMOTE, udp_outer);
+        datagram[20 + 4..20 + 6].copy_from_slice(&100u16.to_be_bytes());
+        assert!(passed(state.inbound(&mut datagram)));
+        datagram = udp(INNER, REMOTE);
+        datagram[20 + 4..20 + 6].copy_from_slice(&4u16.to_be_bytes());
+        assert!(!state.outbound(&mut datagram));
+
+        // Truncated IP headers, fragments, expiring and non-IPv4 packets.
+        let query = udp(INNER, REMOTE);
+        assert!(!state.outbound(&mut query[..12].to_vec()));
+        let mut fragment = query.clone();
+        fragment[6] = 0x20;
+        assert!(!state.outbound(&mut fragment));
+        let mut expiring = query.clone();
+        expiring[8] = 1;
+        assert!(!state.outbound(&mut expiring));
+        let mut ipv6 = query.clone();
+        ipv6[0] = 0x65;
+        assert!(!state.outbound(&mut ipv6));
+        assert!(passed(state.inbound(&mut [0x45, 0, 0])));
+    }
+}
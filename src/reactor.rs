@@ -1,95 +1,250 @@
 use crate::{
+    clock::Clock,
     device::{BufferDevice, Packet},
+    filter::{PacketFilter, Verdict},
     socket_allocator::{BufferSize, SocketAlloctor},
+    stats::Counters,
 };
-use futures::{stream::iter, FutureExt, SinkExt, StreamExt};
+use futures::{future::poll_fn, FutureExt};
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 use smoltcp::{
-    iface::{Context, Interface, SocketHandle},
+    iface::{Context, Interface, PollResult, SocketHandle},
+    phy::Medium,
     socket::{AnySocket, Socket},
-    time::{Duration, Instant},
+    time::Duration,
 };
-use std::{collections::VecDeque, future::Future, io, sync::Arc};
-use tokio::{pin, select, sync::Notify, time::sleep};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Wake, Waker},
+};
+use tokio::{pin, select, sync::Notify};
 
 pub(crate) type BufferInterface = Arc<Mutex<Interface>>;
 const MAX_BURST_SIZE: usize = 100;
 
+/// Wakes the reactor, coalescing the wakeups requested while it's busy.
+#[derive(Default)]
+struct Wakeup {
+    notify: Notify,
+    pending: AtomicBool,
+}
+
+impl Wakeup {
+    fn wake(&self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            self.notify.notify_waiters();
+        }
+    }
+    /// Returns whether a wakeup was requested since the last call.
+    fn take(&self) -> bool {
+        self.pending.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// A `Waker` waking the reactor, for the code without access to it.
+struct ReactorWaker(Arc<Wakeup>);
+
+impl Wake for ReactorWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.wake()
+    }
+}
+
 pub(crate) struct Reactor {
-    notify: Arc<Notify>,
+    wakeup: Arc<Wakeup>,
     iface: BufferInterface,
     socket_allocator: SocketAlloctor,
+    clock: Arc<dyn Clock>,
+    stats: Arc<Counters>,
 }
 
+/// The `PacketFilter` of a `Net`, with the medium of its device.
+struct Filter {
+    filter: Option<Box<dyn PacketFilter>>,
+    medium: Medium,
+}
+
+/// Returns `false` when the device stream has ended.
 async fn receive(
     async_iface: &mut impl crate::device::AsyncDevice,
     recv_buf: &mut VecDeque<Packet>,
-) -> io::Result<()> {
-    if let Some(packet) = async_iface.next().await {
-        recv_buf.push_back(packet?);
+    max: usize,
+    stats: &Counters,
+    filter: &mut Filter,
+) -> io::Result<bool> {
+    let start = recv_buf.len();
+    let r = match poll_fn(|cx| async_iface.poll_recv_batch(cx, recv_buf, max)).await {
+        Some(r) => r.map(|_| true),
+        None => Ok(false),
+    };
+    for packet in recv_buf.range(start..) {
+        stats.rx(packet.len());
     }
-    Ok(())
+    if let Some(packet_filter) = &mut filter.filter {
+        let mut index = 0;
+        recv_buf.retain_mut(|packet| {
+            index += 1;
+            let accept =
+                index <= start || packet_filter.ingress(filter.medium, packet) == Verdict::Accept;
+            if !accept {
+                stats.rx_filtered();
+            }
+            accept
+        });
+    }
+    if r.is_err() {
+        stats.rx_error();
+    }
+    #[cfg(feature = "tracing")]
+    if let Err(e) = &r {
+        tracing::warn!(error = %e, "device receive error, skipped");
+    }
+    r
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run(
     mut async_iface: impl crate::device::AsyncDevice,
     iface: BufferInterface,
     mut device: BufferDevice,
     socket_allocator: SocketAlloctor,
-    notify: Arc<Notify>,
+    wakeup: Arc<Wakeup>,
+    clock: Arc<dyn Clock>,
+    stats: Arc<Counters>,
+    filter: Option<Box<dyn PacketFilter>>,
+    #[cfg(feature = "metrics")] mut metrics: crate::metrics::Metrics,
     stopper: Arc<Notify>,
 ) -> io::Result<()> {
     let default_timeout = Duration::from_secs(60);
-    let timer = sleep(default_timeout.into());
+    let mut timer = None;
     let max_burst_size = async_iface
         .capabilities()
         .max_burst_size
         .unwrap_or(MAX_BURST_SIZE);
     let mut recv_buf = VecDeque::with_capacity(max_burst_size);
-    pin!(timer);
+    let mut filter = Filter {
+        filter,
+        medium: async_iface.capabilities().medium,
+    };
 
     loop {
-        let packets = device.take_send_queue();
+        let mut packets = device.take_send_queue();
+        if let Some(packet_filter) = &mut filter.filter {
+            packets.retain_mut(|packet| {
+                let accept = packet_filter.egress(filter.medium, packet) == Verdict::Accept;
+                if !accept {
+                    stats.tx_filtered();
+                }
+                accept
+            });
+        }
+        for packet in &packets {
+            stats.tx(packet.len());
+        }
+        trace!(packets = packets.len(), "sending");
 
-        async_iface
-            .send_all(&mut iter(packets).map(|p| Ok(p)))
-            .await?;
+        let sent = poll_fn(|cx| async_iface.poll_send_batch(cx, &mut packets)).await;
+        #[cfg(feature = "tracing")]
+        if let Err(e) = &sent {
+            tracing::warn!(error = %e, "device send error, stopping");
+        }
+        sent?;
 
+        let mut timer_expired = false;
         if recv_buf.is_empty() && device.need_wait() {
-            let start = Instant::now();
-            let deadline = {
-                iface
+            // Listen before looking for pending wakeups, so one requested in
+            // between still wakes the reactor.
+            let notified = wakeup.notify.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+
+            if !wakeup.pending.load(Ordering::Acquire) && socket_allocator.sync_queue().is_empty()
+            {
+                let start = clock.now();
+                let delay = iface
                     .lock()
                     .poll_delay(start, &socket_allocator.sockets().lock())
-                    .unwrap_or(default_timeout)
-            };
-
-            timer
-                .as_mut()
-                .reset(tokio::time::Instant::now() + deadline.into());
-            select! {
-                _ = &mut timer => {},
-                _ = receive(&mut async_iface,&mut recv_buf) => {}
-                _ = notify.notified() => {}
-                _ = stopper.notified() => break,
-            };
-
-            while let (true, Some(Ok(p))) = (
-                recv_buf.len() < max_burst_size,
-                async_iface.next().now_or_never().flatten(),
-            ) {
-                recv_buf.push_back(p);
+                    .unwrap_or(default_timeout);
+                let deadline = start + delay;
+                #[cfg(feature = "metrics")]
+                let deadline = metrics.deadline().map_or(deadline, |at| at.min(deadline));
+                trace!(%delay, "waiting");
+
+                let (_, sleep) = match timer.take() {
+                    Some((at, sleep)) if at == deadline => timer.insert((at, sleep)),
+                    _ => timer.insert((deadline, clock.sleep_until(deadline))),
+                };
+                select! {
+                    _ = sleep => {
+                        timer = None;
+                        timer_expired = true;
+                    }
+                    r = receive(&mut async_iface, &mut recv_buf, max_burst_size, &stats, &mut filter) => {
+                        if let Ok(false) = r {
+                            debug!("device ended, stopping");
+                            break;
+                        }
+                    }
+                    _ = &mut notified => {}
+                    _ = stopper.notified() => {
+                        debug!("net dropped, stopping");
+                        break;
+                    }
+                };
+
+                let max = max_burst_size.saturating_sub(recv_buf.len());
+                if max > 0 {
+                    receive(&mut async_iface, &mut recv_buf, max, &stats, &mut filter)
+                        .now_or_never();
+                }
             }
         }
 
+        stats.wakeup();
+        let egress = wakeup.take() || timer_expired;
         let mut iface = iface.lock();
+        let mut sockets = socket_allocator.sockets().lock();
 
+        // What doesn't fit waits in `recv_buf` for the next poll.
         device.push_recv_queue(recv_buf.drain(..device.avaliable_recv_queue().min(recv_buf.len())));
 
-        iface.poll(
-            Instant::now(),
-            &mut device,
-            &mut socket_allocator.sockets().lock(),
+        let ingress = !device.need_wait();
+        let synced = socket_allocator.sync_queue().sync(&mut sockets);
+        // Nothing was received and no socket has anything new to send.
+        if !ingress && !synced && !egress {
+            continue;
+        }
+        stats.poll();
+        trace!(
+            ingress,
+            synced,
+            egress,
+            rx_queue = recv_buf.len(),
+            "polling"
+        );
+        if ingress {
+            iface.poll(clock.now(), &mut device, &mut sockets);
+        }
+        // Each socket sends at most one packet per poll, keep going until the
+        // batch is full so the device can coalesce consecutive segments.
+        while iface.poll_egress(clock.now(), &mut device, &mut sockets)
+            == PollResult::SocketStateChanged
+        {}
+        // Hand the received data over to the streams.
+        socket_allocator.sync_queue().sync(&mut sockets);
+        socket_allocator.remove_aborted(&mut sockets);
+        #[cfg(feature = "metrics")]
+        metrics.polled(
+            clock.now(),
+            &stats,
+            &sockets,
+            socket_allocator.sync_queue(),
         );
     }
 
@@ -97,30 +252,45 @@ async fn run(
 }
 
 impl Reactor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         async_device: impl crate::device::AsyncDevice,
         iface: Interface,
         device: BufferDevice,
         buffer_size: BufferSize,
+        clock: Arc<dyn Clock>,
+        filter: Option<Box<dyn PacketFilter>>,
+        #[cfg(feature = "metrics")] metrics: crate::metrics::Metrics,
         stopper: Arc<Notify>,
     ) -> (Self, impl Future<Output = io::Result<()>> + Send) {
         let iface = Arc::new(Mutex::new(iface));
-        let notify = Arc::new(Notify::new());
-        let socket_allocator = SocketAlloctor::new(buffer_size);
+        let wakeup = Arc::new(Wakeup::default());
+        let stats = Arc::new(Counters::default());
+        let socket_allocator = SocketAlloctor::new(
+            buffer_size,
+            Waker::from(Arc::new(ReactorWaker(wakeup.clone()))),
+        );
         let fut = run(
             async_device,
             iface.clone(),
             device,
             socket_allocator.clone(),
-            notify.clone(),
+            wakeup.clone(),
+            clock.clone(),
+            stats.clone(),
+            filter,
+            #[cfg(feature = "metrics")]
+            metrics,
             stopper,
         );
 
         (
             Reactor {
-                notify,
+                wakeup,
                 iface: iface.clone(),
                 socket_allocator,
+                clock,
+                stats,
             },
             fut,
         )
@@ -140,12 +310,19 @@ impl Reactor {
     pub fn socket_allocator(&self) -> &SocketAlloctor {
         &self.socket_allocator
     }
+    /// Wakes the reactor to send what the sockets queued.
     pub fn notify(&self) {
-        self.notify.notify_waiters();
+        self.wakeup.wake();
     }
     pub fn iface(&self) -> &BufferInterface {
         &self.iface
     }
+    pub fn stats(&self) -> &Counters {
+        &self.stats
+    }
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
 }
 
 impl Drop for Reactor {
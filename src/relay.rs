@@ -0,0 +1,179 @@
+//! Relays the datagrams of many UDP peers through a socket per peer, as done
+//! by `Net::forward_udp` and the `Bridge`.
+
+use crate::{Clock, UdpSocket, clock::sleep};
+use parking_lot::Mutex;
+use smoltcp::time::Instant;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{select, task::JoinHandle};
+
+/// A UDP socket, of the host or of a `Net`.
+pub(crate) trait DatagramSocket: Send + Sync + 'static {
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+    fn send_to(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl DatagramSocket for tokio::net::UdpSocket {
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        tokio::net::UdpSocket::recv_from(self, buf)
+    }
+    fn send_to(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        tokio::net::UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+impl DatagramSocket for UdpSocket {
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        UdpSocket::recv_from(self, buf)
+    }
+    fn send_to(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+/// When a `UdpRelay` stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Until {
+    /// Only when dropped, an error of the socket is skipped.
+    Dropped,
+    /// Once every peer is forgotten, or the socket fails.
+    Idle,
+}
+
+/// Sends the datagrams each peer sends to `socket` to `target`, from a socket
+/// of its own, and the replies back to the peer.
+pub(crate) struct UdpRelay<S> {
+    pub(crate) socket: Arc<S>,
+    pub(crate) target: SocketAddr,
+    /// How long a peer may stay silent, both ways, before it is forgotten.
+    pub(crate) timeout: Duration,
+    /// The limit of peers, the datagrams of new peers above it are dropped.
+    pub(crate) max_peers: Arc<AtomicUsize>,
+    pub(crate) until: Until,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+/// A peer sending datagrams through its own socket.
+struct Peer<P> {
+    socket: Arc<P>,
+    last: Arc<Mutex<Instant>>,
+    reader: JoinHandle<()>,
+}
+
+impl<P> Drop for Peer<P> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl<S: DatagramSocket> UdpRelay<S> {
+    /// Relays the datagrams, opening the socket of a new peer with `open`.
+    pub(crate) async fn run<P, F, Fut>(self, mut open: F)
+    where
+        P: DatagramSocket,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<P>>,
+    {
+        let mut peers: HashMap<SocketAddr, Peer<P>> = HashMap::new();
+        let mut buf = vec![0; 65536];
+        let clock = &*self.clock;
+        let timeout = self.timeout.into();
+        let period = self.timeout.min(Duration::from_secs(1));
+        // The first sweep is a period away, it would find no peer yet.
+        let mut sweep = sleep(clock, period);
+
+        loop {
+            select! {
+                r = self.socket.recv_from(&mut buf) => {
+                    let (len, addr) = match r {
+                        Ok(r) => r,
+                        Err(_) if self.until == Until::Dropped => continue,
+                        Err(_) => break,
+                    };
+                    if !peers.contains_key(&addr) {
+                        if peers.len() >= self.max_peers.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let Ok(socket) = open().await else {
+                            continue;
+                        };
+                        let socket = Arc::new(socket);
+                        let last = Arc::new(Mutex::new(clock.now()));
+                        let reader = tokio::spawn(reply(
+                            socket.clone(),
+                            self.socket.clone(),
+                            self.target,
+                            addr,
+                            last.clone(),
+                            self.clock.clone(),
+                        ));
+                        peers.insert(addr, Peer { socket, last, reader });
+                    }
+                    let peer = &peers[&addr];
+                    *peer.last.lock() = clock.now();
+                    let _ = peer.socket.send_to(&buf[..len], self.target).await;
+                }
+                _ = &mut sweep => {
+                    sweep = sleep(clock, period);
+                    let now = clock.now();
+                    peers.retain(|_, peer| now - *peer.last.lock() < timeout);
+                    if peers.is_empty() && self.until == Until::Idle {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends the datagrams the socket of a peer receives from `target` back to it.
+async fn reply<P: DatagramSocket, S: DatagramSocket>(
+    socket: Arc<P>,
+    relay: Arc<S>,
+    target: SocketAddr,
+    peer: SocketAddr,
+    last: Arc<Mutex<Instant>>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut buf = vec![0; 65536];
+    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+        if from != target {
+            continue;
+        }
+        *last.lock() = clock.now();
+        if relay.send_to(&buf[..len], peer).await.is_err() {
+            break;
+        }
+    }
+}
@@ -1,10 +1,13 @@
-use super::{reactor::Reactor, socket_allocator::SocketHandle};
+use super::{
+    handoff::TcpHandoff, reactor::Reactor, socket_allocator::SocketHandle, stats::TcpInfo,
+};
 use futures::future::{self, poll_fn};
 use futures::{ready, Stream};
 pub use smoltcp::socket::{raw, tcp, udp};
+use smoltcp::time::Duration;
 use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol, IpVersion};
 use std::mem::replace;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
 use std::{
     io,
     net::SocketAddr,
@@ -17,6 +20,8 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 /// A TCP socket server, listening for connections.
 ///
 /// You can accept a new connection by using the accept method.
+///
+/// Dropping it resets the connection it may be in the middle of accepting.
 pub struct TcpListener {
     handle: SocketHandle,
     reactor: Arc<Reactor>,
@@ -24,15 +29,17 @@ pub struct TcpListener {
 }
 
 fn map_err<E: std::error::Error>(e: E) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, e.to_string())
+    io::Error::other(e.to_string())
 }
 
 impl TcpListener {
-    pub(super) async fn new(
+    pub(super) fn new(
         reactor: Arc<Reactor>,
         local_endpoint: IpEndpoint,
     ) -> io::Result<TcpListener> {
-        let handle = reactor.socket_allocator().new_tcp_socket();
+        let mut handle = reactor.socket_allocator().new_tcp_socket();
+        // Resets the connection being accepted, if any.
+        handle.set_abort_on_drop(true);
         {
             let mut socket = reactor.get_socket::<tcp::Socket>(*handle);
             socket.listen(local_endpoint).map_err(map_err)?;
@@ -51,9 +58,15 @@ impl TcpListener {
     ) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
         let mut socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
 
-        if socket.state() == tcp::State::Established {
-            drop(socket);
-            return Poll::Ready(Ok(TcpStream::accept(self)?));
+        match socket.state() {
+            tcp::State::Listen | tcp::State::SynReceived => {}
+            // The connection was reset or timed out before it was accepted.
+            tcp::State::Closed => socket.listen(self.local_addr).map_err(map_err)?,
+            // Established, or already closing with data left to read.
+            _ => {
+                drop(socket);
+                return Poll::Ready(Ok(TcpStream::accept(self)?));
+            }
         }
         socket.register_send_waker(cx.waker());
         Poll::Pending
@@ -79,18 +92,40 @@ impl Stream for Incoming {
     }
 }
 
-fn ep2sa(ep: &IpEndpoint) -> SocketAddr {
+pub(crate) fn ep2sa(ep: &IpEndpoint) -> SocketAddr {
     match ep.addr {
-        IpAddress::Ipv4(v4) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from(v4)), ep.port),
-        IpAddress::Ipv6(v6) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from(v6)), ep.port),
+        IpAddress::Ipv4(v4) => SocketAddr::new(IpAddr::V4(v4), ep.port),
+        IpAddress::Ipv6(v6) => SocketAddr::new(IpAddr::V6(v6), ep.port),
         #[allow(unreachable_patterns)]
         _ => unreachable!(),
     }
 }
 
+/// Whether a connection in `state` is made, still being made, or failed:
+/// refused if it never was `connected`, reset otherwise.
+fn poll_connection(state: tcp::State, connected: bool) -> Poll<io::Result<()>> {
+    match state {
+        tcp::State::SynSent | tcp::State::SynReceived => Poll::Pending,
+        tcp::State::Closed if connected => Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "connection reset",
+        ))),
+        tcp::State::Closed => Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "connection refused",
+        ))),
+        // Established, or already closing with data left to read.
+        _ => Poll::Ready(Ok(())),
+    }
+}
+
 /// A TCP stream between a local and a remote socket.
+///
+/// Reads and writes go through buffers of the stream, which the reactor
+/// exchanges with the socket, so they don't wait for the other sockets.
 pub struct TcpStream {
     handle: SocketHandle,
+    handoff: Arc<TcpHandoff>,
     reactor: Arc<Reactor>,
     local_addr: SocketAddr,
     peer_addr: SocketAddr,
@@ -101,38 +136,61 @@ impl TcpStream {
         reactor: Arc<Reactor>,
         local_endpoint: IpEndpoint,
         remote_endpoint: IpEndpoint,
+        connect_timeout: Option<Duration>,
     ) -> io::Result<TcpStream> {
-        let handle = reactor.socket_allocator().new_tcp_socket();
+        let mut handle = reactor.socket_allocator().new_tcp_socket();
+        // Resets the peer if the connect is cancelled.
+        handle.set_abort_on_drop(true);
 
         let connect_result = {
             // Issue #11. We must lock the context before we call connect to
             // avoid lock inversion deadlocks, but drop it before constructing
             // the TcpStream to avoid a second mutable borror of the reactor.
             let mut context = reactor.context();
-            reactor
-                .get_socket::<tcp::Socket>(*handle)
-                .connect(&mut context, remote_endpoint, local_endpoint)
+            let mut socket = reactor.get_socket::<tcp::Socket>(*handle);
+            // smoltcp aborts the socket once the SYN is unanswered for this
+            // long, after as many retransmissions as fit.
+            socket.set_timeout(connect_timeout);
+            socket.connect(&mut context, remote_endpoint, local_endpoint)
         };
         connect_result.map_err(map_err)?;
+        debug!(handle = %*handle, local = %local_endpoint, peer = %remote_endpoint, "connecting");
+        let deadline = connect_timeout.map(|timeout| reactor.clock().now() + timeout);
+
+        reactor.notify();
+        future::poll_fn(|cx| {
+            let mut socket = reactor.get_socket::<tcp::Socket>(*handle);
+            match poll_connection(socket.state(), false) {
+                Poll::Pending => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                Poll::Ready(Ok(())) => {
+                    socket.set_timeout(None);
+                    Poll::Ready(Ok(()))
+                }
+                Poll::Ready(Err(err)) => {
+                    let timed_out = deadline.is_some_and(|at| reactor.clock().now() >= at);
+                    debug!(handle = %*handle, timed_out, "connect failed");
+                    Poll::Ready(Err(if timed_out {
+                        io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+                    } else {
+                        err
+                    }))
+                }
+            }
+        })
+        .await?;
 
         let local_addr = ep2sa(&local_endpoint);
         let peer_addr = ep2sa(&remote_endpoint);
-        let tcp = TcpStream {
-            handle,
-            reactor,
-            local_addr,
-            peer_addr,
-        };
-
-        tcp.reactor.notify();
-        future::poll_fn(|cx| tcp.poll_connected(cx)).await?;
-
-        Ok(tcp)
+        Ok(TcpStream::new(handle, reactor, local_addr, peer_addr))
     }
 
     fn accept(listener: &mut TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
         let reactor = listener.reactor.clone();
-        let new_handle = reactor.socket_allocator().new_tcp_socket();
+        let mut new_handle = reactor.socket_allocator().new_tcp_socket();
+        new_handle.set_abort_on_drop(true);
         {
             let mut new_socket = reactor.get_socket::<tcp::Socket>(*new_handle);
             new_socket.listen(listener.local_addr).map_err(map_err)?;
@@ -146,30 +204,127 @@ impl TcpStream {
             )
         };
 
+        let handle = replace(&mut listener.handle, new_handle);
+        debug!(handle = %*handle, local = %local_addr, peer = %peer_addr, "accepted");
         Ok((
-            TcpStream {
-                handle: replace(&mut listener.handle, new_handle),
-                reactor: reactor.clone(),
-                local_addr,
-                peer_addr,
-            },
+            TcpStream::new(handle, reactor, local_addr, peer_addr),
             peer_addr,
         ))
     }
 
+    /// Waits for the connection of a listener used for a single peer, instead
+    /// of listening again like `TcpListener::accept`.
+    pub(crate) async fn accept_one(listener: TcpListener) -> io::Result<TcpStream> {
+        let TcpListener {
+            handle, reactor, ..
+        } = listener;
+        poll_fn(|cx| {
+            let mut socket = reactor.get_socket::<tcp::Socket>(*handle);
+            match socket.state() {
+                tcp::State::Listen | tcp::State::SynReceived => {}
+                tcp::State::Closed => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "connection reset before it was accepted",
+                    )));
+                }
+                _ => return Poll::Ready(Ok(())),
+            }
+            socket.register_send_waker(cx.waker());
+            Poll::Pending
+        })
+        .await?;
+        let (local_addr, peer_addr) = {
+            let socket = reactor.get_socket::<tcp::Socket>(*handle);
+            (
+                ep2sa(&socket.local_endpoint().unwrap()),
+                ep2sa(&socket.remote_endpoint().unwrap()),
+            )
+        };
+        debug!(handle = %*handle, local = %local_addr, peer = %peer_addr, "accepted");
+        Ok(TcpStream::new(handle, reactor, local_addr, peer_addr))
+    }
+
+    fn new(
+        mut handle: SocketHandle,
+        reactor: Arc<Reactor>,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) -> TcpStream {
+        handle.set_abort_on_drop(false);
+        let allocator = reactor.socket_allocator();
+        let buffer_size = allocator.buffer_size();
+        let handoff = TcpHandoff::new(
+            *handle,
+            &mut reactor.get_socket::<tcp::Socket>(*handle),
+            buffer_size.tcp_rx_size,
+            buffer_size.tcp_tx_size,
+            allocator.sync_queue().clone(),
+        );
+        TcpStream {
+            handle,
+            handoff,
+            reactor,
+            local_addr,
+            peer_addr,
+        }
+    }
+
+    /// Drops the stream after resetting the connection, where a drop just
+    /// forgets it.
+    pub(crate) fn abort(mut self) {
+        debug!(parent: self.handoff.span(), "aborted");
+        self.handle.set_abort_on_drop(true);
+    }
+
+    /// Queues the buffers to be synced, and wakes the reactor if they weren't already.
+    fn schedule(&self) {
+        if self.handoff.schedule() {
+            self.reactor.notify();
+        }
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         Ok(self.local_addr)
     }
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         Ok(self.peer_addr)
     }
+    /// Returns the state and counters of the stream.
+    pub fn info(&self) -> TcpInfo {
+        // The reactor locks the socket before the buffers.
+        let socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
+        let buffers = self.handoff.buffers();
+        TcpInfo {
+            state: socket.state(),
+            local_addr: self.local_addr,
+            peer_addr: self.peer_addr,
+            bytes_sent: buffers.bytes_sent,
+            bytes_received: buffers.bytes_received,
+            send_queue: buffers.tx.len() + socket.send_queue(),
+            recv_queue: buffers.rx.len() + socket.recv_queue(),
+            timeout: socket.timeout(),
+            keep_alive: socket.keep_alive(),
+            ack_delay: socket.ack_delay(),
+            nagle_enabled: socket.nagle_enabled(),
+        }
+    }
+    /// Polls whether the connection is made: `Ok` once it is, even if it is
+    /// already closing, `ConnectionReset` once the socket is closed.
     pub fn poll_connected(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        let mut socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
-        if socket.state() == tcp::State::Established {
-            return Poll::Ready(Ok(()));
+        let mut buffers = self.handoff.buffers();
+        let poll = poll_connection(buffers.state, true);
+        if poll.is_pending() {
+            buffers.tx_waker = Some(cx.waker().clone());
         }
-        socket.register_send_waker(cx.waker());
-        Poll::Pending
+        poll
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        debug!(parent: self.handoff.span(), "dropped");
+        self.handoff.close();
     }
 }
 
@@ -179,19 +334,22 @@ impl AsyncRead for TcpStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let mut socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
-        if !socket.may_recv() {
+        let mut buffers = self.handoff.buffers();
+        if !buffers.rx.is_empty() {
+            let read = buffers.rx.dequeue_slice(buf.initialize_unfilled());
+            buf.advance(read);
+            // The socket's window only needs to be reopened if the data
+            // didn't fit in the stream's buffer.
+            if buffers.rx_pending {
+                drop(buffers);
+                self.schedule();
+            }
             return Poll::Ready(Ok(()));
         }
-        if socket.can_recv() {
-            let read = socket
-                .recv_slice(buf.initialize_unfilled())
-                .map_err(map_err)?;
-            self.reactor.notify();
-            buf.advance(read);
+        if !buffers.may_recv {
             return Poll::Ready(Ok(()));
         }
-        socket.register_recv_waker(cx.waker());
+        buffers.rx_waker = Some(cx.waker().clone());
         Poll::Pending
     }
 }
@@ -202,38 +360,42 @@ impl AsyncWrite for TcpStream {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        let mut socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
-        if !socket.may_send() {
+        let mut buffers = self.handoff.buffers();
+        if !buffers.may_send || buffers.shutdown {
             return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
         }
-        if socket.can_send() {
-            let r = socket.send_slice(buf).map_err(map_err)?;
-            self.reactor.notify();
-            return Poll::Ready(Ok(r));
+        if !buffers.tx.is_full() {
+            let written = buffers.tx.enqueue_slice(buf);
+            drop(buffers);
+            self.schedule();
+            return Poll::Ready(Ok(written));
         }
-        socket.register_send_waker(cx.waker());
+        buffers.tx_waker = Some(cx.waker().clone());
         Poll::Pending
     }
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        let mut socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
-        if socket.send_queue() == 0 {
+        let mut buffers = self.handoff.buffers();
+        if buffers.tx.is_empty() && buffers.send_queue == 0 {
             return Poll::Ready(Ok(()));
         }
-        socket.register_send_waker(cx.waker());
+        buffers.tx_waker = Some(cx.waker().clone());
         Poll::Pending
     }
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        let mut socket = self.reactor.get_socket::<tcp::Socket>(*self.handle);
-
-        if socket.is_open() {
-            socket.close();
-            self.reactor.notify();
+        let mut buffers = self.handoff.buffers();
+
+        if !buffers.shutdown {
+            debug!(parent: self.handoff.span(), "shutting down");
+            buffers.shutdown = true;
+            drop(buffers);
+            self.schedule();
+            buffers = self.handoff.buffers();
         }
-        if socket.state() == tcp::State::Closed {
+        if buffers.state == tcp::State::Closed {
             return Poll::Ready(Ok(()));
         }
 
-        socket.register_send_waker(cx.waker());
+        buffers.tx_waker = Some(cx.waker().clone());
         Poll::Pending
     }
 }
@@ -246,7 +408,7 @@ pub struct UdpSocket {
 }
 
 impl UdpSocket {
-    pub(super) async fn new(
+    pub(super) fn new(
         reactor: Arc<Reactor>,
         local_endpoint: IpEndpoint,
     ) -> io::Result<UdpSocket> {
@@ -304,7 +466,6 @@ impl UdpSocket {
             Err(udp::RecvError::Exhausted) => {}
             r => {
                 let (size, metadata) = r.map_err(map_err)?;
-                self.reactor.notify();
                 return Poll::Ready(Ok((size, ep2sa(&metadata.endpoint))));
             }
         }
@@ -339,6 +500,18 @@ impl RawSocket {
 
         Ok(RawSocket { handle, reactor })
     }
+    /// Makes a raw socket which drops what it receives, for the sockets never
+    /// read from.
+    pub(crate) fn send_only(
+        reactor: Arc<Reactor>,
+        ip_version: IpVersion,
+        ip_protocol: IpProtocol,
+    ) -> RawSocket {
+        let handle = reactor
+            .socket_allocator()
+            .new_send_only_raw_socket(ip_version, ip_protocol);
+        RawSocket { handle, reactor }
+    }
     /// Note that on multiple calls to a poll_* method in the send direction, only the Waker from the Context passed to the most recent call will be scheduled to receive a wakeup.
     pub fn poll_send(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         let mut socket = self.reactor.get_socket::<raw::Socket>(*self.handle);
@@ -1,3 +1,4 @@
+use crate::handoff::SyncQueue;
 use parking_lot::Mutex;
 use smoltcp::{
     iface::{SocketHandle as InnerSocketHandle, SocketSet},
@@ -7,6 +8,7 @@ use smoltcp::{
 use std::{
     ops::{Deref, DerefMut},
     sync::Arc,
+    task::Waker,
 };
 
 /// `BufferSize` is used to configure the size of the socket buffer.
@@ -43,44 +45,95 @@ impl Default for BufferSize {
 
 type SharedSocketSet = Arc<Mutex<SocketSet<'static>>>;
 
+/// The TCP sockets aborted on drop, kept until their reset is sent.
+struct Aborted {
+    handles: Mutex<Vec<InnerSocketHandle>>,
+    /// Wakes the reactor to send the resets.
+    waker: Waker,
+}
+
 #[derive(Clone)]
 pub struct SocketAlloctor {
     sockets: SharedSocketSet,
+    sync_queue: Arc<SyncQueue>,
+    aborted: Arc<Aborted>,
     buffer_size: BufferSize,
 }
 
 impl SocketAlloctor {
-    pub(crate) fn new(buffer_size: BufferSize) -> SocketAlloctor {
+    pub(crate) fn new(buffer_size: BufferSize, waker: Waker) -> SocketAlloctor {
         let sockets = Arc::new(Mutex::new(SocketSet::new(Vec::new())));
         SocketAlloctor {
             sockets,
+            sync_queue: Arc::new(SyncQueue::default()),
+            aborted: Arc::new(Aborted {
+                handles: Mutex::new(Vec::new()),
+                waker,
+            }),
             buffer_size,
         }
     }
     pub(crate) fn sockets(&self) -> &SharedSocketSet {
         &self.sockets
     }
+    pub(crate) fn sync_queue(&self) -> &Arc<SyncQueue> {
+        &self.sync_queue
+    }
+    pub(crate) fn buffer_size(&self) -> &BufferSize {
+        &self.buffer_size
+    }
+    /// Removes the aborted sockets which sent their reset, or had no peer.
+    pub(crate) fn remove_aborted(&self, sockets: &mut SocketSet<'static>) {
+        self.aborted.handles.lock().retain(|&handle| {
+            let reset = sockets.get::<tcp::Socket>(handle).local_endpoint().is_none();
+            if reset {
+                sockets.remove(handle);
+            }
+            !reset
+        });
+    }
+    fn handle(&self, handle: InnerSocketHandle) -> SocketHandle {
+        SocketHandle {
+            inner: handle,
+            sockets: self.sockets.clone(),
+            aborted: self.aborted.clone(),
+            abort_on_drop: false,
+        }
+    }
     pub fn new_tcp_socket(&self) -> SocketHandle {
         let mut set = self.sockets.lock();
         let handle = set.add(self.alloc_tcp_socket());
-        SocketHandle::new(handle, self.sockets.clone())
+        self.handle(handle)
     }
     pub fn new_udp_socket(&self) -> SocketHandle {
         let mut set = self.sockets.lock();
         let handle = set.add(self.alloc_udp_socket());
-        SocketHandle::new(handle, self.sockets.clone())
+        self.handle(handle)
     }
     pub fn new_raw_socket(&self, ip_version: IpVersion, ip_protocol: IpProtocol) -> SocketHandle {
         let mut set = self.sockets.lock();
         let handle = set.add(self.alloc_raw_socket(ip_version, ip_protocol));
-        SocketHandle::new(handle, self.sockets.clone())
+        self.handle(handle)
+    }
+    /// Makes a raw socket without a receive buffer, which drops the packets
+    /// it matches instead of keeping them for a reader.
+    pub(crate) fn new_send_only_raw_socket(
+        &self,
+        ip_version: IpVersion,
+        ip_protocol: IpProtocol,
+    ) -> SocketHandle {
+        let mut set = self.sockets.lock();
+        let handle = set.add(self.alloc_raw_socket_with(
+            ip_version,
+            ip_protocol,
+            raw::PacketBuffer::new(Vec::new(), Vec::new()),
+        ));
+        self.handle(handle)
     }
     fn alloc_tcp_socket(&self) -> tcp::Socket<'static> {
         let rx_buffer = tcp::SocketBuffer::new(vec![0; self.buffer_size.tcp_rx_size]);
         let tx_buffer = tcp::SocketBuffer::new(vec![0; self.buffer_size.tcp_tx_size]);
-        let tcp = tcp::Socket::new(rx_buffer, tx_buffer);
-
-        tcp
+        tcp::Socket::new(rx_buffer, tx_buffer)
     }
     fn alloc_udp_socket(&self) -> udp::Socket<'static> {
         let rx_buffer = udp::PacketBuffer::new(
@@ -91,9 +144,7 @@ impl SocketAlloctor {
             vec![udp::PacketMetadata::EMPTY; self.buffer_size.udp_tx_meta_size],
             vec![0; self.buffer_size.udp_tx_size],
         );
-        let udp = udp::Socket::new(rx_buffer, tx_buffer);
-
-        udp
+        udp::Socket::new(rx_buffer, tx_buffer)
     }
     fn alloc_raw_socket(
         &self,
@@ -104,28 +155,47 @@ impl SocketAlloctor {
             vec![raw::PacketMetadata::EMPTY; self.buffer_size.raw_rx_meta_size],
             vec![0; self.buffer_size.raw_rx_size],
         );
+        self.alloc_raw_socket_with(ip_version, ip_protocol, rx_buffer)
+    }
+    fn alloc_raw_socket_with(
+        &self,
+        ip_version: IpVersion,
+        ip_protocol: IpProtocol,
+        rx_buffer: raw::PacketBuffer<'static>,
+    ) -> raw::Socket<'static> {
         let tx_buffer = raw::PacketBuffer::new(
             vec![raw::PacketMetadata::EMPTY; self.buffer_size.raw_tx_meta_size],
             vec![0; self.buffer_size.raw_tx_size],
         );
-        let raw = raw::Socket::new(ip_version, ip_protocol, rx_buffer, tx_buffer);
-
-        raw
+        raw::Socket::new(ip_version, ip_protocol, rx_buffer, tx_buffer)
     }
 }
 
-pub struct SocketHandle(InnerSocketHandle, SharedSocketSet);
+pub struct SocketHandle {
+    inner: InnerSocketHandle,
+    sockets: SharedSocketSet,
+    aborted: Arc<Aborted>,
+    abort_on_drop: bool,
+}
 
 impl SocketHandle {
-    fn new(inner: InnerSocketHandle, set: SharedSocketSet) -> SocketHandle {
-        SocketHandle(inner, set)
+    /// Makes the drop of a TCP socket abort it, resetting its peer, instead of
+    /// removing it right away.
+    pub(crate) fn set_abort_on_drop(&mut self, abort_on_drop: bool) {
+        self.abort_on_drop = abort_on_drop;
     }
 }
 
 impl Drop for SocketHandle {
     fn drop(&mut self) {
-        let mut iface = self.1.lock();
-        iface.remove(self.0);
+        let mut iface = self.sockets.lock();
+        if self.abort_on_drop {
+            iface.get_mut::<tcp::Socket>(self.inner).abort();
+            self.aborted.handles.lock().push(self.inner);
+            self.aborted.waker.wake_by_ref();
+        } else {
+            iface.remove(self.inner);
+        }
     }
 }
 
@@ -133,12 +203,12 @@ impl Deref for SocketHandle {
     type Target = InnerSocketHandle;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
 impl DerefMut for SocketHandle {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.inner
     }
 }
@@ -0,0 +1,385 @@
+//! A SOCKS5 server giving the applications of the host access to a `Net`.
+//!
+//! It serves `CONNECT` and `UDP ASSOCIATE` (RFC 1928), optionally behind a
+//! username/password authentication (RFC 1929), and fulfils them with
+//! `Net::tcp_connect` and `Net::udp_bind`. Domain names are resolved by
+//! `Net::lookup`, through the DNS server of the `NetConfig`.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use tokio_smoltcp::Net;
+//! # async fn socks5(net: Arc<Net>) -> std::io::Result<()> {
+//! use tokio_smoltcp::socks5::{Socks5Config, Socks5Server};
+//!
+//! let config = Socks5Config::new();
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:1080").await?;
+//! Socks5Server::new(net, config).serve(listener).await
+//! # }
+//! ```
+
+use crate::{Net, clock::timeout};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional},
+    net::{TcpListener, TcpStream, UdpSocket},
+    select,
+};
+
+const VERSION: u8 = 5;
+
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+const CONNECT: u8 = 1;
+const UDP_ASSOCIATE: u8 = 3;
+
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NETWORK_UNREACHABLE: u8 = 3;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// The domain names a UDP association keeps resolved, it forgets them all
+/// once there are more.
+const MAX_NAMES: usize = 64;
+
+/// A config for a `Socks5Server`.
+#[non_exhaustive]
+pub struct Socks5Config {
+    /// The username and password the clients must authenticate with. Clients
+    /// aren't authenticated by default.
+    pub auth: Option<(String, String)>,
+    /// How long to wait for a TCP connection or a DNS reply, 10 seconds by
+    /// default.
+    pub timeout: Duration,
+}
+
+impl Socks5Config {
+    pub fn new() -> Self {
+        Self {
+            auth: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for Socks5Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A SOCKS5 server connecting its clients through a `Net`.
+#[derive(Clone)]
+pub struct Socks5Server {
+    net: Arc<Net>,
+    config: Arc<Socks5Config>,
+}
+
+/// The destination of a request, as sent by the client.
+enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Socks5Server {
+    pub fn new(net: Arc<Net>, config: Socks5Config) -> Self {
+        Self {
+            net,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Serves every client accepted on `listener`, each in its own task.
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (client, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                let _ = server.serve_client(client).await;
+            });
+        }
+    }
+
+    /// Serves a single client, until its connection, or its UDP association,
+    /// is closed.
+    pub async fn serve_client(&self, mut client: TcpStream) -> io::Result<()> {
+        self.authenticate(&mut client).await?;
+
+        let mut request = [0; 3];
+        client.read_exact(&mut request).await?;
+        if request[0] != VERSION {
+            return Err(invalid_data("unsupported SOCKS version"));
+        }
+        let target = match read_target(&mut client).await? {
+            Some(target) => target,
+            None => return reply(&mut client, ADDRESS_TYPE_NOT_SUPPORTED, None).await,
+        };
+        match request[1] {
+            CONNECT => self.connect(client, target).await,
+            UDP_ASSOCIATE => self.associate(client, target).await,
+            _ => reply(&mut client, COMMAND_NOT_SUPPORTED, None).await,
+        }
+    }
+
+    async fn authenticate(&self, client: &mut TcpStream) -> io::Result<()> {
+        let mut header = [0; 2];
+        client.read_exact(&mut header).await?;
+        if header[0] != VERSION {
+            return Err(invalid_data("unsupported SOCKS version"));
+        }
+        let mut methods = vec![0; header[1] as usize];
+        client.read_exact(&mut methods).await?;
+
+        let method = match self.config.auth {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTH,
+        };
+        if !methods.contains(&method) {
+            client.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "no acceptable authentication method",
+            ));
+        }
+        client.write_all(&[VERSION, method]).await?;
+
+        let Some((username, password)) = &self.config.auth else {
+            return Ok(());
+        };
+        // The subnegotiation of RFC 1929, which has its own version.
+        let mut version = [0; 1];
+        client.read_exact(&mut version).await?;
+        let given_username = read_string(client).await?;
+        let given_password = read_string(client).await?;
+        // Both are compared in full, so the time taken doesn't tell which
+        // one, nor how much of it, is wrong.
+        let matches = constant_time_eq(given_username.as_bytes(), username.as_bytes())
+            & constant_time_eq(given_password.as_bytes(), password.as_bytes());
+        if version[0] != 1 || !matches {
+            client.write_all(&[1, 1]).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "authentication failed",
+            ));
+        }
+        client.write_all(&[1, 0]).await
+    }
+
+    async fn connect(&self, mut client: TcpStream, target: Target) -> io::Result<()> {
+        let addr = match self.resolve(target).await {
+            Ok(addr) => addr,
+            Err(code) => return reply(&mut client, code, None).await,
+        };
+        let connect = self.net.tcp_connect(addr);
+        let mut stream = match timeout(&**self.net.clock(), self.config.timeout, connect).await {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => return reply(&mut client, error_code(&e), None).await,
+            None => return reply(&mut client, HOST_UNREACHABLE, None).await,
+        };
+        reply(&mut client, SUCCEEDED, Some(stream.local_addr()?)).await?;
+        copy_bidirectional(&mut client, &mut stream).await?;
+        Ok(())
+    }
+
+    /// Relays the datagrams of the client between a host socket and a socket
+    /// of the `Net`, until the control connection is closed.
+    async fn associate(&self, mut control: TcpStream, target: Target) -> io::Result<()> {
+        let client_ip = control.peer_addr()?.ip();
+        // The port the client said it would send from, if it knew it.
+        let client_port = match target {
+            Target::Addr(addr) => addr.port(),
+            Target::Domain(_, port) => port,
+        };
+        let sockets = async {
+            let relay = UdpSocket::bind((control.local_addr()?.ip(), 0)).await?;
+            let socket = self.net.udp_bind((Ipv4Addr::UNSPECIFIED, 0).into()).await?;
+            io::Result::Ok((relay, socket))
+        };
+        let (relay, socket) = match sockets.await {
+            Ok(sockets) => sockets,
+            Err(e) => return reply(&mut control, error_code(&e), None).await,
+        };
+        reply(&mut control, SUCCEEDED, Some(relay.local_addr()?)).await?;
+
+        let mut client = None;
+        let mut names = HashMap::new();
+        let mut buf = vec![0; 65536];
+        let mut reply_buf = vec![0; 65536];
+        let mut control_buf = [0; 64];
+        loop {
+            select! {
+                r = control.read(&mut control_buf) => {
+                    if matches!(r, Ok(0) | Err(_)) {
+                        return Ok(());
+                    }
+                }
+                r = relay.recv_from(&mut buf) => {
+                    let (len, from) = r?;
+                    if from.ip() != client_ip || (client_port != 0 && from.port() != client_port) {
+                        continue;
+                    }
+                    client = Some(from);
+                    // Fragments are not supported, and dropped.
+                    let Some((target, header_len)) = buf[..len]
+                        .strip_prefix(&[0, 0, 0])
+                        .and_then(parse_target)
+                    else {
+                        continue;
+                    };
+                    let addr = match target {
+                        Target::Domain(name, port) if !names.contains_key(&name) => {
+                            let Ok(addr) = self.resolve(Target::Domain(name.clone(), port)).await
+                            else {
+                                continue;
+                            };
+                            if names.len() >= MAX_NAMES {
+                                names.clear();
+                            }
+                            names.insert(name, addr.ip());
+                            addr
+                        }
+                        Target::Domain(name, port) => SocketAddr::new(names[&name], port),
+                        Target::Addr(addr) => addr,
+                    };
+                    let _ = socket.send_to(&buf[3 + header_len..len], addr).await;
+                }
+                r = socket.recv_from(&mut reply_buf) => {
+                    let (len, from) = r?;
+                    let Some(client) = client else {
+                        continue;
+                    };
+                    let mut datagram = vec![0, 0, 0];
+                    write_addr(&mut datagram, from);
+                    datagram.extend_from_slice(&reply_buf[..len]);
+                    let _ = relay.send_to(&datagram, client).await;
+                }
+            }
+        }
+    }
+
+    /// Resolves the domain name of `target`, or returns the reply code to
+    /// refuse it with. Domain names are refused if the `Net` has no DNS
+    /// server.
+    async fn resolve(&self, target: Target) -> Result<SocketAddr, u8> {
+        let (name, port) = match target {
+            Target::Addr(addr) => return Ok(addr),
+            Target::Domain(name, port) => (name, port),
+        };
+        let lookup = self.net.lookup(&name);
+        match timeout(&**self.net.clock(), self.config.timeout, lookup).await {
+            Some(Ok(ips)) => Ok(SocketAddr::new(ips[0], port)),
+            Some(Err(e)) if e.kind() == io::ErrorKind::InvalidInput => {
+                Err(ADDRESS_TYPE_NOT_SUPPORTED)
+            }
+            _ => Err(HOST_UNREACHABLE),
+        }
+    }
+}
+
+async fn reply(client: &mut TcpStream, code: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut reply = vec![VERSION, code, 0];
+    write_addr(
+        &mut reply,
+        bound.unwrap_or((Ipv4Addr::UNSPECIFIED, 0).into()),
+    );
+    client.write_all(&reply).await
+}
+
+fn error_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => HOST_UNREACHABLE,
+        _ => GENERAL_FAILURE,
+    }
+}
+
+/// Compares two strings in a time depending only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(a.len() ^ b.len(), |diff, (x, y)| diff | usize::from(x ^ y));
+    diff == 0
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+async fn read_string(client: &mut TcpStream) -> io::Result<String> {
+    let len = client.read_u8().await?;
+    let mut string = vec![0; len as usize];
+    client.read_exact(&mut string).await?;
+    String::from_utf8(string).map_err(|_| invalid_data("invalid string"))
+}
+
+/// Reads an address of a request, returns `None` if its type is unknown.
+async fn read_target(client: &mut TcpStream) -> io::Result<Option<Target>> {
+    let mut target = vec![client.read_u8().await?];
+    let len = match target[0] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => {
+            let len = client.read_u8().await?;
+            target.push(len);
+            len as usize
+        }
+        _ => return Ok(None),
+    };
+    let start = target.len();
+    target.resize(start + len + 2, 0);
+    client.read_exact(&mut target[start..]).await?;
+    match parse_target(&target) {
+        Some((target, _)) => Ok(Some(target)),
+        None => Err(invalid_data("invalid address")),
+    }
+}
+
+/// Parses an address and its port, returns it with its length.
+fn parse_target(buf: &[u8]) -> Option<(Target, usize)> {
+    let (&kind, rest) = buf.split_first()?;
+    let (start, len) = match kind {
+        IPV4 => (0, 4),
+        IPV6 => (0, 16),
+        DOMAIN => (1, *rest.first()? as usize),
+        _ => return None,
+    };
+    let addr = rest.get(start..start + len)?;
+    let port = rest.get(start + len..start + len + 2)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let target = match kind {
+        IPV4 => Target::Addr((<[u8; 4]>::try_from(addr).ok()?, port).into()),
+        IPV6 => Target::Addr((<[u8; 16]>::try_from(addr).ok()?, port).into()),
+        _ => Target::Domain(std::str::from_utf8(addr).ok()?.to_string(), port),
+    };
+    Some((target, 1 + start + len + 2))
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
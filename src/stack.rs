@@ -0,0 +1,187 @@
+//! Traits over the network stacks, implemented by `Net` and by `TokioNet`, the
+//! stack of the host through `tokio::net`.
+//!
+//! Code generic over a `NetStack` runs unchanged on both.
+//!
+//! ```
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//! use tokio_smoltcp::stack::NetStack;
+//!
+//! async fn fetch<S: NetStack>(stack: &S, host: &str) -> std::io::Result<Vec<u8>> {
+//!     let ip = stack.lookup(host).await?[0];
+//!     let mut stream = stack.tcp_connect((ip, 80).into()).await?;
+//!     stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+//!     let mut response = Vec::new();
+//!     stream.read_to_end(&mut response).await?;
+//!     Ok(response)
+//! }
+//! ```
+
+use crate::Net;
+use futures::Future;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A network stack making TCP connections, TCP listeners and UDP sockets.
+pub trait NetStack: Send + Sync {
+    type TcpStream: StackTcpStream;
+    type TcpListener: StackTcpListener<TcpStream = Self::TcpStream>;
+    type UdpSocket: StackUdpSocket;
+
+    /// Opens a TCP connection to `addr`.
+    fn tcp_connect(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<Self::TcpStream>> + Send;
+    /// Creates a TCP listener bound to `addr`.
+    fn tcp_bind(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<Self::TcpListener>> + Send;
+    /// Creates a UDP socket bound to `addr`.
+    fn udp_bind(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<Self::UdpSocket>> + Send;
+    /// Resolves `host`, a domain name or an IP address.
+    fn lookup(&self, host: &str) -> impl Future<Output = io::Result<Vec<IpAddr>>> + Send;
+}
+
+/// A TCP connection of a `NetStack`.
+pub trait StackTcpStream: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A TCP listener of a `NetStack`.
+pub trait StackTcpListener: Send + 'static {
+    type TcpStream: StackTcpStream;
+
+    /// Accepts a new connection, returns it with the address of the peer.
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::TcpStream, SocketAddr)>> + Send;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A UDP socket of a `NetStack`.
+pub trait StackUdpSocket: Send + Sync + 'static {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl NetStack for Net {
+    type TcpStream = crate::TcpStream;
+    type TcpListener = crate::TcpListener;
+    type UdpSocket = crate::UdpSocket;
+
+    async fn tcp_connect(&self, addr: SocketAddr) -> io::Result<crate::TcpStream> {
+        Net::tcp_connect(self, addr).await
+    }
+    async fn tcp_bind(&self, addr: SocketAddr) -> io::Result<crate::TcpListener> {
+        Net::tcp_bind(self, addr).await
+    }
+    async fn udp_bind(&self, addr: SocketAddr) -> io::Result<crate::UdpSocket> {
+        Net::udp_bind(self, addr).await
+    }
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Net::lookup(self, host).await
+    }
+}
+
+impl StackTcpStream for crate::TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        crate::TcpStream::local_addr(self)
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        crate::TcpStream::peer_addr(self)
+    }
+}
+
+impl StackTcpListener for crate::TcpListener {
+    type TcpStream = crate::TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(crate::TcpStream, SocketAddr)> {
+        crate::TcpListener::accept(self).await
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        crate::TcpListener::local_addr(self)
+    }
+}
+
+impl StackUdpSocket for crate::UdpSocket {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        crate::UdpSocket::send_to(self, buf, target).await
+    }
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        crate::UdpSocket::recv_from(self, buf).await
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        crate::UdpSocket::local_addr(self)
+    }
+}
+
+/// The network stack of the host, through `tokio::net`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioNet;
+
+impl NetStack for TokioNet {
+    type TcpStream = tokio::net::TcpStream;
+    type TcpListener = tokio::net::TcpListener;
+    type UdpSocket = tokio::net::UdpSocket;
+
+    async fn tcp_connect(&self, addr: SocketAddr) -> io::Result<tokio::net::TcpStream> {
+        tokio::net::TcpStream::connect(addr).await
+    }
+    async fn tcp_bind(&self, addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+        tokio::net::TcpListener::bind(addr).await
+    }
+    async fn udp_bind(&self, addr: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+        tokio::net::UdpSocket::bind(addr).await
+    }
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs = tokio::net::lookup_host((host, 0)).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+impl StackTcpStream for tokio::net::TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::TcpStream::local_addr(self)
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::TcpStream::peer_addr(self)
+    }
+}
+
+impl StackTcpListener for tokio::net::TcpListener {
+    type TcpStream = tokio::net::TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(tokio::net::TcpStream, SocketAddr)> {
+        tokio::net::TcpListener::accept(self).await
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::TcpListener::local_addr(self)
+    }
+}
+
+impl StackUdpSocket for tokio::net::UdpSocket {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        tokio::net::UdpSocket::send_to(self, buf, target).await
+    }
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        tokio::net::UdpSocket::recv_from(self, buf).await
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+}
@@ -0,0 +1,174 @@
+use crate::socket::ep2sa;
+use smoltcp::{
+    socket::{Socket, tcp},
+    time::Duration,
+    wire::{IpEndpoint, IpListenEndpoint, IpProtocol, IpVersion},
+};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters of a `Net`, as returned by `Net::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct NetStats {
+    /// Packets received from the device.
+    pub rx_packets: u64,
+    /// Bytes received from the device.
+    pub rx_bytes: u64,
+    /// Errors returned by the device's stream, which the reactor skips.
+    pub rx_errors: u64,
+    /// Received packets dropped by the `PacketFilter`.
+    pub rx_filtered: u64,
+    /// Packets sent to the device.
+    pub tx_packets: u64,
+    /// Bytes sent to the device.
+    pub tx_bytes: u64,
+    /// Packets to send dropped by the `PacketFilter`.
+    pub tx_filtered: u64,
+    /// Times the reactor woke up.
+    pub wakeups: u64,
+    /// Times the reactor polled the interface.
+    pub polls: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_errors: AtomicU64,
+    rx_filtered: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_filtered: AtomicU64,
+    wakeups: AtomicU64,
+    polls: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn rx(&self, bytes: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub(crate) fn rx_error(&self) {
+        self.rx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn rx_filtered(&self) {
+        self.rx_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn tx(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub(crate) fn tx_filtered(&self) {
+        self.tx_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn wakeup(&self) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn poll(&self) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn snapshot(&self) -> NetStats {
+        NetStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_errors: self.rx_errors.load(Ordering::Relaxed),
+            rx_filtered: self.rx_filtered.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_filtered: self.tx_filtered.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+            polls: self.polls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The state of a `TcpStream`, as returned by `TcpStream::info`.
+///
+/// smoltcp doesn't expose its RTT estimate nor how many segments it
+/// retransmitted, so they aren't reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TcpInfo {
+    pub state: tcp::State,
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    /// Bytes handed over to the socket to be sent.
+    pub bytes_sent: u64,
+    /// Bytes received from the socket.
+    pub bytes_received: u64,
+    /// Bytes written to the stream but not acknowledged by the peer yet.
+    pub send_queue: usize,
+    /// Bytes received but not read from the stream yet.
+    pub recv_queue: usize,
+    pub timeout: Option<Duration>,
+    pub keep_alive: Option<Duration>,
+    pub ack_delay: Option<Duration>,
+    pub nagle_enabled: bool,
+}
+
+/// A socket of a `Net`, as listed by `Net::sockets`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SocketInfo {
+    Tcp {
+        state: tcp::State,
+        local_addr: SocketAddr,
+        peer_addr: Option<SocketAddr>,
+        send_queue: usize,
+        recv_queue: usize,
+    },
+    Udp {
+        local_addr: SocketAddr,
+        send_queue: usize,
+        recv_queue: usize,
+    },
+    Raw {
+        ip_version: IpVersion,
+        ip_protocol: IpProtocol,
+        send_queue: usize,
+        recv_queue: usize,
+    },
+}
+
+impl SocketInfo {
+    /// Describes `socket`, whose stream, if it has one, holds `buffered` bytes
+    /// to send and to read on top of the socket's queues.
+    pub(crate) fn new(socket: &Socket<'_>, buffered: (usize, usize)) -> Option<SocketInfo> {
+        Some(match socket {
+            Socket::Tcp(tcp) => SocketInfo::Tcp {
+                state: tcp.state(),
+                local_addr: match tcp.local_endpoint() {
+                    Some(endpoint) => ep2sa(&endpoint),
+                    None => listen_addr(tcp.listen_endpoint()),
+                },
+                peer_addr: tcp.remote_endpoint().as_ref().map(ep2sa),
+                send_queue: buffered.0 + tcp.send_queue(),
+                recv_queue: buffered.1 + tcp.recv_queue(),
+            },
+            Socket::Udp(udp) => SocketInfo::Udp {
+                local_addr: listen_addr(udp.endpoint()),
+                send_queue: udp.send_queue(),
+                recv_queue: udp.recv_queue(),
+            },
+            Socket::Raw(raw) => SocketInfo::Raw {
+                ip_version: raw.ip_version(),
+                ip_protocol: raw.ip_protocol(),
+                send_queue: raw.send_queue(),
+                recv_queue: raw.recv_queue(),
+            },
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        })
+    }
+}
+
+/// The address a socket listens on, unspecified if it accepts any.
+fn listen_addr(endpoint: IpListenEndpoint) -> SocketAddr {
+    match endpoint.addr {
+        Some(addr) => ep2sa(&IpEndpoint::new(addr, endpoint.port)),
+        None => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), endpoint.port),
+    }
+}
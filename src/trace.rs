@@ -0,0 +1,19 @@
+//! Events emitted through `tracing` when the `tracing` feature is enabled.
+//!
+//! The macros take the arguments of their `tracing` counterparts and expand to
+//! nothing without the feature, so their arguments must not be the only use of
+//! a binding.
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::trace!($($arg)*);
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($arg)*);
+    };
+}
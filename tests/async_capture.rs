@@ -0,0 +1,197 @@
+#![cfg(unix)]
+
+use futures::{Sink, SinkExt, future::poll_fn};
+use smoltcp::phy::DeviceCapabilities;
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio_smoltcp::device::{AsyncCapture, AsyncDevice, OverflowPolicy, Packet};
+
+/// A packet starting with this byte fails to be sent.
+const BAD: u8 = 0xff;
+
+fn capture(
+    queue_capacity: usize,
+    overflow: OverflowPolicy,
+) -> (impl AsyncDevice, UnixDatagram, Arc<AtomicU64>) {
+    let (a, b) = UnixDatagram::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    b.set_nonblocking(true).unwrap();
+    // Fill the send buffer of `a`.
+    while a.send(&[0; 1024]).is_ok() {}
+
+    let mut caps = DeviceCapabilities::default();
+    caps.max_transmission_unit = 1500;
+    let mut capture = AsyncCapture::new(
+        a,
+        |a| {
+            let mut buf = vec![0; 1500];
+            let n = a.recv(&mut buf)?;
+            buf.truncate(n);
+            Ok(buf)
+        },
+        |a, p| {
+            if p[0] == BAD {
+                return Err(io::Error::other("bad packet"));
+            }
+            a.send(p).map(|_| ())
+        },
+        caps,
+    )
+    .unwrap();
+    capture.set_queue_capacity(queue_capacity);
+    capture.set_overflow(overflow);
+    let dropped = capture.drop_counter();
+    (capture, b, dropped)
+}
+
+/// Reads what `a` sent after the filler, which is 1024 bytes long.
+fn received(b: &UnixDatagram) -> Vec<Vec<u8>> {
+    let mut buf = [0; 2048];
+    let mut packets = Vec::new();
+    while let Ok(n) = b.recv(&mut buf) {
+        if n != 1024 {
+            packets.push(buf[..n].to_vec());
+        }
+    }
+    packets
+}
+
+/// Polls the stream, which sends the queue, until `count` packets are received.
+async fn stream_received<D: AsyncDevice>(
+    capture: &mut D,
+    b: &UnixDatagram,
+    count: usize,
+) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let wait = async {
+        while packets.len() < count {
+            poll_fn(|cx| match Pin::new(&mut *capture).poll_next(cx) {
+                Poll::Pending => Poll::Ready(()),
+                Poll::Ready(r) => panic!("unexpected {:?}", r),
+            })
+            .await;
+            packets.extend(received(b));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .expect("packets not sent");
+    packets
+}
+
+fn packet(byte: u8) -> Packet {
+    Packet::from(&[byte; 8][..])
+}
+
+fn poll_ready<D: AsyncDevice>(device: &mut D) -> Poll<io::Result<()>> {
+    Pin::new(device).poll_ready(&mut Context::from_waker(futures::task::noop_waker_ref()))
+}
+
+#[tokio::test]
+async fn block_when_full() {
+    let (mut capture, b, dropped) = capture(2, OverflowPolicy::Block);
+
+    for byte in 1..=2 {
+        assert!(poll_ready(&mut capture).is_ready());
+        Pin::new(&mut capture).start_send(packet(byte)).unwrap();
+    }
+    assert!(poll_ready(&mut capture).is_pending());
+
+    assert!(received(&b).is_empty());
+    capture.send(packet(3)).await.unwrap();
+    assert_eq!(received(&b), [[1; 8], [2; 8], [3; 8]]);
+    assert_eq!(dropped.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn drop_oldest_when_full() {
+    let (mut capture, b, dropped) = capture(2, OverflowPolicy::DropOldest);
+
+    for byte in 1..=3 {
+        assert!(poll_ready(&mut capture).is_ready());
+        Pin::new(&mut capture).start_send(packet(byte)).unwrap();
+    }
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    // The flush doesn't wait for the socket.
+    capture.flush().await.unwrap();
+
+    assert!(received(&b).is_empty());
+    assert_eq!(stream_received(&mut capture, &b, 2).await, [[2; 8], [3; 8]]);
+}
+
+#[tokio::test]
+async fn stream_drains_queue_past_errors() {
+    let (mut capture, b, dropped) = capture(4, OverflowPolicy::DropOldest);
+
+    for byte in [1, BAD, 2, BAD, 3] {
+        Pin::new(&mut capture).start_send(packet(byte)).unwrap();
+    }
+    // The queue overflowed into dropping the first packet.
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+    assert!(received(&b).is_empty());
+    // Both failed packets are dropped and the rest of the queue is sent.
+    assert_eq!(stream_received(&mut capture, &b, 2).await, [[2; 8], [3; 8]]);
+    assert_eq!(dropped.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn direct_send_errors() {
+    let (mut capture, b, dropped) = capture(2, OverflowPolicy::Block);
+    // Makes room in the send buffer, so packets are sent without queueing.
+    assert!(received(&b).is_empty());
+
+    capture.send(packet(BAD)).await.unwrap();
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    capture.send(packet(1)).await.unwrap();
+    assert_eq!(received(&b), [[1; 8]]);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn mmsg_batches() {
+    use std::collections::VecDeque;
+
+    let (a, b) = UnixDatagram::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    let mut caps = DeviceCapabilities::default();
+    caps.max_transmission_unit = 100;
+    let mut capture =
+        AsyncCapture::new(a, |_| unreachable!(), |_, _| unreachable!(), caps).unwrap();
+    capture.set_mmsg(true);
+    let dropped = capture.drop_counter();
+
+    b.send(&[1; 10]).unwrap();
+    b.send(&[2; 101]).unwrap();
+    b.send(&[3; 100]).unwrap();
+    let mut buf = VecDeque::new();
+    let received = poll_fn(|cx| capture.poll_recv_batch(cx, &mut buf, 8))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, 2);
+    assert_eq!(buf, [&[1; 10][..], &[3; 100][..]]);
+    // The message longer than the MTU is dropped.
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+    let mut packets = VecDeque::from([packet(4), packet(5)]);
+    poll_fn(|cx| capture.poll_send_batch(cx, &mut packets))
+        .await
+        .unwrap();
+    assert!(packets.is_empty());
+    let mut buf = [0; 16];
+    for byte in [4, 5] {
+        assert_eq!(b.recv(&mut buf).unwrap(), 8);
+        assert_eq!(buf[..8], [byte; 8]);
+    }
+}
@@ -0,0 +1,304 @@
+mod common;
+
+use futures::{Sink, Stream};
+use smoltcp::phy::{DeviceCapabilities, Medium};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio_smoltcp::device::{AsyncDevice, Bond, BondMode, Packet};
+
+const MAX_BURST_SIZE: usize = 4;
+
+#[derive(Default)]
+struct LinkState {
+    sent: Vec<Packet>,
+    received: VecDeque<Packet>,
+    stalled: bool,
+    failing: bool,
+    waker: Option<Waker>,
+}
+
+/// An in-memory link. Packets pushed to `received` come out of its stream,
+/// and the packets sent on it are collected in `sent`.
+#[derive(Clone)]
+struct MockLink {
+    state: Arc<Mutex<LinkState>>,
+    caps: DeviceCapabilities,
+}
+
+impl MockLink {
+    fn new() -> MockLink {
+        MockLink {
+            state: Default::default(),
+            caps: caps(),
+        }
+    }
+
+    fn sent(&self) -> Vec<Packet> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    fn set_stalled(&self, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.stalled = stalled;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn set_failing(&self, failing: bool) {
+        self.state.lock().unwrap().failing = failing;
+    }
+}
+
+impl Stream for MockLink {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock().unwrap();
+        match state.received.pop_front() {
+            Some(packet) => Poll::Ready(Some(Ok(packet))),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Sink<Packet> for MockLink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if state.failing {
+            return Poll::Ready(Err(io::Error::other("link failed")));
+        }
+        if state.stalled {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Packet) -> io::Result<()> {
+        self.state.lock().unwrap().sent.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncDevice for MockLink {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        &self.caps
+    }
+}
+
+fn caps() -> DeviceCapabilities {
+    let mut caps = common::caps(Medium::Ip, 1500);
+    caps.max_burst_size = Some(MAX_BURST_SIZE);
+    caps
+}
+
+fn bond(mode: BondMode, links: &[MockLink]) -> Bond {
+    links
+        .iter()
+        .fold(Bond::builder(caps()).mode(mode), |builder, link| {
+            builder.link(link.clone())
+        })
+        .build()
+        .unwrap()
+}
+
+/// An IPv4 UDP packet from port `src_port`, tagged with `tag` in its payload.
+fn udp(src_port: u16, tag: u8) -> Packet {
+    let mut packet = vec![0; 28];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&28u16.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    packet[20..22].copy_from_slice(&src_port.to_be_bytes());
+    packet[22..24].copy_from_slice(&53u16.to_be_bytes());
+    packet.push(tag);
+    Packet::from(&packet[..])
+}
+
+fn tags(packets: &[Packet]) -> Vec<u8> {
+    packets.iter().map(|packet| packet[28]).collect()
+}
+
+fn send_batch(bond: &mut Bond, packets: &mut VecDeque<Packet>) -> Poll<io::Result<()>> {
+    bond.poll_send_batch(
+        &mut Context::from_waker(futures::task::noop_waker_ref()),
+        packets,
+    )
+}
+
+fn send(bond: &mut Bond, packets: impl IntoIterator<Item = Packet>) {
+    let mut packets = packets.into_iter().collect();
+    assert!(matches!(
+        send_batch(bond, &mut packets),
+        Poll::Ready(Ok(()))
+    ));
+}
+
+fn poll_next(bond: &mut Bond) -> Poll<Option<io::Result<Packet>>> {
+    Pin::new(bond).poll_next(&mut Context::from_waker(futures::task::noop_waker_ref()))
+}
+
+#[test]
+fn round_robin() {
+    let links = [MockLink::new(), MockLink::new()];
+    let mut bond = bond(BondMode::RoundRobin, &links);
+
+    send(&mut bond, (0..6).map(|tag| udp(1000, tag)));
+    assert_eq!(tags(&links[0].sent()), [0, 2, 4]);
+    assert_eq!(tags(&links[1].sent()), [1, 3, 5]);
+
+    // A link that is down is skipped.
+    bond.handle().set_up(0, false);
+    send(&mut bond, (6..8).map(|tag| udp(1000, tag)));
+    assert_eq!(tags(&links[1].sent()), [1, 3, 5, 6, 7]);
+}
+
+#[test]
+fn active_backup_failover() {
+    let links = [MockLink::new(), MockLink::new()];
+    let mut bond = bond(BondMode::ActiveBackup, &links);
+    let handle = bond.handle();
+
+    send(&mut bond, [udp(1000, 0)]);
+    assert_eq!(handle.active(), Some(0));
+
+    // Sending on the active link fails, so the next packets use the backup.
+    links[0].set_failing(true);
+    send(&mut bond, [udp(1000, 1)]);
+    assert!(!handle.is_up(0));
+    assert_eq!(handle.active(), Some(1));
+    send(&mut bond, [udp(1000, 2), udp(1000, 3)]);
+    assert_eq!(tags(&links[0].sent()), [0]);
+    assert_eq!(tags(&links[1].sent()), [2, 3]);
+    // The packet queued on the failed link is dropped.
+    assert_eq!(bond.dropped(), 1);
+
+    links[0].set_failing(false);
+    handle.set_up(0, true);
+    send(&mut bond, [udp(1000, 4)]);
+    assert_eq!(tags(&links[0].sent()), [0, 4]);
+
+    // Without any link up, packets are dropped.
+    handle.set_up(0, false);
+    handle.set_up(1, false);
+    send(&mut bond, [udp(1000, 5)]);
+    assert_eq!(bond.dropped(), 2);
+}
+
+#[test]
+fn hash_sticks_to_flow() {
+    let links = [MockLink::new(), MockLink::new()];
+    let mut bond = bond(BondMode::Hash, &links);
+
+    for round in 0..3 {
+        send(&mut bond, (0..16).map(|flow| udp(1000 + flow, round)));
+    }
+    let ports = |link: &MockLink| -> Vec<u16> {
+        link.sent()
+            .iter()
+            .map(|packet| u16::from_be_bytes([packet[20], packet[21]]))
+            .collect()
+    };
+    let (ports0, ports1) = (ports(&links[0]), ports(&links[1]));
+    assert!(!ports0.is_empty() && !ports1.is_empty());
+    assert_eq!(ports0.len() + ports1.len(), 48);
+    // Every packet of a flow went to the same link.
+    assert!(ports0.iter().all(|port| !ports1.contains(port)));
+}
+
+#[test]
+fn stalled_link_queue_is_capped() {
+    let links = [MockLink::new(), MockLink::new()];
+    let mut bond = bond(BondMode::RoundRobin, &links);
+    links[0].set_stalled(true);
+
+    let mut packets: VecDeque<Packet> = (0..20).map(|tag| udp(1000, tag)).collect();
+    assert!(send_batch(&mut bond, &mut packets).is_pending());
+    // The stalled link holds `MAX_BURST_SIZE` packets, the rest stays in the batch.
+    assert_eq!(tags(&links[1].sent()), [1, 3, 5, 7]);
+    assert_eq!(packets.len(), 20 - 2 * MAX_BURST_SIZE);
+    assert!(send_batch(&mut bond, &mut packets).is_pending());
+    assert_eq!(packets.len(), 20 - 2 * MAX_BURST_SIZE);
+
+    links[0].set_stalled(false);
+    assert!(matches!(
+        send_batch(&mut bond, &mut packets),
+        Poll::Ready(Ok(()))
+    ));
+    assert!(packets.is_empty());
+    let mut sent = [tags(&links[0].sent()), tags(&links[1].sent())].concat();
+    sent.sort();
+    assert_eq!(sent, (0..20).collect::<Vec<_>>());
+    assert_eq!(bond.dropped(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn health_check() {
+    let links = [MockLink::new(), MockLink::new()];
+    let healthy = Arc::new([AtomicBool::new(true), AtomicBool::new(true)]);
+    let check = healthy.clone();
+    let mut bond = links
+        .iter()
+        .fold(
+            Bond::builder(caps())
+                .mode(BondMode::ActiveBackup)
+                .health_check(Duration::from_secs(1), move |index| {
+                    check[index].load(Ordering::Relaxed)
+                }),
+            |builder, link| builder.link(link.clone()),
+        )
+        .build()
+        .unwrap();
+    let handle = bond.handle();
+
+    assert!(poll_next(&mut bond).is_pending());
+    healthy[0].store(false, Ordering::Relaxed);
+    assert!(handle.is_up(0));
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(poll_next(&mut bond).is_pending());
+    assert!(!handle.is_up(0));
+    assert_eq!(handle.active(), Some(1));
+
+    healthy[0].store(true, Ordering::Relaxed);
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(poll_next(&mut bond).is_pending());
+    assert!(handle.is_up(0));
+
+    // Packets received on any link are merged.
+    links[1]
+        .state
+        .lock()
+        .unwrap()
+        .received
+        .push_back(udp(1000, 7));
+    match poll_next(&mut bond) {
+        Poll::Ready(Some(Ok(packet))) => assert_eq!(packet[28], 7),
+        other => panic!("unexpected {:?}", other),
+    }
+}
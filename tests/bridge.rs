@@ -0,0 +1,163 @@
+mod common;
+
+use smoltcp::socket::tcp;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
+use tokio_smoltcp::{
+    Net,
+    bridge::{Bridge, BridgeConfig},
+};
+
+const RESOLVED: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+/// A guest `Net` behind a `Bridge`, which lets it reach every port but
+/// `denied`.
+fn bridged(dns: SocketAddr, denied: u16) -> (Bridge, Net) {
+    let (a, b) = common::link();
+    let mut bridge_config = BridgeConfig::new();
+    bridge_config.dns = Some(("10.0.2.3".parse().unwrap(), dns));
+    bridge_config.policy = Some(Box::new(move |_, addr| addr.port() != denied));
+    let bridge = Bridge::new(a, common::config("10.0.2.2/24"), bridge_config);
+
+    let mut guest_config = common::config("10.0.2.15/24");
+    guest_config.gateway = vec!["10.0.2.2".parse().unwrap()];
+    guest_config.dns = Some("10.0.2.3:53".parse().unwrap());
+    let guest = Net::new(b, guest_config);
+    (bridge, guest)
+}
+
+/// Answers every query with `RESOLVED`.
+async fn dns_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let mut reply = buf[..len].to_vec();
+            // A response, recursion available, one question and one answer.
+            reply[2..4].copy_from_slice(&[0x81, 0x80]);
+            reply[6..8].copy_from_slice(&1u16.to_be_bytes());
+            // The name of the question, type A, class IN, TTL, address.
+            reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            reply.extend_from_slice(&RESOLVED.octets());
+            let _ = socket.send_to(&reply, peer).await;
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn proxy_tcp() {
+    let (_bridge, guest) = bridged(dns_server().await, 0);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let len = data.len();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    let mut stream = guest.tcp_connect(addr).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    let mut echoed = vec![0; len];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, data);
+}
+
+#[tokio::test]
+async fn proxy_udp() {
+    let (_bridge, guest) = bridged(dns_server().await, 0);
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        while let Ok((len, peer)) = server.recv_from(&mut buf).await {
+            let _ = server.send_to(&buf[..len], peer).await;
+        }
+    });
+
+    // The first datagram of the flow opens it, and must not be lost.
+    let socket = guest.udp_bind("0.0.0.0:0".parse().unwrap()).await.unwrap();
+    let mut buf = [0; 1500];
+    for i in 0..3u8 {
+        socket.send_to(&[i; 100], addr).await.unwrap();
+        let (len, from) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .expect("no reply")
+            .unwrap();
+        assert_eq!(from, addr);
+        assert_eq!(buf[..len], [i; 100]);
+    }
+}
+
+#[tokio::test]
+async fn forward_dns() {
+    let (_bridge, guest) = bridged(dns_server().await, 0);
+    let addrs = guest.lookup("example.com").await.unwrap();
+    assert_eq!(addrs, [RESOLVED]);
+}
+
+#[tokio::test]
+async fn policy_denies() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = UdpSocket::bind(addr).await.unwrap();
+    let (_bridge, guest) = bridged(dns_server().await, addr.port());
+
+    let err = guest
+        .tcp_connect(addr)
+        .await
+        .err()
+        .expect("connection allowed");
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+    let socket = guest.udp_bind("0.0.0.0:0".parse().unwrap()).await.unwrap();
+    socket.send_to(b"denied", addr).await.unwrap();
+    let mut buf = [0; 16];
+    let received = tokio::time::timeout(Duration::from_millis(200), server.recv_from(&mut buf));
+    assert!(received.await.is_err(), "datagram proxied");
+}
+
+#[tokio::test]
+async fn host_refuses() {
+    let (_bridge, guest) = bridged(dns_server().await, 0);
+    // Nothing listens on the port once the listener is dropped.
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    // The guest is accepted before the host connects, then reset.
+    let mut stream = guest.tcp_connect(addr).await.unwrap();
+    common::until(|| stream.info().state == tcp::State::Closed).await;
+    let err = stream.write(b"refused").await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[tokio::test]
+async fn broadcasts_not_proxied() {
+    let (bridge, guest) = bridged(dns_server().await, 0);
+    let socket = guest.udp_bind("0.0.0.0:0".parse().unwrap()).await.unwrap();
+    for addr in ["255.255.255.255:67", "10.0.2.255:137", "224.0.0.251:5353"] {
+        socket
+            .send_to(b"everyone", addr.parse().unwrap())
+            .await
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(guest.stats().tx_packets >= 3);
+    // No socket was bound to proxy them.
+    let sockets = bridge.net().sockets();
+    assert!(sockets.is_empty(), "{sockets:?}");
+}
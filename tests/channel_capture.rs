@@ -0,0 +1,94 @@
+use futures::{SinkExt, StreamExt, future};
+use smoltcp::phy::DeviceCapabilities;
+use std::{io, sync::atomic::Ordering, time::Duration};
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    time::timeout,
+};
+use tokio_smoltcp::device::{ChannelCapture, OverflowPolicy, Packet};
+
+/// A `ChannelCapture` whose channels are handed to the test instead of tasks.
+async fn capture(
+    capacity: usize,
+    overflow: OverflowPolicy,
+) -> (
+    ChannelCapture,
+    Sender<io::Result<Vec<u8>>>,
+    Receiver<Vec<u8>>,
+) {
+    let mut caps = DeviceCapabilities::default();
+    caps.max_transmission_unit = 1500;
+    let (recv_tx, recv_rx) = oneshot::channel();
+    let (send_tx, send_rx) = oneshot::channel();
+    let capture = ChannelCapture::builder(caps)
+        .capacity(capacity)
+        .overflow(overflow)
+        .spawn_tasks(
+            move |tx| {
+                recv_tx.send(tx).unwrap();
+                future::pending()
+            },
+            move |rx| {
+                send_tx.send(rx).unwrap();
+                future::pending()
+            },
+        );
+    (capture, recv_rx.await.unwrap(), send_rx.await.unwrap())
+}
+
+fn packet(tag: u8) -> Packet {
+    Packet::from(&[tag; 10][..])
+}
+
+#[tokio::test]
+async fn round_trip() {
+    let (mut capture, tx, mut rx) = capture(2, OverflowPolicy::Block).await;
+
+    tx.send(Ok(vec![1; 10])).await.unwrap();
+    assert_eq!(capture.next().await.unwrap().unwrap(), packet(1));
+
+    // Filling the channel doesn't block, flushing reserves no slot.
+    for tag in [2, 3] {
+        timeout(Duration::from_secs(1), capture.send(packet(tag)))
+            .await
+            .expect("send blocked")
+            .unwrap();
+    }
+    assert_eq!(rx.recv().await.unwrap(), [2; 10]);
+    assert_eq!(rx.recv().await.unwrap(), [3; 10]);
+    assert_eq!(capture.dropped(), 0);
+}
+
+#[tokio::test]
+async fn drop_oldest() {
+    let (mut capture, _tx, mut rx) = capture(2, OverflowPolicy::DropOldest).await;
+    let dropped = capture.drop_counter();
+
+    // Two packets fill the channel, two wait in the queue, the fifth pushes
+    // the third out.
+    for tag in 1..=5 {
+        capture.send(packet(tag)).await.unwrap();
+    }
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+    let mut received = Vec::new();
+    for _ in 0..4 {
+        received.push(rx.recv().await.unwrap()[0]);
+        capture.flush().await.unwrap();
+    }
+    assert_eq!(received, [1, 2, 4, 5]);
+    assert_eq!(capture.dropped(), 1);
+}
+
+#[tokio::test]
+async fn task_exit() {
+    let mut caps = DeviceCapabilities::default();
+    caps.max_transmission_unit = 1500;
+    let mut capture =
+        ChannelCapture::builder(caps).spawn_tasks(|_tx| future::pending(), |_rx| async {});
+    let err = capture.next().await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
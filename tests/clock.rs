@@ -0,0 +1,129 @@
+mod common;
+
+use futures::{Sink, Stream};
+use smoltcp::{phy::DeviceCapabilities, time::Instant};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_smoltcp::{
+    Clock, Net, VirtualClock,
+    device::{AsyncDevice, Framed, Packet},
+};
+
+/// A link losing the first `lost` TCP SYNs it sends.
+struct LossyLink {
+    inner: Framed<DuplexStream>,
+    lost: Arc<AtomicUsize>,
+}
+
+fn is_syn(packet: &[u8]) -> bool {
+    let Some(&ihl) = packet.first() else {
+        return false;
+    };
+    let header_len = usize::from(ihl & 0x0f) * 4;
+    packet.get(9) == Some(&6) && packet.get(header_len + 13).is_some_and(|f| f & 0x02 != 0)
+}
+
+impl Stream for LossyLink {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Sink<Packet> for LossyLink {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> io::Result<()> {
+        if is_syn(&item)
+            && self
+                .lost
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |lost| {
+                    lost.checked_sub(1)
+                })
+                .is_ok()
+        {
+            return Ok(());
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl AsyncDevice for LossyLink {
+    fn capabilities(&self) -> &DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+fn net(link: LossyLink, ip: &str, clock: &VirtualClock) -> Net {
+    let mut config = common::config(ip);
+    config.clock = Arc::new(clock.clone());
+    Net::new(link, config)
+}
+
+#[tokio::test]
+async fn syn_retransmit() {
+    let (a, b) = common::link();
+    let clock = VirtualClock::new();
+    let lost = Arc::new(AtomicUsize::new(1));
+    let link = |inner| LossyLink {
+        inner,
+        lost: lost.clone(),
+    };
+    let client = net(link(a), "10.0.0.1/24", &clock);
+    let server = net(link(b), "10.0.0.2/24", &clock);
+
+    let mut listener = server
+        .tcp_bind("10.0.0.2:80".parse().unwrap())
+        .await
+        .unwrap();
+    let echo = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream
+    });
+    let connect = tokio::spawn(async move {
+        let mut stream = client.tcp_connect("10.0.0.2:80".parse().unwrap()).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        io::Result::Ok(buf)
+    });
+
+    // Let both stacks settle, then jump to the next timer, the retransmission
+    // of the lost SYN.
+    while !connect.is_finished() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if let Some(deadline) = clock.next_deadline() {
+            clock.advance_to(deadline);
+        }
+    }
+    assert_eq!(&connect.await.unwrap().unwrap(), b"hello");
+    echo.await.unwrap();
+    assert_eq!(lost.load(Ordering::Relaxed), 0);
+    // The connection waited for the retransmission timer.
+    assert!(clock.now() > Instant::ZERO);
+}
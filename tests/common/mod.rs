@@ -0,0 +1,81 @@
+//! Helpers shared by the integration tests, each of which uses a part of them.
+#![allow(dead_code)]
+
+use smoltcp::{
+    iface::Config,
+    phy::{DeviceCapabilities, Medium},
+    wire::{EthernetAddress, HardwareAddress},
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::io::{DuplexStream, duplex};
+use tokio_smoltcp::{
+    Net, NetConfig,
+    device::{Framed, Framing},
+};
+
+pub fn caps(medium: Medium, mtu: usize) -> DeviceCapabilities {
+    let mut caps = DeviceCapabilities::default();
+    caps.medium = medium;
+    caps.max_transmission_unit = mtu;
+    caps
+}
+
+/// The config of a `Net` at `ip` on an IP device, without gateway.
+pub fn config(ip: &str) -> NetConfig {
+    NetConfig::new(
+        Config::new(HardwareAddress::Ip),
+        ip.parse().unwrap(),
+        vec![],
+    )
+}
+
+/// The config of a `Net` at `ip` on an Ethernet device, without gateway.
+pub fn ethernet_config(mac: [u8; 6], ip: &str) -> NetConfig {
+    NetConfig::new(
+        Config::new(HardwareAddress::Ethernet(EthernetAddress(mac))),
+        ip.parse().unwrap(),
+        vec![],
+    )
+}
+
+/// The two ends of an in-memory link between IP devices.
+pub fn link() -> (Framed<DuplexStream>, Framed<DuplexStream>) {
+    let (a, b) = duplex(1 << 20);
+    let caps = caps(Medium::Ip, 1500);
+    (
+        Framed::new(a, Framing::LengthU16, caps.clone()),
+        Framed::new(b, Framing::LengthU16, caps),
+    )
+}
+
+/// Two `Net`s at 10.0.0.1 and 10.0.0.2 on a link.
+pub fn nets() -> (Net, Net) {
+    nets_with(|_| {})
+}
+
+/// Like `nets`, with the config of both changed by `configure`.
+pub fn nets_with(configure: impl Fn(&mut NetConfig)) -> (Net, Net) {
+    let (a, b) = link();
+    let net = |device, ip| {
+        let mut config = config(ip);
+        configure(&mut config);
+        Net::new(device, config)
+    };
+    (net(a, "10.0.0.1/24"), net(b, "10.0.0.2/24"))
+}
+
+pub fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+/// Waits until `f` holds, checking it every few milliseconds, for at most 5
+/// seconds.
+pub async fn until(mut f: impl FnMut() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !f() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition never held")
+}
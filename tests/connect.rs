@@ -0,0 +1,95 @@
+mod common;
+
+use common::addr;
+use futures::{StreamExt, future::poll_fn};
+use smoltcp::{
+    socket::tcp,
+    time::{self, Instant},
+};
+use std::{io, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, time::timeout};
+use tokio_smoltcp::{Clock, Net, VirtualClock};
+
+/// The TCP flags of an IPv4 packet.
+fn tcp_flags(packet: &[u8]) -> u8 {
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    assert_eq!(packet[9], 6, "not TCP");
+    packet[header_len + 13]
+}
+
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+
+#[tokio::test]
+async fn timed_out() {
+    // Nothing answers on the other end of the link.
+    let (a, _b) = common::link();
+    let clock = VirtualClock::new();
+    let mut config = common::config("10.0.0.1/24");
+    config.clock = Arc::new(clock.clone());
+    config.connect_timeout = Some(time::Duration::from_secs(10));
+    let net = Net::new(a, config);
+
+    let connect = tokio::spawn(async move { net.tcp_connect(addr("10.0.0.2:80")).await });
+    // Jump from one retransmission of the SYN to the next.
+    while !connect.is_finished() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if let Some(deadline) = clock.next_deadline() {
+            clock.advance_to(deadline);
+        }
+    }
+    let err = connect.await.unwrap().err().expect("connected to no one");
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(clock.now() >= Instant::from_secs(10));
+}
+
+#[tokio::test]
+async fn refused() {
+    let (client, _server) = common::nets();
+    let err = timeout(
+        Duration::from_secs(5),
+        client.tcp_connect(addr("10.0.0.2:80")),
+    )
+    .await
+    .expect("connect hangs")
+    .err()
+    .expect("connected without a listener");
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn cancelled_resets() {
+    let (a, mut b) = common::link();
+    let net = Net::new(a, common::config("10.0.0.1/24"));
+
+    // The connect is dropped once its SYN is out, unanswered.
+    let connect = net.tcp_connect(addr("10.0.0.2:80"));
+    assert!(timeout(Duration::from_millis(100), connect).await.is_err());
+    let syn = b.next().await.unwrap().unwrap();
+    assert_eq!(tcp_flags(&syn) & SYN, SYN);
+    let rst = timeout(Duration::from_secs(5), b.next())
+        .await
+        .expect("no reset")
+        .unwrap()
+        .unwrap();
+    assert_eq!(tcp_flags(&rst) & RST, RST);
+    assert!(net.sockets().is_empty());
+}
+
+#[tokio::test]
+async fn connected_until_reset() {
+    let (client, server) = common::nets();
+    let mut listener = server.tcp_bind(addr("10.0.0.2:80")).await.unwrap();
+    let (stream, accepted) =
+        tokio::join!(client.tcp_connect(addr("10.0.0.2:80")), listener.accept());
+    let mut stream = stream.unwrap();
+    poll_fn(|cx| stream.poll_connected(cx)).await.unwrap();
+
+    // Without the socket of the peer, the next segment is answered with a
+    // reset.
+    drop(accepted);
+    stream.write_all(b"ping").await.unwrap();
+    common::until(|| stream.info().state == tcp::State::Closed).await;
+    let err = poll_fn(|cx| stream.poll_connected(cx)).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::nets;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+fn server_addr(port: u16) -> SocketAddr {
+    SocketAddr::new("10.0.0.2".parse().unwrap(), port)
+}
+
+#[tokio::test]
+async fn tcp_half_close() {
+    let (net, server) = nets();
+    let mut listener = server.tcp_bind(server_addr(8080)).await.unwrap();
+    let forward = net
+        .forward_tcp("127.0.0.1:0".parse().unwrap(), server_addr(8080))
+        .await
+        .unwrap();
+    // Answers with the length of the request, once the client is done sending.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        stream
+            .write_all(&(request.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let mut client = TcpStream::connect(forward.local_addr()).await.unwrap();
+    client.write_all(&[7; 100_000]).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), client.read_to_end(&mut response))
+        .await
+        .expect("no response")
+        .unwrap();
+    assert_eq!(response, 100_000u32.to_be_bytes());
+}
+
+async fn echo_once(client: &mut TcpStream, byte: u8) -> bool {
+    if client.write_all(&[byte; 10]).await.is_err() {
+        return false;
+    }
+    let mut buf = [0; 10];
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf));
+    matches!(read.await, Ok(Ok(_))) && buf == [byte; 10]
+}
+
+#[tokio::test]
+async fn tcp_connection_limit() {
+    let (net, server) = nets();
+    let mut listener = server.tcp_bind(server_addr(8080)).await.unwrap();
+    let forward = net
+        .forward_tcp("127.0.0.1:0".parse().unwrap(), server_addr(8080))
+        .await
+        .unwrap();
+    forward.set_max_connections(1);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 10];
+                while stream.read_exact(&mut buf).await.is_ok() {
+                    stream.write_all(&buf).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let mut first = TcpStream::connect(forward.local_addr()).await.unwrap();
+    assert!(echo_once(&mut first, 1).await);
+    // Above the limit, the connection is closed as soon as accepted.
+    let mut second = TcpStream::connect(forward.local_addr()).await.unwrap();
+    assert!(!echo_once(&mut second, 2).await);
+    assert!(echo_once(&mut first, 3).await);
+
+    forward.set_max_connections(2);
+    let mut third = TcpStream::connect(forward.local_addr()).await.unwrap();
+    assert!(echo_once(&mut third, 4).await);
+}
+
+#[tokio::test]
+async fn udp_peers() {
+    let (net, server) = nets();
+    let socket = server.udp_bind(server_addr(5353)).await.unwrap();
+    let forward = net
+        .forward_udp("127.0.0.1:0".parse().unwrap(), server_addr(5353))
+        .await
+        .unwrap();
+    forward.set_max_connections(2);
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            socket.send_to(&buf[..len], peer).await.unwrap();
+        }
+    });
+
+    let mut peers = Vec::new();
+    for _ in 0..3 {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.connect(forward.local_addr()).await.unwrap();
+        peers.push(peer);
+    }
+    let mut buf = [0; 16];
+    for (i, peer) in peers.iter().enumerate() {
+        peer.send(&[i as u8; 16]).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(1), peer.recv(&mut buf)).await;
+        if i < 2 {
+            assert_eq!(reply.expect("no reply").unwrap(), 16);
+            assert_eq!(buf, [i as u8; 16]);
+        } else {
+            // The third peer is above the limit.
+            assert!(reply.is_err());
+        }
+    }
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use smoltcp::socket::tcp;
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use tokio_smoltcp::{BufferSize, Net, TcpStream};
+
+/// Two linked `Net`s whose TCP buffers, both the sockets' and the streams',
+/// are `tcp_size` bytes.
+fn nets(tcp_size: usize) -> (Net, Net) {
+    common::nets_with(|config| {
+        config.buffer_size = BufferSize {
+            tcp_rx_size: tcp_size,
+            tcp_tx_size: tcp_size,
+            ..Default::default()
+        }
+    })
+}
+
+/// Connects a stream of `client` to a stream of `server`.
+async fn pair(client: &Net, server: &Net) -> (TcpStream, TcpStream) {
+    let mut listener = server
+        .tcp_bind("10.0.0.2:80".parse().unwrap())
+        .await
+        .unwrap();
+    let (connected, accepted) = tokio::join!(
+        client.tcp_connect("10.0.0.2:80".parse().unwrap()),
+        listener.accept()
+    );
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn large_transfer_with_small_buffers() {
+    let (client, server) = nets(1024);
+    let (client, mut server) = pair(&client, &server).await;
+    let data = pattern(200_000);
+
+    let echo = tokio::spawn(async move {
+        let mut buf = [0; 4096];
+        loop {
+            let read = server.read(&mut buf).await.unwrap();
+            if read == 0 {
+                break server;
+            }
+            server.write_all(&buf[..read]).await.unwrap();
+        }
+    });
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let mut response = vec![0; data.len()];
+    let (written, read) = tokio::join!(
+        async {
+            writer.write_all(&data).await?;
+            writer.flush().await
+        },
+        timeout(Duration::from_secs(10), reader.read_exact(&mut response)),
+    );
+    written.unwrap();
+    read.expect("no response").unwrap();
+    assert!(response == data);
+
+    let client = reader.unsplit(writer);
+    let info = client.info();
+    assert_eq!(info.bytes_sent, data.len() as u64);
+    assert_eq!(info.bytes_received, data.len() as u64);
+    assert_eq!(info.send_queue, 0);
+    assert_eq!(info.recv_queue, 0);
+    drop(client);
+    echo.await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_after_buffered_data() {
+    let (client, server) = nets(1024);
+    let (mut client, mut server) = pair(&client, &server).await;
+    let data = pattern(50_000);
+
+    // The last bytes are still in the stream's buffer when it shuts down, the
+    // FIN must follow them.
+    let expected = data.clone();
+    let server = tokio::spawn(async move {
+        server.write_all(&data).await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    });
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(10), client.read_to_end(&mut response))
+        .await
+        .expect("no EOF")
+        .unwrap();
+    assert!(response == expected);
+    // Writing is still possible after the EOF, until the stream shuts down.
+    client.write_all(b"late").await.unwrap();
+    timeout(Duration::from_secs(10), client.shutdown())
+        .await
+        .expect("no close")
+        .unwrap();
+    assert_eq!(client.info().state, tcp::State::Closed);
+    let err = client.write(b"closed").await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    drop(server);
+}
+
+#[tokio::test]
+async fn peer_reset_with_buffered_data() {
+    let (client, server) = nets(8192);
+    let (mut client, mut server) = pair(&client, &server).await;
+
+    server.write_all(b"unread").await.unwrap();
+    server.flush().await.unwrap();
+    // Dropping the stream removes its socket, so the next segment of the
+    // client is answered with a reset.
+    drop(server);
+    client.write_all(b"ping").await.unwrap();
+    common::until(|| client.info().state == tcp::State::Closed).await;
+
+    // What the stream already received is still read, then the EOF.
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"unread");
+    let err = client.write(b"pong").await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
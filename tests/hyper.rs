@@ -0,0 +1,55 @@
+mod common;
+
+use http_body_util::{BodyExt, Full};
+use hyper::{Request, Response, body::Bytes, server::conn::http1, service::service_fn};
+use hyper_util::{
+    client::legacy::Client,
+    rt::{TokioExecutor, TokioIo},
+};
+use std::{convert::Infallible, sync::Arc};
+use tokio_smoltcp::connector::NetConnector;
+
+/// Answers with the method and path of the request, followed by its body.
+async fn echo(
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let head = format!("{} {}\n", request.method(), request.uri().path());
+    let body = request.into_body().collect().await.unwrap().to_bytes();
+    Ok(Response::new(Full::new(
+        [head.as_bytes(), &body].concat().into(),
+    )))
+}
+
+#[tokio::test]
+async fn client_and_server() {
+    let (client, server) = common::nets();
+    let mut listener = server
+        .tcp_bind("10.0.0.2:80".parse().unwrap())
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(
+                http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(echo)),
+            );
+        }
+    });
+
+    let client = Client::builder(TokioExecutor::new()).build(NetConnector::new(Arc::new(client)));
+    for i in 0..3 {
+        let request = Request::post("http://10.0.0.2/echo")
+            .body(Full::new(Bytes::from(format!("request {}", i))))
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert!(response.status().is_success());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, format!("POST /echo\nrequest {}", i));
+    }
+
+    let response = client
+        .get("http://10.0.0.2/".parse().unwrap())
+        .await
+        .unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "GET /\n");
+}
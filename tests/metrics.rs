@@ -0,0 +1,105 @@
+mod common;
+
+use common::addr;
+use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+use std::{collections::HashMap, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The names of the `Net`s, which default to their address.
+const CLIENT: &str = "10.0.0.1/24";
+const SERVER: &str = "10.0.0.2/24";
+
+/// The values exported by `Net` name, metric name and value of their other
+/// label, if any. Taking a snapshot resets the values.
+fn snapshot(snapshotter: &Snapshotter) -> HashMap<(String, String, String), f64> {
+    let mut values = HashMap::new();
+    for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+        let key = key.key();
+        let label = |net: bool| {
+            key.labels()
+                .find(|label| (label.key() == "net") == net)
+                .map(|label| label.value().to_owned())
+                .unwrap_or_default()
+        };
+        let value = match value {
+            DebugValue::Counter(value) => value as f64,
+            DebugValue::Gauge(value) => value.0,
+            DebugValue::Histogram(_) => continue,
+        };
+        values.insert((label(true), key.name().to_owned(), label(false)), value);
+    }
+    values
+}
+
+#[tokio::test]
+async fn exported_after_traffic() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    recorder.install().unwrap();
+
+    let (client, server) = common::nets();
+    let server_addr = addr("10.0.0.2:80");
+    let mut listener = server.tcp_bind(server_addr).await.unwrap();
+    let _udp = server.udp_bind(addr("10.0.0.2:53")).await.unwrap();
+    let (connected, accepted) = tokio::join!(client.tcp_connect(server_addr), listener.accept());
+    let mut stream = connected.unwrap();
+    let (mut accepted, _) = accepted.unwrap();
+    stream.write_all(&[1; 3000]).await.unwrap();
+    // The server leaves 2000 bytes unread in its stream.
+    let mut buf = [0; 1000];
+    accepted.read_exact(&mut buf).await.unwrap();
+
+    // The values of a burst are exported at most a second later.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let values = snapshot(&snapshotter);
+    let value = |net: &str, name: &str, label: &str| {
+        let key = (net.to_owned(), name.to_owned(), label.to_owned());
+        *values
+            .get(&key)
+            .unwrap_or_else(|| panic!("{key:?} not exported: {values:?}"))
+    };
+
+    let stats = server.stats();
+    let rx_bytes = value(SERVER, "tokio_smoltcp_rx_bytes_total", "");
+    assert!(rx_bytes >= 3000.0 && rx_bytes <= stats.rx_bytes as f64);
+    let rx_packets = value(SERVER, "tokio_smoltcp_rx_packets_total", "");
+    assert!(rx_packets >= 4.0 && rx_packets <= stats.rx_packets as f64);
+    assert!(value(SERVER, "tokio_smoltcp_tx_packets_total", "") >= 1.0);
+    assert!(value(SERVER, "tokio_smoltcp_tx_bytes_total", "") > 0.0);
+    assert_eq!(value(SERVER, "tokio_smoltcp_rx_errors_total", ""), 0.0);
+    assert_eq!(value(SERVER, "tokio_smoltcp_rx_filtered_total", ""), 0.0);
+    assert_eq!(value(SERVER, "tokio_smoltcp_tx_filtered_total", ""), 0.0);
+    assert!(value(SERVER, "tokio_smoltcp_reactor_polls_total", "") > 0.0);
+    assert!(value(SERVER, "tokio_smoltcp_reactor_wakeups_total", "") > 0.0);
+
+    // The listener and the accepted stream.
+    assert_eq!(value(SERVER, "tokio_smoltcp_tcp_sockets", "LISTEN"), 1.0);
+    assert_eq!(
+        value(SERVER, "tokio_smoltcp_tcp_sockets", "ESTABLISHED"),
+        1.0
+    );
+    assert_eq!(value(SERVER, "tokio_smoltcp_tcp_sockets", "CLOSED"), 0.0);
+    assert_eq!(value(SERVER, "tokio_smoltcp_udp_sockets", ""), 1.0);
+    assert_eq!(value(SERVER, "tokio_smoltcp_raw_sockets", ""), 0.0);
+    assert_eq!(
+        value(SERVER, "tokio_smoltcp_socket_send_queue_bytes", "tcp"),
+        0.0
+    );
+    // Including the bytes left in the stream.
+    assert_eq!(
+        value(SERVER, "tokio_smoltcp_socket_recv_queue_bytes", "tcp"),
+        2000.0
+    );
+    assert_eq!(
+        value(SERVER, "tokio_smoltcp_socket_recv_queue_bytes", "udp"),
+        0.0
+    );
+
+    // Each `Net` has its own label.
+    assert!(value(CLIENT, "tokio_smoltcp_tx_bytes_total", "") >= 3000.0);
+    assert_eq!(
+        value(CLIENT, "tokio_smoltcp_tcp_sockets", "ESTABLISHED"),
+        1.0
+    );
+    assert_eq!(value(CLIENT, "tokio_smoltcp_udp_sockets", ""), 0.0);
+}
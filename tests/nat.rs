@@ -0,0 +1,171 @@
+mod common;
+
+use common::addr;
+use smoltcp::{
+    phy::Medium,
+    wire::{IpAddress, IpProtocol},
+};
+use std::{
+    net::{SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+use tokio_smoltcp::{
+    Net, SocketInfo,
+    device::{Framed, Framing},
+    nat::{Nat, NatConfig, NatHandle},
+};
+
+/// An Ethernet `Net` at 10.0.0.2 behind a `Nat`, whose outer `Net` at
+/// 192.168.1.10 shares a link with a remote `Net` at 192.168.1.20.
+fn topology() -> (Net, Net, Net, NatHandle) {
+    let nat = Nat::new(NatConfig::new("192.168.1.10".parse().unwrap()));
+    let handle = nat.handle();
+    let (a, b) = common::link();
+    let mut config = common::config("192.168.1.10/24");
+    config.filter = Some(Box::new(nat.filter()));
+    let outer = Net::new(a, config);
+    let remote = Net::new(b, common::config("192.168.1.20/24"));
+
+    let (a, b) = duplex(1 << 20);
+    let ethernet_caps = common::caps(Medium::Ethernet, 1514);
+    let mut config = common::ethernet_config([0x02, 0, 0, 0, 0, 0x02], "10.0.0.2/24");
+    config.gateway = vec![IpAddress::v4(10, 0, 0, 1)];
+    let inner = Net::new(
+        Framed::new(a, Framing::LengthU16, ethernet_caps.clone()),
+        config,
+    );
+    tokio::spawn(nat.run(Framed::new(b, Framing::LengthU16, ethernet_caps), &outer));
+    (inner, outer, remote, handle)
+}
+
+fn outer_port(peer: SocketAddr) -> u16 {
+    assert_eq!(peer.ip(), addr("192.168.1.10:0").ip());
+    assert!(peer.port() >= 61000, "{peer}");
+    peer.port()
+}
+
+#[tokio::test]
+async fn tcp_from_inner() {
+    let (inner, _outer, remote, handle) = topology();
+    let mut listener = remote.tcp_bind(addr("192.168.1.20:80")).await.unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        (stream, peer)
+    });
+
+    let mut stream = inner.tcp_connect(addr("192.168.1.20:80")).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("no echo")
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // The remote sees the outer address.
+    let (_stream, peer) = server.await.unwrap();
+    let connections = handle.connections();
+    assert_eq!(connections.len(), 1);
+    let connection = &connections[0];
+    assert_eq!(connection.protocol, IpProtocol::Tcp);
+    assert_eq!(
+        SocketAddr::V4(connection.inner),
+        stream.local_addr().unwrap()
+    );
+    assert_eq!(connection.outer_port, outer_port(peer));
+    assert_eq!(
+        connection.remote,
+        "192.168.1.20:80".parse::<SocketAddrV4>().unwrap()
+    );
+    assert!(!connection.forwarded);
+}
+
+#[tokio::test]
+async fn udp_from_inner() {
+    let (inner, _outer, remote, handle) = topology();
+    let server = remote.udp_bind(addr("192.168.1.20:53")).await.unwrap();
+    let client = inner.udp_bind(addr("0.0.0.0:0")).await.unwrap();
+
+    client
+        .send_to(b"query", addr("192.168.1.20:53"))
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let (len, peer) = tokio::time::timeout(Duration::from_secs(5), server.recv_from(&mut buf))
+        .await
+        .expect("no query")
+        .unwrap();
+    assert_eq!(&buf[..len], b"query");
+    let port = outer_port(peer);
+    server.send_to(b"answer", peer).await.unwrap();
+    let (len, from) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .expect("no answer")
+        .unwrap();
+    assert_eq!(&buf[..len], b"answer");
+    assert_eq!(from, addr("192.168.1.20:53"));
+    assert_eq!(handle.connections()[0].outer_port, port);
+}
+
+#[tokio::test]
+async fn forward_and_outer_sockets() {
+    let (inner, outer, remote, handle) = topology();
+    handle
+        .add_forward(IpProtocol::Tcp, 8080, "10.0.0.2:80".parse().unwrap())
+        .unwrap();
+    let mut forwarded = inner.tcp_bind(addr("10.0.0.2:80")).await.unwrap();
+    let mut own = outer.tcp_bind(addr("192.168.1.10:22")).await.unwrap();
+    let echo = |mut stream: tokio_smoltcp::TcpStream| async move {
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream
+    };
+    let inner_server = tokio::spawn(async move {
+        let (stream, peer) = forwarded.accept().await.unwrap();
+        (echo(stream).await, peer)
+    });
+    let outer_server = tokio::spawn(async move {
+        let (stream, _) = own.accept().await.unwrap();
+        echo(stream).await
+    });
+
+    // The forward reaches the inner host, the other ports the outer `Net`.
+    for port in [8080, 22] {
+        let mut stream = remote
+            .tcp_connect(SocketAddr::new(addr("192.168.1.10:0").ip(), port))
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .expect("no echo")
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    // The inner host sees the remote address.
+    let (_stream, peer) = inner_server.await.unwrap();
+    assert_eq!(peer.ip(), addr("192.168.1.20:0").ip());
+    outer_server.await.unwrap();
+    let connections = handle.connections();
+    assert_eq!(connections.len(), 1);
+    assert!(connections[0].forwarded);
+    assert_eq!(connections[0].outer_port, 8080);
+
+    // The raw sockets of the `Nat` keep nothing of the outer traffic.
+    let raw: Vec<_> = outer
+        .sockets()
+        .into_iter()
+        .filter_map(|socket| match socket {
+            SocketInfo::Raw { recv_queue, .. } => Some(recv_queue),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(raw, [0, 0, 0]);
+}
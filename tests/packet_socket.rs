@@ -0,0 +1,80 @@
+//! Needs `CAP_NET_ADMIN` to create a veth pair, run it in a network namespace
+//! with `unshare -rn cargo test --test packet_socket -- --ignored`.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use smoltcp::{phy::Medium, wire::EthernetAddress};
+use std::process::Command;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_smoltcp::{Net, device::PacketSocket};
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().unwrap();
+    assert!(status.success(), "ip {:?} failed", args);
+}
+
+/// A veth pair, deleted when dropped.
+struct Veth(&'static str, &'static str);
+
+impl Veth {
+    fn new(a: &'static str, b: &'static str) -> Veth {
+        ip(&["link", "add", a, "type", "veth", "peer", "name", b]);
+        let veth = Veth(a, b);
+        ip(&["link", "set", a, "up"]);
+        ip(&["link", "set", b, "up"]);
+        veth
+    }
+}
+
+impl Drop for Veth {
+    fn drop(&mut self) {
+        let _ = Command::new("ip").args(["link", "del", self.0]).status();
+    }
+}
+
+fn net(interface: &str, mac: [u8; 6], ip: &str) -> Net {
+    let caps = common::caps(Medium::Ethernet, 1514);
+    let device = PacketSocket::builder(interface, caps)
+        .filter_mac(EthernetAddress(mac))
+        .open()
+        .unwrap();
+    Net::new(device, common::ethernet_config(mac, ip))
+}
+
+#[tokio::test]
+#[ignore = "needs CAP_NET_ADMIN"]
+async fn tcp_over_veth() {
+    let veth = Veth::new("tsmoltcp0", "tsmoltcp1");
+    let client = net(veth.0, [2, 0, 0, 0, 0, 1], "10.0.0.1/24");
+    let server = net(veth.1, [2, 0, 0, 0, 0, 2], "10.0.0.2/24");
+
+    let mut listener = server
+        .tcp_bind("10.0.0.2:8080".parse().unwrap())
+        .await
+        .unwrap();
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+    let len = data.len();
+    let echo = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream
+    });
+
+    let mut stream = client
+        .tcp_connect("10.0.0.2:8080".parse().unwrap())
+        .await
+        .unwrap();
+    let (mut reader, mut writer) = tokio::io::split(&mut stream);
+    let write = async { writer.write_all(&data).await.unwrap() };
+    let read = async {
+        let mut echoed = vec![0; len];
+        reader.read_exact(&mut echoed).await.unwrap();
+        echoed
+    };
+    let ((), echoed) = tokio::join!(write, read);
+    assert!(echoed == data);
+    echo.await.unwrap();
+}
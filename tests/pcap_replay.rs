@@ -0,0 +1,77 @@
+mod common;
+
+use smoltcp::{
+    phy::{DeviceCapabilities, Medium},
+    wire::{Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, TcpPacket},
+};
+use std::io;
+use tokio_smoltcp::{Net, device::PcapReplay};
+
+/// An ICMP echo request and a TCP SYN to port 80, from 10.0.0.2 to 10.0.0.1.
+const ECHO_SYN: &[u8] = include_bytes!("data/echo_syn.pcap");
+
+fn caps() -> DeviceCapabilities {
+    common::caps(Medium::Ip, 1500)
+}
+
+#[tokio::test]
+async fn replay_into_net() {
+    let replay = PcapReplay::new(ECHO_SYN, caps()).unwrap();
+    let handle = replay.handle();
+    let net = Net::new(replay, common::config("10.0.0.1/24"));
+    let _listener = net.tcp_bind("10.0.0.1:80".parse().unwrap()).await.unwrap();
+
+    handle.finished().await;
+    handle.wait_transmitted(2).await;
+    assert_eq!(handle.replayed().len(), 2);
+
+    let transmitted = handle.transmitted();
+    let (mut reply, mut syn_ack) = (None, None);
+    for packet in &transmitted {
+        let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert_eq!(ip.src_addr().octets(), [10, 0, 0, 1]);
+        assert_eq!(ip.dst_addr().octets(), [10, 0, 0, 2]);
+        match ip.next_header() {
+            IpProtocol::Icmp => reply = Some(ip.payload().to_vec()),
+            IpProtocol::Tcp => syn_ack = Some(ip.payload().to_vec()),
+            other => panic!("unexpected protocol {}", other),
+        }
+    }
+
+    let reply = reply.expect("no echo reply");
+    let icmp = Icmpv4Packet::new_checked(&reply[..]).unwrap();
+    match Icmpv4Repr::parse(&icmp, &Default::default()).unwrap() {
+        Icmpv4Repr::EchoReply {
+            ident,
+            seq_no,
+            data,
+        } => {
+            assert_eq!((ident, seq_no), (0x1234, 1));
+            assert_eq!(data, b"tokio-smoltcp");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let syn_ack = syn_ack.expect("no SYN-ACK");
+    let tcp = TcpPacket::new_checked(&syn_ack[..]).unwrap();
+    assert!(tcp.syn() && tcp.ack());
+    assert_eq!((tcp.src_port(), tcp.dst_port()), (80, 40000));
+    assert_eq!(tcp.ack_number().0, 1001);
+}
+
+#[test]
+fn truncated_record_header() {
+    // The global header and the first record, then half a record header.
+    let first = 24 + 16 + 41;
+    let err = PcapReplay::new(&ECHO_SYN[..first + 8], caps())
+        .err()
+        .expect("truncated file loaded");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let err = PcapReplay::new(&ECHO_SYN[..ECHO_SYN.len() - 1], caps())
+        .err()
+        .expect("truncated file loaded");
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    assert!(PcapReplay::new(&ECHO_SYN[..first], caps()).is_ok());
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::timeout,
+};
+use tokio_smoltcp::socks5::{Socks5Config, Socks5Server};
+
+const NAME: &str = "echo.test";
+
+/// A `Net` behind a SOCKS5 server listening on the host, whose peer `Net` at
+/// 10.0.0.2 echoes on TCP and UDP port 7 and resolves `NAME` to itself.
+async fn proxy(auth: Option<(&str, &str)>) -> SocketAddr {
+    let (proxied, server) = common::nets_with(|config| {
+        config.dns = Some("10.0.0.2:53".parse().unwrap());
+    });
+
+    let mut listener = server
+        .tcp_bind("10.0.0.2:7".parse().unwrap())
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(len @ 1..) = stream.read(&mut buf).await {
+                    stream.write_all(&buf[..len]).await.unwrap();
+                }
+            });
+        }
+    });
+    let echo = server
+        .udp_bind("10.0.0.2:7".parse().unwrap())
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        while let Ok((len, peer)) = echo.recv_from(&mut buf).await {
+            let _ = echo.send_to(&buf[..len], peer).await;
+        }
+    });
+    let dns = server
+        .udp_bind("10.0.0.2:53".parse().unwrap())
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        // Keeps the `Net` alive with its sockets.
+        let _server = server;
+        let mut buf = [0; 512];
+        while let Ok((len, peer)) = dns.recv_from(&mut buf).await {
+            let mut reply = buf[..len].to_vec();
+            // A response, recursion available, one question and one answer.
+            reply[2..4].copy_from_slice(&[0x81, 0x80]);
+            reply[6..8].copy_from_slice(&1u16.to_be_bytes());
+            // The name of the question, type A, class IN, TTL, address.
+            reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 2]);
+            let _ = dns.send_to(&reply, peer).await;
+        }
+    });
+
+    let mut config = Socks5Config::new();
+    config.auth = auth.map(|(username, password)| (username.into(), password.into()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let socks5 = Socks5Server::new(Arc::new(proxied), config);
+    tokio::spawn(async move { socks5.serve(listener).await });
+    addr
+}
+
+/// Connects to the server without authentication.
+async fn connect(proxy: SocketAddr) -> TcpStream {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read::<2>(&mut client).await, [5, 0]);
+    client
+}
+
+async fn read<const N: usize>(client: &mut TcpStream) -> [u8; N] {
+    let mut buf = [0; N];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+        .await
+        .expect("no answer")
+        .unwrap();
+    buf
+}
+
+/// The address of a request, by IPv4 address or by domain name.
+fn target(by_name: bool, port: u16) -> Vec<u8> {
+    let mut target = if by_name {
+        [&[3, NAME.len() as u8], NAME.as_bytes()].concat()
+    } else {
+        vec![1, 10, 0, 0, 2]
+    };
+    target.extend_from_slice(&port.to_be_bytes());
+    target
+}
+
+/// Sends a `CONNECT` to the echo server and checks it echoes.
+async fn connect_and_echo(mut client: TcpStream, by_name: bool) {
+    client
+        .write_all(&[&[5, 1, 0][..], &target(by_name, 7)].concat())
+        .await
+        .unwrap();
+    let reply = read::<10>(&mut client).await;
+    // Succeeded, bound to the address of the proxied `Net`.
+    assert_eq!(reply[..8], [5, 0, 0, 1, 10, 0, 0, 1]);
+
+    client.write_all(b"hello").await.unwrap();
+    assert_eq!(&read::<5>(&mut client).await, b"hello");
+}
+
+#[tokio::test]
+async fn connect_by_ip() {
+    let proxy = proxy(None).await;
+    connect_and_echo(connect(proxy).await, false).await;
+}
+
+#[tokio::test]
+async fn connect_by_name() {
+    let proxy = proxy(None).await;
+    connect_and_echo(connect(proxy).await, true).await;
+}
+
+#[tokio::test]
+async fn connect_refused() {
+    let proxy = proxy(None).await;
+    let mut client = connect(proxy).await;
+    client
+        .write_all(&[&[5, 1, 0][..], &target(false, 8)].concat())
+        .await
+        .unwrap();
+    assert_eq!(read::<10>(&mut client).await[..2], [5, 5]);
+}
+
+#[tokio::test]
+async fn udp_associate() {
+    let proxy = proxy(None).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut control = connect(proxy).await;
+    let mut request = vec![5, 3, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&socket.local_addr().unwrap().port().to_be_bytes());
+    control.write_all(&request).await.unwrap();
+    let reply = read::<10>(&mut control).await;
+    assert_eq!(reply[..4], [5, 0, 0, 1]);
+    let relay = SocketAddr::from((
+        [reply[4], reply[5], reply[6], reply[7]],
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+
+    let mut buf = [0; 1500];
+    for by_name in [false, true, true] {
+        let datagram = [&[0, 0, 0][..], &target(by_name, 7), b"ping"].concat();
+        socket.send_to(&datagram, relay).await.unwrap();
+        let (len, from) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .expect("no reply")
+            .unwrap();
+        assert_eq!(from, relay);
+        // The reply comes from the address of the echo server.
+        assert_eq!(
+            buf[..len],
+            [&[0, 0, 0][..], &target(false, 7), b"ping"].concat()
+        );
+    }
+
+    // Closing the control connection ends the association.
+    drop(control);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    socket
+        .send_to(
+            &[&[0, 0, 0][..], &target(false, 7), b"ping"].concat(),
+            relay,
+        )
+        .await
+        .unwrap();
+    let reply = timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await;
+    assert!(!matches!(reply, Ok(Ok(_))), "association still open");
+}
+
+/// Authenticates with `username` and `password`, returns whether the server
+/// accepted them.
+async fn authenticate(client: &mut TcpStream, username: &str, password: &str) -> bool {
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    assert_eq!(read::<2>(client).await, [5, 2]);
+    let request = [
+        &[1, username.len() as u8],
+        username.as_bytes(),
+        &[password.len() as u8],
+        password.as_bytes(),
+    ]
+    .concat();
+    client.write_all(&request).await.unwrap();
+    match read::<2>(client).await {
+        [1, 0] => true,
+        [1, _] => false,
+        reply => panic!("unexpected reply {reply:?}"),
+    }
+}
+
+#[tokio::test]
+async fn username_password() {
+    let proxy = proxy(Some(("user", "secret"))).await;
+
+    for (username, password) in [("user", "wrong"), ("user", "secre"), ("resu", "secret")] {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        assert!(!authenticate(&mut client, username, password).await);
+        // The server hangs up.
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), client.read(&mut buf)).await;
+        assert!(matches!(read.expect("not closed"), Ok(0) | Err(_)));
+    }
+
+    // Without offering the method, the client is refused.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read::<2>(&mut client).await, [5, 0xff]);
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert!(authenticate(&mut client, "user", "secret").await);
+    connect_and_echo(client, false).await;
+}
+
+#[tokio::test]
+async fn unsupported_command() {
+    let proxy = proxy(None).await;
+    let mut client = connect(proxy).await;
+    // BIND.
+    client
+        .write_all(&[&[5, 2, 0][..], &target(false, 7)].concat())
+        .await
+        .unwrap();
+    assert_eq!(read::<10>(&mut client).await[..2], [5, 7]);
+}
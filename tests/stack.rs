@@ -0,0 +1,61 @@
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use tokio_smoltcp::stack::{NetStack, StackTcpListener, StackTcpStream, StackUdpSocket, TokioNet};
+
+/// Echoes a TCP stream and a UDP datagram from `client` to `server`, which
+/// `host` resolves to, the same way on every stack.
+async fn echo<S: NetStack>(client: &S, server: &S, host: &str) {
+    let ip = server.lookup(host).await.unwrap()[0];
+
+    let mut listener = server.tcp_bind(SocketAddr::new(ip, 0)).await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let mut stream = client.tcp_connect(server_addr).await.unwrap();
+    let (mut accepted, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, stream.local_addr().unwrap());
+    assert_eq!(stream.peer_addr().unwrap(), server_addr);
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    accepted.read_exact(&mut buf).await.unwrap();
+    accepted.write_all(&buf).await.unwrap();
+    let mut echoed = [0; 5];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("no echo")
+        .unwrap();
+    assert_eq!(&echoed, b"hello");
+
+    let socket = server.udp_bind(SocketAddr::new(ip, 0)).await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let unspecified = SocketAddr::new("0.0.0.0".parse().unwrap(), 0);
+    let client_socket = client.udp_bind(unspecified).await.unwrap();
+    client_socket.send_to(b"ping", server_addr).await.unwrap();
+    let mut buf = [0; 16];
+    let (len, peer) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .expect("no datagram")
+        .unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    socket.send_to(&buf[..len], peer).await.unwrap();
+    let (len, from) = timeout(Duration::from_secs(5), client_socket.recv_from(&mut buf))
+        .await
+        .expect("no echo")
+        .unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from, server_addr);
+}
+
+#[tokio::test]
+async fn echo_on_net() {
+    let (client, server) = common::nets();
+    echo(&client, &server, "10.0.0.2").await;
+}
+
+#[tokio::test]
+async fn echo_on_host() {
+    echo(&TokioNet, &TokioNet, "127.0.0.1").await;
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::{addr, nets, until};
+use smoltcp::socket::tcp;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_smoltcp::SocketInfo;
+
+#[tokio::test]
+async fn counters_after_traffic() {
+    let (client, server) = nets();
+    let mut listener = server.tcp_bind(addr("10.0.0.2:80")).await.unwrap();
+    let udp = server.udp_bind(addr("10.0.0.2:53")).await.unwrap();
+
+    let (connected, accepted) =
+        tokio::join!(client.tcp_connect(addr("10.0.0.2:80")), listener.accept());
+    let mut stream = connected.unwrap();
+    let (mut accepted, peer) = accepted.unwrap();
+    stream.write_all(&[1; 3000]).await.unwrap();
+    stream.flush().await.unwrap();
+    // The server reads part of it, the rest waits in its stream.
+    let mut buf = [0; 1000];
+    accepted.read_exact(&mut buf).await.unwrap();
+    until(|| accepted.info().bytes_received == 3000).await;
+
+    let info = stream.info();
+    assert_eq!(info.state, tcp::State::Established);
+    assert_eq!(info.local_addr, peer);
+    assert_eq!(info.peer_addr, addr("10.0.0.2:80"));
+    assert_eq!(info.bytes_sent, 3000);
+    assert_eq!(info.bytes_received, 0);
+    assert_eq!(info.send_queue, 0);
+    let info = accepted.info();
+    assert_eq!(info.bytes_received, 3000);
+    assert_eq!(info.recv_queue, 2000);
+
+    let stats = client.stats();
+    // At least the SYN and the three segments of data.
+    assert!(stats.tx_packets >= 4, "{stats:?}");
+    assert!(stats.tx_bytes >= 3000, "{stats:?}");
+    assert!(stats.rx_packets >= 1, "{stats:?}");
+    assert_eq!(stats.rx_errors, 0);
+    assert!(stats.polls > 0 && stats.wakeups >= stats.polls, "{stats:?}");
+    let stats = server.stats();
+    assert!(stats.rx_bytes >= 3000, "{stats:?}");
+
+    let sockets = server.sockets();
+    assert_eq!(sockets.len(), 3, "{sockets:?}");
+    assert!(sockets.contains(&SocketInfo::Tcp {
+        state: tcp::State::Listen,
+        local_addr: addr("10.0.0.2:80"),
+        peer_addr: None,
+        send_queue: 0,
+        recv_queue: 0,
+    }));
+    // Like `TcpStream::info`, the queues include the stream's buffers.
+    assert!(sockets.contains(&SocketInfo::Tcp {
+        state: tcp::State::Established,
+        local_addr: addr("10.0.0.2:80"),
+        peer_addr: Some(peer),
+        send_queue: 0,
+        recv_queue: 2000,
+    }));
+    assert!(sockets.contains(&SocketInfo::Udp {
+        local_addr: addr("10.0.0.2:53"),
+        send_queue: 0,
+        recv_queue: 0,
+    }));
+
+    drop(udp);
+    drop(accepted);
+    until(|| server.sockets().len() == 1).await;
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use futures::{SinkExt, StreamExt};
+use smoltcp::phy::{DeviceCapabilities, Medium};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+};
+use tokio_smoltcp::{
+    Net,
+    device::{Packet, UdpTunnel},
+};
+
+fn net(tunnel: UdpTunnel, ip: &str) -> Net {
+    Net::new(tunnel, common::config(ip))
+}
+
+fn caps() -> DeviceCapabilities {
+    common::caps(Medium::Ip, 1400)
+}
+
+#[tokio::test]
+async fn tcp_over_localhost_tunnel() {
+    let caps = caps();
+
+    let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let client = net(UdpTunnel::new(a, b_addr, caps.clone()), "10.0.0.1/24");
+    let server = net(UdpTunnel::new(b, a_addr, caps), "10.0.0.2/24");
+
+    let mut listener = server
+        .tcp_bind("10.0.0.2:8080".parse().unwrap())
+        .await
+        .unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let len = data.len();
+    let echo = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream
+    });
+
+    let mut stream = client
+        .tcp_connect("10.0.0.2:8080".parse().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(&data).await.unwrap();
+    let mut echoed = vec![0; len];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, data);
+    echo.await.unwrap();
+}
+
+#[tokio::test]
+async fn drop_oversize_datagrams() {
+    let caps = caps();
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut tunnel = UdpTunnel::bind("127.0.0.1:0", peer.local_addr().unwrap(), caps)
+        .await
+        .unwrap();
+    let addr = tunnel.local_addr().unwrap();
+    peer.send_to(&[1; 1401], addr).await.unwrap();
+    peer.send_to(&[2; 1400], addr).await.unwrap();
+
+    // The first datagram doesn't fit the MTU, it isn't cut to size.
+    let packet = tunnel.next().await.unwrap().unwrap();
+    assert_eq!(packet[..], [2; 1400]);
+    assert_eq!(tunnel.dropped(), 1);
+}
+
+#[tokio::test]
+async fn send_errors_drop_packets() {
+    let caps = caps();
+
+    // An IPv4 socket can't send to an IPv6 peer.
+    let mut tunnel = UdpTunnel::bind("127.0.0.1:0", "[::1]:9".parse().unwrap(), caps)
+        .await
+        .unwrap();
+    for i in 1..=3 {
+        tunnel.send(Packet::from(&[0; 100][..])).await.unwrap();
+        assert_eq!(tunnel.dropped(), i);
+    }
+}
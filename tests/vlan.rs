@@ -0,0 +1,127 @@
+mod common;
+
+use futures::{SinkExt, StreamExt};
+use smoltcp::phy::Medium;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+use tokio_smoltcp::{
+    Net,
+    device::{AsyncDevice, Framed, Framing, Packet, Vlan, VlanPort},
+};
+
+fn trunk(io: DuplexStream) -> Framed<DuplexStream> {
+    Framed::new(io, Framing::LengthU16, common::caps(Medium::Ethernet, 1514))
+}
+
+/// A frame from 02:00:00:00:00:02 to 02:00:00:00:00:01 with `tag` between
+/// the addresses and the EtherType.
+fn frame(tag: &[u8], payload: u8) -> Packet {
+    let mut frame = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2];
+    frame.extend_from_slice(tag);
+    frame.extend_from_slice(&[0x08, 0x00, payload, payload]);
+    Packet::from(&frame[..])
+}
+
+#[tokio::test]
+async fn tag_and_strip() {
+    let (a, b) = duplex(1 << 16);
+    let vlan = Vlan::new(trunk(a)).unwrap();
+    let mut raw = trunk(b);
+    let mut port = vlan.port(10).unwrap();
+    let mut untagged = vlan.untagged().unwrap();
+
+    // Frames sent on a port are tagged with its VLAN ID.
+    port.send(frame(&[], 1)).await.unwrap();
+    assert_eq!(
+        raw.next().await.unwrap().unwrap(),
+        frame(&[0x81, 0x00, 0x00, 10], 1)
+    );
+    untagged.send(frame(&[], 2)).await.unwrap();
+    assert_eq!(raw.next().await.unwrap().unwrap(), frame(&[], 2));
+
+    // Frames received on the trunk are stripped of their tag. The priority
+    // bits don't change the VLAN.
+    raw.send(frame(&[0x81, 0x00, 0xa0, 10], 3)).await.unwrap();
+    assert_eq!(port.next().await.unwrap().unwrap(), frame(&[], 3));
+
+    // Untagged and priority-tagged frames go to the untagged port, frames of
+    // a VLAN without a port are dropped.
+    raw.send(frame(&[0x81, 0x00, 0x00, 20], 4)).await.unwrap();
+    raw.send(frame(&[], 5)).await.unwrap();
+    raw.send(frame(&[0x81, 0x00, 0xa0, 0], 6)).await.unwrap();
+    assert_eq!(untagged.next().await.unwrap().unwrap(), frame(&[], 5));
+    assert_eq!(untagged.next().await.unwrap().unwrap(), frame(&[], 6));
+    assert_eq!(vlan.dropped(), 1);
+}
+
+#[tokio::test]
+async fn port_capabilities() {
+    let (a, _b) = duplex(1 << 16);
+    let vlan = Vlan::new(trunk(a)).unwrap();
+
+    let port = vlan.port(10).unwrap();
+    assert_eq!(port.vid(), Some(10));
+    assert_eq!(port.capabilities().max_transmission_unit, 1514 - 4);
+    let untagged = vlan.untagged().unwrap();
+    assert_eq!(untagged.vid(), None);
+    assert_eq!(untagged.capabilities().max_transmission_unit, 1514);
+
+    for vid in [0, 4095] {
+        let err = vlan.port(vid).err().expect("invalid VLAN ID accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    let err = vlan.port(10).err().expect("port opened twice");
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    drop(port);
+    assert!(vlan.port(10).is_ok());
+
+    let (c, _d) = duplex(1 << 16);
+    let ip_caps = common::caps(Medium::Ip, 1514);
+    let err = Vlan::new(Framed::new(c, Framing::LengthU16, ip_caps))
+        .err()
+        .expect("Medium::Ip trunk accepted");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+fn net(port: VlanPort, mac: u8, ip: &str) -> Net {
+    Net::new(port, common::ethernet_config([2, 0, 0, 0, 0, mac], ip))
+}
+
+async fn echo(client: &Net, server: &Net, addr: &str, len: usize) {
+    let mut listener = server.tcp_bind(addr.parse().unwrap()).await.unwrap();
+    let echo = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream
+    });
+
+    let data: Vec<u8> = (0..len as u32).map(|i| i as u8).collect();
+    let mut stream = client.tcp_connect(addr.parse().unwrap()).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    let mut echoed = vec![0; len];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, data);
+    echo.await.unwrap();
+}
+
+#[tokio::test]
+async fn route_between_ports() {
+    let (a, b) = duplex(1 << 20);
+    let (left, right) = (Vlan::new(trunk(a)).unwrap(), Vlan::new(trunk(b)).unwrap());
+
+    // Both VLANs use the same addresses, so a frame leaking from one to the
+    // other would break the connections.
+    let client10 = net(left.port(10).unwrap(), 1, "10.0.0.1/24");
+    let server10 = net(right.port(10).unwrap(), 2, "10.0.0.2/24");
+    let client20 = net(left.port(20).unwrap(), 1, "10.0.0.1/24");
+    let server20 = net(right.port(20).unwrap(), 2, "10.0.0.2/24");
+
+    tokio::join!(
+        echo(&client10, &server10, "10.0.0.2:8010", 100_000),
+        echo(&client20, &server20, "10.0.0.2:8020", 100_000),
+    );
+    assert_eq!(left.dropped(), 0);
+    assert_eq!(right.dropped(), 0);
+}